@@ -0,0 +1,152 @@
+use crate::client_api::backup_service_client::BackupServiceClient;
+use crate::client_api::{BackupRequest, BackupResponse};
+use crate::client_api::{RestoreRequest, RestoreResponse};
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{PaddingScheme, RsaPrivateKey};
+use serde_json::Value;
+use sha2::Sha256;
+use std::fs;
+
+const PROTECTION_RSA_OAEP: &str = "RSA-OAEP";
+const PROTECTION_ECDH: &str = "ECDH-ES";
+// Must match verdictd's HKDF info of the backups encrypted to an EC public key
+const ECDH_KDF_INFO: &[u8] = b"verdictd backup";
+
+// The passphrase is the first line of the passphrase file
+fn passphrase(path: &str) -> Vec<u8> {
+    fs::read_to_string(path)
+        .expect(&format!(
+            "Failed to read from the passphrase file {}.",
+            path
+        ))
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .as_bytes()
+        .to_vec()
+}
+
+fn field(envelope: &Value, name: &str) -> Result<Vec<u8>, String> {
+    base64::decode(envelope[name].as_str().unwrap_or_default())
+        .map_err(|e| format!("The archive's {} is broken: {}", name, e))
+}
+
+// Unwrap the data key of an archive encrypted to a public key with the PKCS#8 PEM private key
+fn data_key(archive: &[u8], private_key: &str) -> Result<Vec<u8>, String> {
+    let envelope: Value =
+        serde_json::from_slice(archive).map_err(|e| format!("Parse the archive failed: {}", e))?;
+    let wrapped_key = field(&envelope, "wrapped_key")?;
+
+    match envelope["protection"].as_str().unwrap_or_default() {
+        PROTECTION_RSA_OAEP => RsaPrivateKey::from_pkcs8_pem(private_key)
+            .map_err(|e| format!("Parse the private key failed: {}", e))?
+            .decrypt(PaddingScheme::new_oaep::<Sha256>(), &wrapped_key)
+            .map_err(|e| format!("Unwrap the data key failed: {}", e)),
+        PROTECTION_ECDH => {
+            let private_key = p256::SecretKey::from_pkcs8_pem(private_key)
+                .map_err(|e| format!("Parse the private key failed: {}", e))?;
+            let ephemeral_key =
+                p256::PublicKey::from_sec1_bytes(&field(&envelope, "ephemeral_key")?)
+                    .map_err(|e| format!("Parse the ephemeral key failed: {}", e))?;
+            let shared = p256::ecdh::diffie_hellman(
+                private_key.to_nonzero_scalar(),
+                ephemeral_key.as_affine(),
+            );
+
+            let mut kek = [0u8; 32];
+            Hkdf::<Sha256>::new(None, shared.raw_secret_bytes())
+                .expand(ECDH_KDF_INFO, &mut kek)
+                .map_err(|e| format!("Derive the key-encryption key failed: {}", e))?;
+            let key_iv = field(&envelope, "key_iv")?;
+            if key_iv.len() != 12 {
+                return Err("The archive's key_iv is broken".to_string());
+            }
+            Aes256Gcm::new(Key::from_slice(&kek))
+                .decrypt(Nonce::from_slice(&key_iv), wrapped_key.as_slice())
+                .map_err(|e| format!("Unwrap the data key failed: {:?}", e))
+        }
+        protection => Err(format!(
+            "The archive isn't encrypted to a public key: {:?}",
+            protection
+        )),
+    }
+}
+
+// The archive is encrypted to the passphrase of passphrase_path, or to the PEM public key of public_key_path
+pub async fn backup_cmd(
+    path: &str,
+    passphrase_path: Option<&str>,
+    public_key_path: Option<&str>,
+    without_keys: bool,
+    addr: &str,
+) {
+    let request = BackupRequest {
+        passphrase: passphrase_path.map(passphrase).unwrap_or_default(),
+        publickey: public_key_path
+            .map(|path| {
+                fs::read(path).expect(&format!(
+                    "Failed to read from the public key file {}.",
+                    path
+                ))
+            })
+            .unwrap_or_default(),
+        withoutkeys: without_keys,
+    };
+
+    let mut client = BackupServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: BackupResponse = client.backup(request).await.unwrap().into_inner();
+    let status = String::from_utf8(response.status).unwrap();
+    info!("backup status is: {:?}", status);
+    if status == "OK" {
+        fs::write(path, response.archive).expect(&format!("Failed to write to {}.", path));
+        info!("archive: {}", path);
+    }
+}
+
+// The archive is decrypted with the passphrase of passphrase_path, or with the PEM private key
+// of private_key_path, which never leaves this host: only the archive's data key is sent
+pub async fn restore_cmd(
+    path: &str,
+    passphrase_path: Option<&str>,
+    private_key_path: Option<&str>,
+    addr: &str,
+) {
+    let archive = fs::read(path).expect(&format!("Failed to read from the archive {}.", path));
+    let datakey = match private_key_path {
+        Some(private_key_path) => {
+            let private_key = fs::read_to_string(private_key_path).expect(&format!(
+                "Failed to read from the private key file {}.",
+                private_key_path
+            ));
+            match data_key(&archive, &private_key) {
+                Ok(datakey) => datakey,
+                Err(e) => {
+                    info!("restore failed: {}", e);
+                    return;
+                }
+            }
+        }
+        None => vec![],
+    };
+    let request = RestoreRequest {
+        archive,
+        passphrase: passphrase_path.map(passphrase).unwrap_or_default(),
+        datakey,
+    };
+
+    let mut client = BackupServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RestoreResponse = client.restore(request).await.unwrap().into_inner();
+    info!(
+        "restore status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
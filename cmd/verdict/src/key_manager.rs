@@ -0,0 +1,423 @@
+use crate::client_api::key_manager_service_client::KeyManagerServiceClient;
+use crate::client_api::{CreateKeyRequest, CreateKeyResponse};
+use crate::client_api::{DeleteKeyRequest, DeleteKeyResponse};
+use crate::client_api::{GetImportKeyRequest, GetImportKeyResponse};
+use crate::client_api::{GetPublicKeyRequest, GetPublicKeyResponse};
+use crate::client_api::{ImportKeyRequest, ImportKeyResponse};
+use crate::client_api::{KeyInfo, KeyLabel};
+use crate::client_api::{ListKeysRequest, ListKeysResponse};
+use crate::client_api::{RestoreKeyRequest, RestoreKeyResponse};
+use crate::client_api::{RotateKeyRequest, RotateKeyResponse};
+use crate::client_api::{SetKeyLabelsRequest, SetKeyLabelsResponse};
+use crate::client_api::{SetKeyUsagesRequest, SetKeyUsagesResponse};
+use crate::client_api::{SignRequest, SignResponse};
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use rand::RngCore;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{PaddingScheme, PublicKey, RsaPublicKey};
+use sha2::Sha256;
+use std::fs;
+
+lazy_static! {
+    static ref TOKEN: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Send the following key manager requests with the token read from path, verdictd scopes
+/// them to the namespace of the tenant the token is bound to
+pub fn set_credential(path: &str) {
+    let token = fs::read_to_string(path).expect("Read the credential file failed.");
+    *TOKEN.write() = Some(token.trim().to_string());
+}
+
+// Attach the token, if any, as the "authorization: Bearer <TOKEN>" request metadata
+fn scoped<T>(message: T) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    if let Some(token) = TOKEN.read().as_ref() {
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token)
+                .parse()
+                .expect("The token is not valid metadata."),
+        );
+    }
+    request
+}
+
+// Labels are given as "NAME=VALUE"
+fn labels(vals: Vec<&str>) -> Vec<KeyLabel> {
+    vals.iter()
+        .map(|label| {
+            let (name, value) = label
+                .split_once('=')
+                .expect("Label is not in NAME=VALUE format.");
+            KeyLabel {
+                name: name.as_bytes().to_vec(),
+                value: value.as_bytes().to_vec(),
+            }
+        })
+        .collect()
+}
+
+fn usages(vals: Vec<&str>) -> Vec<Vec<u8>> {
+    vals.iter().map(|usage| usage.as_bytes().to_vec()).collect()
+}
+
+// The key type given as "ALGORITHM-LENGTH", e.g. AES-256 or ChaCha20-Poly1305-256
+fn key_type(key_type: &str) -> (Vec<u8>, u32) {
+    let (algorithm, length) = key_type
+        .rsplit_once('-')
+        .expect("Key type is not in ALGORITHM-LENGTH format.");
+    let length = length.parse::<u32>().expect("Key length is not a number.");
+    (algorithm.as_bytes().to_vec(), length)
+}
+
+fn format_key(key: KeyInfo) -> String {
+    let labels: Vec<String> = key
+        .labels
+        .into_iter()
+        .map(|label| {
+            format!(
+                "{}={}",
+                String::from_utf8(label.name).unwrap(),
+                String::from_utf8(label.value).unwrap()
+            )
+        })
+        .collect();
+    let usages: Vec<String> = key
+        .usages
+        .into_iter()
+        .map(|usage| String::from_utf8(usage).unwrap())
+        .collect();
+
+    let mut line = format!(
+        "{} {}-{} version: {} {} created: {} last used: {} labels: [{}] usages: [{}]",
+        String::from_utf8(key.kid).unwrap(),
+        String::from_utf8(key.algorithm).unwrap(),
+        key.length,
+        key.version,
+        String::from_utf8(key.state).unwrap(),
+        key.created,
+        key.lastused,
+        labels.join(", "),
+        usages.join(", ")
+    );
+    if key.purgeafter != 0 {
+        line += &format!(" purge after: {}", key.purgeafter);
+    }
+    line
+}
+
+// An AES-256 key is created without a key type
+pub async fn create_key_cmd(
+    key_type_arg: Option<&str>,
+    vals: Vec<&str>,
+    usage_vals: Vec<&str>,
+    addr: &str,
+) {
+    let (algorithm, length) = key_type(key_type_arg.unwrap_or("AES-256"));
+    let request = CreateKeyRequest {
+        labels: labels(vals),
+        usages: usages(usage_vals),
+        algorithm,
+        length,
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: CreateKeyResponse = client
+        .create_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "create_key status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!("kid: {}", String::from_utf8(response.uuid).unwrap());
+}
+
+pub async fn set_key_labels_cmd(kid: &str, vals: Vec<&str>, addr: &str) {
+    let request = SetKeyLabelsRequest {
+        uuid: kid.as_bytes().to_vec(),
+        labels: labels(vals),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: SetKeyLabelsResponse = client
+        .set_key_labels(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "set_key_labels status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn set_key_usages_cmd(kid: &str, usage_vals: Vec<&str>, addr: &str) {
+    let request = SetKeyUsagesRequest {
+        uuid: kid.as_bytes().to_vec(),
+        usages: usages(usage_vals),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: SetKeyUsagesResponse = client
+        .set_key_usages(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "set_key_usages status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn list_keys_cmd(vals: Vec<&str>, addr: &str) {
+    let request = ListKeysRequest {
+        labels: labels(vals),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: ListKeysResponse = client
+        .list_keys(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "list_keys status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    for key in response.keys {
+        info!("{}", format_key(key));
+    }
+}
+
+// The key is deleted at once without a grace period, else it can be restored until the period is over
+pub async fn delete_key_cmd(kid: &str, grace_period: Option<&str>, addr: &str) {
+    let request = DeleteKeyRequest {
+        uuid: kid.as_bytes().to_vec(),
+        graceperiod: grace_period
+            .map(|period| {
+                period
+                    .parse::<u64>()
+                    .expect("Grace period is not a number.")
+            })
+            .unwrap_or(0),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: DeleteKeyResponse = client
+        .delete_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "delete_key status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn restore_key_cmd(kid: &str, addr: &str) {
+    let request = RestoreKeyRequest {
+        uuid: kid.as_bytes().to_vec(),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RestoreKeyResponse = client
+        .restore_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "restore_key status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn rotate_key_cmd(kid: &str, addr: &str) {
+    let request = RotateKeyRequest {
+        uuid: kid.as_bytes().to_vec(),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RotateKeyResponse = client
+        .rotate_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "rotate_key status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!("version: {}", response.version);
+}
+
+const WRAP_RSA_OAEP: &str = "RSA-OAEP";
+const WRAP_ECDH: &str = "ECDH-ES";
+// Must match verdictd's HKDF info of ECDH-ES
+const ECDH_KDF_INFO: &[u8] = b"verdictd key import";
+
+// The wrapped key, and for ECDH-ES the ephemeral public key and the iv
+fn wrap_key(
+    wrap_algorithm: &str,
+    public_key: &str,
+    key: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+    let mut rng = rand::rngs::OsRng;
+    match wrap_algorithm {
+        WRAP_RSA_OAEP => {
+            let public_key = RsaPublicKey::from_public_key_pem(public_key)
+                .map_err(|e| format!("Parse the import key failed: {}", e))?;
+            let wrapped = public_key
+                .encrypt(&mut rng, PaddingScheme::new_oaep::<Sha256>(), key)
+                .map_err(|e| format!("Wrap the key failed: {}", e))?;
+            Ok((wrapped, vec![], vec![]))
+        }
+        WRAP_ECDH => {
+            let public_key = p256::PublicKey::from_public_key_pem(public_key)
+                .map_err(|e| format!("Parse the import key failed: {}", e))?;
+            let ephemeral = p256::ecdh::EphemeralSecret::random(&mut rng);
+            let shared = ephemeral.diffie_hellman(&public_key);
+
+            let mut kek = [0u8; 32];
+            Hkdf::<Sha256>::new(None, shared.raw_secret_bytes())
+                .expand(ECDH_KDF_INFO, &mut kek)
+                .map_err(|e| format!("Derive the key-encryption key failed: {}", e))?;
+            let mut iv = [0u8; 12];
+            rng.fill_bytes(&mut iv);
+            let wrapped = Aes256Gcm::new(Key::from_slice(&kek))
+                .encrypt(Nonce::from_slice(&iv), key)
+                .map_err(|e| format!("Wrap the key failed: {:?}", e))?;
+
+            let ephemeral_key = p256::EncodedPoint::from(ephemeral.public_key());
+            Ok((wrapped, ephemeral_key.as_bytes().to_vec(), iv.to_vec()))
+        }
+        _ => Err(format!(
+            "Unknown wrap algorithm: {:?}, expect {} or {}",
+            wrap_algorithm, WRAP_RSA_OAEP, WRAP_ECDH
+        )),
+    }
+}
+
+// The key file holds the raw symmetric key or the PKCS#8 DER private key, of an AES-256 key
+// by default. It's wrapped with verdictd's import key before it's sent.
+pub async fn import_key_cmd(
+    path: &str,
+    kid: Option<&str>,
+    key_type_arg: Option<&str>,
+    wrap_algorithm: Option<&str>,
+    vals: Vec<&str>,
+    usage_vals: Vec<&str>,
+    addr: &str,
+) {
+    let key = fs::read(path).expect(&format!("Failed to read from the key file {}.", path));
+    let (algorithm, length) = key_type(key_type_arg.unwrap_or("AES-256"));
+    let wrap_algorithm = wrap_algorithm.unwrap_or(WRAP_RSA_OAEP);
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let request = GetImportKeyRequest {
+        wrapalgorithm: wrap_algorithm.as_bytes().to_vec(),
+    };
+    let response: GetImportKeyResponse = client
+        .get_import_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    let status = String::from_utf8(response.status).unwrap();
+    if status != "OK" {
+        info!("get_import_key status is: {:?}", status);
+        return;
+    }
+
+    let public_key = String::from_utf8(response.publickey).unwrap();
+    let (wrapped, ephemeral_key, iv) = wrap_key(wrap_algorithm, &public_key, &key).unwrap();
+    let request = ImportKeyRequest {
+        uuid: kid.unwrap_or_default().as_bytes().to_vec(),
+        wrapalgorithm: wrap_algorithm.as_bytes().to_vec(),
+        wrappedkey: wrapped,
+        ephemeralkey: ephemeral_key,
+        iv,
+        algorithm,
+        length,
+        labels: labels(vals),
+        usages: usages(usage_vals),
+    };
+
+    let response: ImportKeyResponse = client
+        .import_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "import_key status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!("kid: {}", String::from_utf8(response.uuid).unwrap());
+}
+
+pub async fn get_public_key_cmd(kid: &str, addr: &str) {
+    let request = GetPublicKeyRequest {
+        uuid: kid.as_bytes().to_vec(),
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: GetPublicKeyResponse = client
+        .get_public_key(scoped(request))
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "get_public_key status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!("{}", String::from_utf8(response.publickey).unwrap());
+}
+
+pub async fn sign_cmd(vals: Vec<&str>, addr: &str) {
+    let data = fs::read(vals[1]).expect(&format!("Failed to read from the file {}.", vals[1]));
+    let request = SignRequest {
+        uuid: vals[0].as_bytes().to_vec(),
+        data,
+    };
+
+    let mut client = KeyManagerServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: SignResponse = client.sign(scoped(request)).await.unwrap().into_inner();
+    info!(
+        "sign status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!("signature: {}", base64::encode(response.signature));
+}
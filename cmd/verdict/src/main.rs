@@ -4,9 +4,12 @@ pub mod client_api {
     tonic::include_proto!("clientapi");
 }
 
+mod backup;
 mod gpg;
 mod image;
+mod key_manager;
 mod opa;
+mod unseal;
 
 #[macro_use]
 extern crate log;
@@ -28,6 +31,13 @@ async fn main() {
                 .help("Generate a policy file named <POLICY_NAME>, according to the contents in <POLICY_PATH>.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("require_tests")
+                .long("require-tests")
+                .value_name("REFERENCE_NAME")
+                .help("Refuse to set the policy unless its stored <POLICY_NAME>_test.rego tests pass against <REFERENCE_NAME>, must be used with '--set-opa-policy'.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("export_opa_policy")
                 .long("export-opa-policy")
@@ -51,6 +61,13 @@ async fn main() {
                 .help("Generate a reference file named <REFERENCE_NAME>, according to the contents in <REFERENCE_PATH>.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("tee")
+                .long("tee")
+                .value_name("TEE_TYPE")
+                .help("Validate the reference against <TEE_TYPE>'s (sgx or csv) schema, must be used with '--set-opa-reference'.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("export_opa_reference")
                 .long("export-opa-reference")
@@ -58,6 +75,171 @@ async fn main() {
                 .help("export OPA reference file named <REFERENCE_NAME>")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("reference")
+                .long("reference")
+                .value_name("REFERENCE_NAME")
+                .help("Specify the reference patched by the '--add/remove-sgx-*', '--set-sgx-min-svn' and '--add/remove-csv-measure' options, sgxData or csvData by default. Or the reference used by '--set-opa-shadow-policy', the active one by default.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("add_sgx_mrenclave")
+                .long("add-sgx-mrenclave")
+                .value_name("MRENCLAVE")
+                .help("add the base64 encoded <MRENCLAVE> into the SGX reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remove_sgx_mrenclave")
+                .long("remove-sgx-mrenclave")
+                .value_name("MRENCLAVE")
+                .help("remove the base64 encoded <MRENCLAVE> from the SGX reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("add_sgx_mrsigner")
+                .long("add-sgx-mrsigner")
+                .value_name("MRSIGNER")
+                .help("add the base64 encoded <MRSIGNER> into the SGX reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remove_sgx_mrsigner")
+                .long("remove-sgx-mrsigner")
+                .value_name("MRSIGNER")
+                .help("remove the base64 encoded <MRSIGNER> from the SGX reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("add_sgx_sigstruct")
+                .long("add-sgx-sigstruct")
+                .value_name("PATH")
+                .help("add the mrEnclave and mrSigner of the SIGSTRUCT or signed enclave file <PATH> into the SGX reference, and set its productId if it has none")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("set_svn")
+                .long("set-svn")
+                .help("also set the minimum SVN of the SGX reference to the enclave's, even if it's lower")
+                .requires("add_sgx_sigstruct"),
+        )
+        .arg(
+            Arg::with_name("set_sgx_min_svn")
+                .long("set-sgx-min-svn")
+                .value_name("SVN")
+                .help("set the minimum SVN of the SGX reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("add_csv_measure")
+                .long("add-csv-measure")
+                .value_name("MEASURE")
+                .help("add the base64 encoded <MEASURE> into the CSV reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remove_csv_measure")
+                .long("remove-csv-measure")
+                .value_name("MEASURE")
+                .help("remove the base64 encoded <MEASURE> from the CSV reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("not_before")
+                .long("not-before")
+                .value_name("UNIX_TIME")
+                .help("the values added by '--add-sgx-*' or '--add-csv-measure' are only valid since <UNIX_TIME>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("not_after")
+                .long("not-after")
+                .value_name("UNIX_TIME")
+                .help("the values added by '--add-sgx-*' or '--add-csv-measure' are no longer valid since <UNIX_TIME>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("revoke")
+                .long("revoke")
+                .value_name("KIND")
+                .value_name("VALUE")
+                .help("revoke the base64 encoded <VALUE> of <KIND> (mrEnclave, mrSigner or measure) for all policies")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unrevoke")
+                .long("unrevoke")
+                .value_name("KIND")
+                .value_name("VALUE")
+                .help("remove the base64 encoded <VALUE> of <KIND> (mrEnclave, mrSigner or measure) from the revocation list")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("set_opa_shadow_policy")
+                .long("set-opa-shadow-policy")
+                .value_name("TEE_TYPE")
+                .value_name("POLICY_NAME")
+                .help("evaluate the policy named <POLICY_NAME> alongside <TEE_TYPE>'s (sgx or csv) active policy without enforcing it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("clear_opa_shadow_policy")
+                .long("clear-opa-shadow-policy")
+                .value_name("TEE_TYPE")
+                .help("stop evaluating <TEE_TYPE>'s shadow policy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("promote_opa_shadow_policy")
+                .long("promote-opa-shadow-policy")
+                .value_name("TEE_TYPE")
+                .help("make <TEE_TYPE>'s shadow policy the active policy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_opa_shadow_policies")
+                .long("list-opa-shadow-policies")
+                .help("list the shadow policies with their disagreement counters")
+        )
+        .arg(
+            Arg::with_name("set_opa_policy_set")
+                .long("set-opa-policy-set")
+                .value_name("TEE_TYPE> <MODE> <POLICY_NAME:REFERENCE_NAME")
+                .help("make <TEE_TYPE>'s (sgx or csv) decisions with the policies and their references, combined by <MODE>: all (every policy must allow) or any (a single allowing policy is enough)")
+                .min_values(3)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("get_opa_policy_set")
+                .long("get-opa-policy-set")
+                .value_name("TEE_TYPE")
+                .help("show the policies <TEE_TYPE>'s (sgx or csv) decisions are made with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_opa_policies")
+                .long("list-opa-policies")
+                .help("list all OPA policy files")
+        )
+        .arg(
+            Arg::with_name("list_opa_references")
+                .long("list-opa-references")
+                .help("list all OPA reference files")
+        )
+        .arg(
+            Arg::with_name("delete_opa_policy")
+                .long("delete-opa-policy")
+                .value_name("POLICY_NAME")
+                .help("delete OPA policy file named <POLICY_NAME>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("delete_opa_reference")
+                .long("delete-opa-reference")
+                .value_name("REFERENCE_NAME")
+                .help("delete OPA reference file named <REFERENCE_NAME>")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("client_api")
                 .long("client-api")
@@ -66,6 +248,13 @@ async fn main() {
                 .help("Specify the client API's connection address.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("credential")
+                .long("credential")
+                .value_name("TOKEN_PATH")
+                .help("Manage the keys in the namespace of the tenant the token in <TOKEN_PATH> is bound to.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("test_opa_remote")
                 .long("test-opa-remote")
@@ -102,6 +291,185 @@ async fn main() {
                 .help("test OPA's remote policy and local reference")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("run_opa_tests")
+                .long("run-opa-tests")
+                .value_name("POLICY_NAME")
+                .value_name("REFERENCE_NAME")
+                .value_name("TESTS_NAME")
+                .help("run the remote OPA unit tests against remote policy and remote reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("run_opa_tests_local")
+                .long("run-opa-tests-local")
+                .value_name("POLICY_PATH")
+                .value_name("REFERENCE_PATH")
+                .value_name("TESTS_PATH")
+                .help("run the local OPA unit tests against local policy and local reference")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("create_key")
+                .long("create-key")
+                .help("create a new key of the '--key-type' type, labelled by '--label', restricted to the '--usage' usages"),
+        )
+        .arg(
+            Arg::with_name("key_type")
+                .long("key-type")
+                .value_name("ALGORITHM-LENGTH")
+                .help("the key type of '--create-key' and '--import-key': AES-128, AES-256 (default), ChaCha20-Poly1305-256, RSA-2048, RSA-3072, RSA-4096 or EC-256")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("get_public_key")
+                .long("get-public-key")
+                .value_name("KID")
+                .help("get the public key of the RSA or EC key designated by <KID>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sign")
+                .long("sign")
+                .value_name("KID")
+                .value_name("DATA_PATH")
+                .help("sign the contents of <DATA_PATH> with the RSA or EC key designated by <KID>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list_keys")
+                .long("list-keys")
+                .help("list the keys and their metadata, only the ones carrying all the '--label' labels"),
+        )
+        .arg(
+            Arg::with_name("import_key")
+                .long("import-key")
+                .value_name("KEY_FILE")
+                .help("import the raw AES-256 key of <KEY_FILE>, it's wrapped with verdictd's import key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kid")
+                .long("kid")
+                .value_name("KID")
+                .help("the kid of the key of '--import-key', generated by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wrap_algorithm")
+                .long("wrap-algorithm")
+                .value_name("ALGORITHM")
+                .help("the algorithm '--import-key' wraps the key with: RSA-OAEP (default) or ECDH-ES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rotate_key")
+                .long("rotate-key")
+                .value_name("KID")
+                .help("add a new version of the key designated by <KID>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("set_key_labels")
+                .long("set-key-labels")
+                .value_name("KID")
+                .help("replace the labels of the key designated by <KID> with the '--label' labels")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .value_name("NAME=VALUE")
+                .help("a key label, can be given several times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("usage")
+                .long("usage")
+                .value_name("USAGE")
+                .help("an operation the key can be used for: export, decrypt, wrap, unwrap, sign or derive, can be given several times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("set_key_usages")
+                .long("set-key-usages")
+                .value_name("KID")
+                .help("restrict the key designated by <KID> to the '--usage' usages")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("delete_key")
+                .long("delete-key")
+                .value_name("KID")
+                .help("delete the key designated by <KID>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grace_period")
+                .long("grace-period")
+                .value_name("SECONDS")
+                .help("soft delete the key of '--delete-key', it can be restored during <SECONDS>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("restore_key")
+                .long("restore-key")
+                .value_name("KID")
+                .help("restore the soft deleted key designated by <KID>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backup")
+                .long("backup")
+                .value_name("ARCHIVE_PATH")
+                .help("back up verdictd's keys, OPA files, GPG keyring and image configurations into the encrypted archive <ARCHIVE_PATH>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("without_keys")
+                .long("without-keys")
+                .help("back up without the keys when verdictd's key store can't export them")
+                .requires("backup"),
+        )
+        .arg(
+            Arg::with_name("restore")
+                .long("restore")
+                .value_name("ARCHIVE_PATH")
+                .help("restore verdictd's state from the encrypted archive <ARCHIVE_PATH>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("passphrase_file")
+                .long("passphrase-file")
+                .value_name("PATH")
+                .help("the passphrase '--backup' encrypts the archive to and '--restore' decrypts it with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("public_key")
+                .long("public-key")
+                .value_name("PEM_PATH")
+                .help("the RSA or P-256 public key '--backup' encrypts the archive to")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("private_key")
+                .long("private-key")
+                .value_name("PEM_PATH")
+                .help("the PKCS#8 private key '--restore' decrypts the archive with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unseal")
+                .long("unseal")
+                .value_name("SHARE_PATH")
+                .help("submit the master key's share of <SHARE_PATH> to unseal verdictd")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("list_gpg_keys")
                 .long("list-gpg-keys")
@@ -159,10 +527,15 @@ async fn main() {
     };
     info!("Connect to Verdictd with addr: {}", client_api);
 
+    if let Some(path) = matches.value_of("credential") {
+        key_manager::set_credential(path);
+    }
+
     // set_opa_policy
     if matches.is_present("set_opa_policy") {
         opa::set_policy_cmd(
             matches.values_of("set_opa_policy").unwrap().collect(),
+            matches.value_of("require_tests"),
             &client_api,
         )
         .await;
@@ -190,6 +563,7 @@ async fn main() {
     if matches.is_present("set_opa_reference") {
         opa::set_reference_cmd(
             matches.values_of("set_opa_reference").unwrap().collect(),
+            matches.value_of("tee"),
             &client_api,
         )
         .await;
@@ -213,6 +587,152 @@ async fn main() {
         .await;
     }
 
+    if matches.is_present("add_sgx_mrenclave") || matches.is_present("add_sgx_mrsigner") {
+        opa::add_sgx_reference_cmd(
+            matches.value_of("reference"),
+            matches.value_of("add_sgx_mrenclave"),
+            matches.value_of("add_sgx_mrsigner"),
+            (
+                matches.value_of("not_before"),
+                matches.value_of("not_after"),
+            ),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("remove_sgx_mrenclave") || matches.is_present("remove_sgx_mrsigner") {
+        opa::remove_sgx_reference_cmd(
+            matches.value_of("reference"),
+            matches.value_of("remove_sgx_mrenclave"),
+            matches.value_of("remove_sgx_mrsigner"),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("add_sgx_sigstruct") {
+        opa::add_sgx_sigstruct_cmd(
+            matches.value_of("reference"),
+            matches.value_of("add_sgx_sigstruct").unwrap(),
+            (
+                matches.value_of("not_before"),
+                matches.value_of("not_after"),
+            ),
+            matches.is_present("set_svn"),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("set_sgx_min_svn") {
+        opa::set_sgx_minimum_svn_cmd(
+            matches.value_of("reference"),
+            matches.value_of("set_sgx_min_svn").unwrap(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("add_csv_measure") {
+        opa::add_csv_reference_cmd(
+            matches.value_of("reference"),
+            matches.value_of("add_csv_measure").unwrap(),
+            (
+                matches.value_of("not_before"),
+                matches.value_of("not_after"),
+            ),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("remove_csv_measure") {
+        opa::remove_csv_reference_cmd(
+            matches.value_of("reference"),
+            matches.value_of("remove_csv_measure").unwrap(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("revoke") {
+        opa::revoke_reference_cmd(matches.values_of("revoke").unwrap().collect(), &client_api)
+            .await;
+    }
+
+    if matches.is_present("unrevoke") {
+        opa::unrevoke_reference_cmd(
+            matches.values_of("unrevoke").unwrap().collect(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("set_opa_shadow_policy") {
+        opa::set_shadow_policy_cmd(
+            matches
+                .values_of("set_opa_shadow_policy")
+                .unwrap()
+                .collect(),
+            matches.value_of("reference"),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("clear_opa_shadow_policy") {
+        opa::clear_shadow_policy_cmd(
+            matches.value_of("clear_opa_shadow_policy").unwrap(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("promote_opa_shadow_policy") {
+        opa::promote_shadow_policy_cmd(
+            matches.value_of("promote_opa_shadow_policy").unwrap(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("list_opa_shadow_policies") {
+        opa::list_shadow_policies_cmd(&client_api).await;
+    }
+
+    if matches.is_present("set_opa_policy_set") {
+        opa::set_policy_set_cmd(
+            matches.values_of("set_opa_policy_set").unwrap().collect(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("get_opa_policy_set") {
+        opa::get_policy_set_cmd(matches.value_of("get_opa_policy_set").unwrap(), &client_api).await;
+    }
+
+    if matches.is_present("list_opa_policies") {
+        opa::list_policies_cmd(&client_api).await;
+    }
+
+    if matches.is_present("list_opa_references") {
+        opa::list_references_cmd(&client_api).await;
+    }
+
+    if matches.is_present("delete_opa_policy") {
+        opa::delete_policy_cmd(matches.value_of("delete_opa_policy").unwrap(), &client_api).await;
+    }
+
+    if matches.is_present("delete_opa_reference") {
+        opa::delete_reference_cmd(
+            matches.value_of("delete_opa_reference").unwrap(),
+            &client_api,
+        )
+        .await;
+    }
+
     if matches.is_present("test_opa_remote") {
         opa::test_remote_cmd(
             matches.values_of("test_opa_remote").unwrap().collect(),
@@ -251,6 +771,127 @@ async fn main() {
         .await;
     }
 
+    if matches.is_present("run_opa_tests") {
+        opa::run_tests_remote_cmd(
+            matches.values_of("run_opa_tests").unwrap().collect(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("run_opa_tests_local") {
+        opa::run_tests_local_cmd(
+            matches.values_of("run_opa_tests_local").unwrap().collect(),
+            &client_api,
+        )
+        .await;
+    }
+
+    let labels: Vec<&str> = matches
+        .values_of("label")
+        .map(|vals| vals.collect())
+        .unwrap_or_default();
+    let usages: Vec<&str> = matches
+        .values_of("usage")
+        .map(|vals| vals.collect())
+        .unwrap_or_default();
+
+    if matches.is_present("create_key") {
+        key_manager::create_key_cmd(
+            matches.value_of("key_type"),
+            labels.clone(),
+            usages.clone(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("import_key") {
+        key_manager::import_key_cmd(
+            matches.value_of("import_key").unwrap(),
+            matches.value_of("kid"),
+            matches.value_of("key_type"),
+            matches.value_of("wrap_algorithm"),
+            labels.clone(),
+            usages.clone(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("get_public_key") {
+        key_manager::get_public_key_cmd(matches.value_of("get_public_key").unwrap(), &client_api)
+            .await;
+    }
+
+    if matches.is_present("sign") {
+        key_manager::sign_cmd(matches.values_of("sign").unwrap().collect(), &client_api).await;
+    }
+
+    if matches.is_present("rotate_key") {
+        key_manager::rotate_key_cmd(matches.value_of("rotate_key").unwrap(), &client_api).await;
+    }
+
+    if matches.is_present("set_key_usages") {
+        key_manager::set_key_usages_cmd(
+            matches.value_of("set_key_usages").unwrap(),
+            usages.clone(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("set_key_labels") {
+        key_manager::set_key_labels_cmd(
+            matches.value_of("set_key_labels").unwrap(),
+            labels.clone(),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("list_keys") {
+        key_manager::list_keys_cmd(labels.clone(), &client_api).await;
+    }
+
+    if matches.is_present("delete_key") {
+        key_manager::delete_key_cmd(
+            matches.value_of("delete_key").unwrap(),
+            matches.value_of("grace_period"),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("restore_key") {
+        key_manager::restore_key_cmd(matches.value_of("restore_key").unwrap(), &client_api).await;
+    }
+
+    if matches.is_present("backup") {
+        backup::backup_cmd(
+            matches.value_of("backup").unwrap(),
+            matches.value_of("passphrase_file"),
+            matches.value_of("public_key"),
+            matches.is_present("without_keys"),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("restore") {
+        backup::restore_cmd(
+            matches.value_of("restore").unwrap(),
+            matches.value_of("passphrase_file"),
+            matches.value_of("private_key"),
+            &client_api,
+        )
+        .await;
+    }
+
+    if matches.is_present("unseal") {
+        unseal::unseal_cmd(matches.value_of("unseal").unwrap(), &client_api).await;
+    }
+
     if matches.is_present("list_gpg_keys") {
         gpg::list_gpg_keys_cmd(&client_api).await;
     }
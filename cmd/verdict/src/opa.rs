@@ -3,13 +3,32 @@ use std::fs;
 use std::io::prelude::*;
 
 use crate::client_api::opa_service_client::OpaServiceClient;
+use crate::client_api::{AddCsvReferenceRequest, AddCsvReferenceResponse};
+use crate::client_api::{AddSgxReferenceRequest, AddSgxReferenceResponse};
+use crate::client_api::{AddSgxSigstructRequest, AddSgxSigstructResponse};
+use crate::client_api::{ClearOpaShadowPolicyRequest, ClearOpaShadowPolicyResponse};
+use crate::client_api::{DeleteOpaPolicyRequest, DeleteOpaPolicyResponse};
+use crate::client_api::{DeleteOpaReferenceRequest, DeleteOpaReferenceResponse};
 use crate::client_api::{ExportOpaPolicyRequest, ExportOpaPolicyResponse};
 use crate::client_api::{ExportOpaReferenceRequest, ExportOpaReferenceResponse};
+use crate::client_api::{GetOpaPolicySetRequest, GetOpaPolicySetResponse, OpaPolicyRef};
+use crate::client_api::{ListOpaPoliciesRequest, ListOpaPoliciesResponse};
+use crate::client_api::{ListOpaReferencesRequest, ListOpaReferencesResponse};
+use crate::client_api::{ListOpaShadowPoliciesRequest, ListOpaShadowPoliciesResponse};
+use crate::client_api::{PromoteOpaShadowPolicyRequest, PromoteOpaShadowPolicyResponse};
+use crate::client_api::{RemoveCsvReferenceRequest, RemoveCsvReferenceResponse};
+use crate::client_api::{RemoveSgxReferenceRequest, RemoveSgxReferenceResponse};
+use crate::client_api::{RevokeReferenceRequest, RevokeReferenceResponse};
+use crate::client_api::{RunOpaTestsRequest, RunOpaTestsResponse};
 use crate::client_api::{SetOpaPolicyRequest, SetOpaPolicyResponse};
+use crate::client_api::{SetOpaPolicySetRequest, SetOpaPolicySetResponse};
 use crate::client_api::{SetOpaReferenceRequest, SetOpaReferenceResponse};
+use crate::client_api::{SetOpaShadowPolicyRequest, SetOpaShadowPolicyResponse};
+use crate::client_api::{SetSgxMinimumSvnRequest, SetSgxMinimumSvnResponse};
 use crate::client_api::{TestOpaRequest, TestOpaResponse};
+use crate::client_api::{UnrevokeReferenceRequest, UnrevokeReferenceResponse};
 
-pub async fn set_policy_cmd(vals: Vec<&str>, addr: &str) {
+pub async fn set_policy_cmd(vals: Vec<&str>, tests_reference: Option<&str>, addr: &str) {
     let mut content = String::new();
 
     fs::File::open(vals[1])
@@ -17,9 +36,13 @@ pub async fn set_policy_cmd(vals: Vec<&str>, addr: &str) {
         .read_to_string(&mut content)
         .expect(&format!("Failed to read from the file named {}.", vals[1]));
 
+    // Tests are taken from the <POLICY_NAME>_test.rego file stored in verdictd
     let request = SetOpaPolicyRequest {
         name: vals[0].as_bytes().to_vec(),
         content: content.to_string().into_bytes(),
+        runtests: tests_reference.is_some(),
+        referencename: tests_reference.unwrap_or("").as_bytes().to_vec(),
+        tests: vec![],
     };
 
     let mut client = OpaServiceClient::connect(format!("http://{}", addr))
@@ -61,7 +84,7 @@ pub async fn export_policy_cmd(name: &str, path: String, addr: &str) {
         .expect("Faied to write the policy content into the file.");
 }
 
-pub async fn set_reference_cmd(vals: Vec<&str>, addr: &str) {
+pub async fn set_reference_cmd(vals: Vec<&str>, tee: Option<&str>, addr: &str) {
     let mut data = String::new();
 
     fs::File::open(vals[1])
@@ -74,6 +97,7 @@ pub async fn set_reference_cmd(vals: Vec<&str>, addr: &str) {
     let request = SetOpaReferenceRequest {
         name: vals[0].as_bytes().to_vec(),
         content: data.into_bytes(),
+        tee: tee.unwrap_or("").as_bytes().to_vec(),
     };
 
     let mut client = OpaServiceClient::connect(format!("http://{}", addr))
@@ -119,6 +143,467 @@ pub async fn export_reference_cmd(name: &str, path: String, addr: &str) {
         .expect("Faied to write the policy content into the file.");
 }
 
+// Seconds since the unix epoch, 0 for an unbounded validity window
+fn timestamp(time: Option<&str>) -> u64 {
+    time.map(|time| time.parse::<u64>().expect("Timestamp is not a number."))
+        .unwrap_or(0)
+}
+
+// An empty reference name lets verdictd patch the TEE type's default reference
+pub async fn add_sgx_reference_cmd(
+    reference: Option<&str>,
+    mrenclave: Option<&str>,
+    mrsigner: Option<&str>,
+    validity: (Option<&str>, Option<&str>),
+    addr: &str,
+) {
+    let request = AddSgxReferenceRequest {
+        name: reference.unwrap_or("").as_bytes().to_vec(),
+        mrenclave: mrenclave.unwrap_or("").as_bytes().to_vec(),
+        mrsigner: mrsigner.unwrap_or("").as_bytes().to_vec(),
+        notbefore: timestamp(validity.0),
+        notafter: timestamp(validity.1),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: AddSgxReferenceResponse = client
+        .add_sgx_reference(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "add_sgx_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn remove_sgx_reference_cmd(
+    reference: Option<&str>,
+    mrenclave: Option<&str>,
+    mrsigner: Option<&str>,
+    addr: &str,
+) {
+    let request = RemoveSgxReferenceRequest {
+        name: reference.unwrap_or("").as_bytes().to_vec(),
+        mrenclave: mrenclave.unwrap_or("").as_bytes().to_vec(),
+        mrsigner: mrsigner.unwrap_or("").as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RemoveSgxReferenceResponse = client
+        .remove_sgx_reference(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "remove_sgx_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+// path is a SIGSTRUCT file or a signed enclave
+pub async fn add_sgx_sigstruct_cmd(
+    reference: Option<&str>,
+    path: &str,
+    validity: (Option<&str>, Option<&str>),
+    set_svn: bool,
+    addr: &str,
+) {
+    let mut sigstruct = Vec::new();
+
+    fs::File::open(path)
+        .expect(&format!("Failed to open the file named {}.", path))
+        .read_to_end(&mut sigstruct)
+        .expect(&format!("Failed to read from the file named {}.", path));
+
+    let request = AddSgxSigstructRequest {
+        name: reference.unwrap_or("").as_bytes().to_vec(),
+        sigstruct,
+        notbefore: timestamp(validity.0),
+        notafter: timestamp(validity.1),
+        setsvn: set_svn,
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: AddSgxSigstructResponse = client
+        .add_sgx_sigstruct(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "add_sgx_sigstruct status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!(
+        "mrEnclave: {}, mrSigner: {}, productId: {}, svn: {}",
+        String::from_utf8(response.mrenclave).unwrap(),
+        String::from_utf8(response.mrsigner).unwrap(),
+        response.productid,
+        response.svn
+    );
+}
+
+pub async fn set_sgx_minimum_svn_cmd(reference: Option<&str>, svn: &str, addr: &str) {
+    let request = SetSgxMinimumSvnRequest {
+        name: reference.unwrap_or("").as_bytes().to_vec(),
+        svn: svn.parse::<u32>().expect("SVN is not a number."),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: SetSgxMinimumSvnResponse = client
+        .set_sgx_minimum_svn(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "set_sgx_minimum_svn status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn add_csv_reference_cmd(
+    reference: Option<&str>,
+    measure: &str,
+    validity: (Option<&str>, Option<&str>),
+    addr: &str,
+) {
+    let request = AddCsvReferenceRequest {
+        name: reference.unwrap_or("").as_bytes().to_vec(),
+        measure: measure.as_bytes().to_vec(),
+        notbefore: timestamp(validity.0),
+        notafter: timestamp(validity.1),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: AddCsvReferenceResponse = client
+        .add_csv_reference(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "add_csv_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn remove_csv_reference_cmd(reference: Option<&str>, measure: &str, addr: &str) {
+    let request = RemoveCsvReferenceRequest {
+        name: reference.unwrap_or("").as_bytes().to_vec(),
+        measure: measure.as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RemoveCsvReferenceResponse = client
+        .remove_csv_reference(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "remove_csv_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn revoke_reference_cmd(vals: Vec<&str>, addr: &str) {
+    let request = RevokeReferenceRequest {
+        kind: vals[0].as_bytes().to_vec(),
+        value: vals[1].as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RevokeReferenceResponse =
+        client.revoke_reference(request).await.unwrap().into_inner();
+    info!(
+        "revoke_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn unrevoke_reference_cmd(vals: Vec<&str>, addr: &str) {
+    let request = UnrevokeReferenceRequest {
+        kind: vals[0].as_bytes().to_vec(),
+        value: vals[1].as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: UnrevokeReferenceResponse = client
+        .unrevoke_reference(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "unrevoke_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn list_policies_cmd(addr: &str) {
+    let request = ListOpaPoliciesRequest {};
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: ListOpaPoliciesResponse = client
+        .list_opa_policies(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "list_opa_policies status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    for name in response.names {
+        info!("{}", String::from_utf8(name).unwrap());
+    }
+}
+
+pub async fn list_references_cmd(addr: &str) {
+    let request = ListOpaReferencesRequest {};
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: ListOpaReferencesResponse = client
+        .list_opa_references(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "list_opa_references status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    for name in response.names {
+        info!("{}", String::from_utf8(name).unwrap());
+    }
+}
+
+pub async fn delete_policy_cmd(name: &str, addr: &str) {
+    info!("delete OPA policy: {}", name);
+
+    let request = DeleteOpaPolicyRequest {
+        name: name.as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: DeleteOpaPolicyResponse = client
+        .delete_opa_policy(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "delete_opa_policy status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn delete_reference_cmd(name: &str, addr: &str) {
+    info!("delete OPA reference: {}", name);
+
+    let request = DeleteOpaReferenceRequest {
+        name: name.as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: DeleteOpaReferenceResponse = client
+        .delete_opa_reference(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "delete_opa_reference status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn set_shadow_policy_cmd(vals: Vec<&str>, reference: Option<&str>, addr: &str) {
+    info!("set {}'s shadow policy: {}", vals[0], vals[1]);
+
+    let request = SetOpaShadowPolicyRequest {
+        tee: vals[0].as_bytes().to_vec(),
+        policyname: vals[1].as_bytes().to_vec(),
+        referencename: reference.unwrap_or("").as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: SetOpaShadowPolicyResponse = client
+        .set_opa_shadow_policy(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "set_opa_shadow_policy status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn clear_shadow_policy_cmd(tee: &str, addr: &str) {
+    let request = ClearOpaShadowPolicyRequest {
+        tee: tee.as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: ClearOpaShadowPolicyResponse = client
+        .clear_opa_shadow_policy(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "clear_opa_shadow_policy status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn promote_shadow_policy_cmd(tee: &str, addr: &str) {
+    let request = PromoteOpaShadowPolicyRequest {
+        tee: tee.as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: PromoteOpaShadowPolicyResponse = client
+        .promote_opa_shadow_policy(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "promote_opa_shadow_policy status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn list_shadow_policies_cmd(addr: &str) {
+    let request = ListOpaShadowPoliciesRequest {};
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: ListOpaShadowPoliciesResponse = client
+        .list_opa_shadow_policies(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "list_opa_shadow_policies status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    for shadow in response.shadows {
+        info!(
+            "{}: policy: {}, reference: {}, evaluations: {}, disagreements: {}, errors: {}",
+            String::from_utf8(shadow.tee).unwrap(),
+            String::from_utf8(shadow.policyname).unwrap(),
+            String::from_utf8(shadow.referencename).unwrap(),
+            shadow.evaluations,
+            shadow.disagreements,
+            shadow.errors
+        );
+    }
+}
+
+// vals are the TEE type, the mode and the <POLICY_NAME>:<REFERENCE_NAME> pairs
+pub async fn set_policy_set_cmd(vals: Vec<&str>, addr: &str) {
+    let policies = vals[2..]
+        .iter()
+        .map(|pair| {
+            let (policy, reference) = pair
+                .split_once(':')
+                .expect("Policy is not in <POLICY_NAME>:<REFERENCE_NAME> format.");
+            OpaPolicyRef {
+                policyname: policy.as_bytes().to_vec(),
+                referencename: reference.as_bytes().to_vec(),
+            }
+        })
+        .collect();
+
+    let request = SetOpaPolicySetRequest {
+        tee: vals[0].as_bytes().to_vec(),
+        mode: vals[1].as_bytes().to_vec(),
+        policies,
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: SetOpaPolicySetResponse = client
+        .set_opa_policy_set(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "set_opa_policy_set status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+}
+
+pub async fn get_policy_set_cmd(tee: &str, addr: &str) {
+    let request = GetOpaPolicySetRequest {
+        tee: tee.as_bytes().to_vec(),
+    };
+
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: GetOpaPolicySetResponse = client
+        .get_opa_policy_set(request)
+        .await
+        .unwrap()
+        .into_inner();
+    info!(
+        "get_opa_policy_set status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    info!("mode: {}", String::from_utf8(response.mode).unwrap());
+    for policy_ref in response.policies {
+        info!(
+            "policy: {}, reference: {}",
+            String::from_utf8(policy_ref.policyname).unwrap(),
+            String::from_utf8(policy_ref.referencename).unwrap()
+        );
+    }
+}
+
 pub async fn test_remote_cmd(vals: Vec<&str>, addr: &str) {
     info!(
         "OPA Test remote: policy name: {}, reference name:{}, input file:{}",
@@ -284,3 +769,84 @@ pub async fn test_localreference_cmd(vals: Vec<&str>, addr: &str) {
         String::from_utf8(response.status).unwrap()
     );
 }
+
+async fn run_tests(request: RunOpaTestsRequest, addr: &str) {
+    let mut client = OpaServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: RunOpaTestsResponse = client.run_opa_tests(request).await.unwrap().into_inner();
+    info!(
+        "RunOpaTests status is: {:?}",
+        String::from_utf8(response.status).unwrap()
+    );
+    for result in response.results {
+        info!(
+            "{}.{}: {} {}",
+            String::from_utf8(result.package).unwrap(),
+            String::from_utf8(result.name).unwrap(),
+            if result.pass { "PASS" } else { "FAIL" },
+            String::from_utf8(result.error).unwrap()
+        );
+    }
+}
+
+pub async fn run_tests_remote_cmd(vals: Vec<&str>, addr: &str) {
+    info!(
+        "OPA run tests remote: policy name: {}, reference name:{}, tests name:{}",
+        vals[0], vals[1], vals[2]
+    );
+
+    let request = RunOpaTestsRequest {
+        policyname: vals[0].as_bytes().to_vec(),
+        policycontent: vec![],
+        policylocal: false,
+        referencename: vals[1].as_bytes().to_vec(),
+        referencecontent: vec![],
+        referencelocal: false,
+        testsname: vals[2].as_bytes().to_vec(),
+        testscontent: vec![],
+        testslocal: false,
+    };
+
+    run_tests(request, addr).await;
+}
+
+pub async fn run_tests_local_cmd(vals: Vec<&str>, addr: &str) {
+    info!(
+        "OPA run tests local: policy file: {}, reference file:{}, tests file:{}",
+        vals[0], vals[1], vals[2]
+    );
+
+    let mut policycontent = String::new();
+    fs::File::open(vals[0])
+        .expect(&format!("Failed to open the file named {}.", vals[0]))
+        .read_to_string(&mut policycontent)
+        .expect(&format!("Failed to read from the file named {}.", vals[0]));
+
+    let mut referencecontent = String::new();
+    fs::File::open(vals[1])
+        .expect(&format!("Failed to open the file named {}.", vals[1]))
+        .read_to_string(&mut referencecontent)
+        .expect(&format!("Failed to read from the file named {}.", vals[1]));
+
+    let mut testscontent = String::new();
+    fs::File::open(vals[2])
+        .expect(&format!("Failed to open the file named {}.", vals[2]))
+        .read_to_string(&mut testscontent)
+        .expect(&format!("Failed to read from the file named {}.", vals[2]));
+
+    let request = RunOpaTestsRequest {
+        policyname: vals[0].as_bytes().to_vec(),
+        policycontent: policycontent.into_bytes(),
+        policylocal: true,
+        referencename: vals[1].as_bytes().to_vec(),
+        referencecontent: referencecontent.into_bytes(),
+        referencelocal: true,
+        testsname: vals[2].as_bytes().to_vec(),
+        testscontent: testscontent.into_bytes(),
+        testslocal: true,
+    };
+
+    run_tests(request, addr).await;
+}
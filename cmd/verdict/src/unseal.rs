@@ -0,0 +1,36 @@
+use crate::client_api::unseal_service_client::UnsealServiceClient;
+use crate::client_api::{UnsealRequest, UnsealResponse};
+use std::fs;
+
+// The share is the first line of the share file, as printed by verdictd --split-master-key
+pub async fn unseal_cmd(share_path: &str, addr: &str) {
+    let share = fs::read_to_string(share_path)
+        .expect(&format!(
+            "Failed to read from the share file {}.",
+            share_path
+        ))
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let request = UnsealRequest {
+        share: share.into_bytes(),
+    };
+
+    let mut client = UnsealServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let response: UnsealResponse = client.unseal(request).await.unwrap().into_inner();
+    let status = String::from_utf8(response.status).unwrap();
+    info!("unseal status is: {:?}", status);
+    if status == "OK" {
+        match response.sealed {
+            true => info!(
+                "{} of {} shares are submitted, verdictd is still sealed",
+                response.received, response.threshold
+            ),
+            false => info!("verdictd is unsealed"),
+        }
+    }
+}
@@ -1,8 +1,16 @@
 use crate::attestation_agent::rats_tls;
-use crate::crypto::aes256_gcm;
+use crate::crypto::cipher::Cipher;
 use crate::resources;
+use crate::resources::key_store::{Scope, Usage};
+use crate::resources::opa_schema::{TEE_CSV, TEE_SGX};
 use base64;
 use serde_json::Value;
+use std::io;
+
+// Separates the derived keys from any other use of the root key
+const DERIVE_KEY_CONTEXT: &[u8] = b"verdictd derive key";
+const DERIVED_KEY_LEN: u64 = 32;
+const DERIVED_KEY_MAX_LEN: u64 = 64;
 
 fn handle_version() -> Result<String, String> {
     let mut response = serde_json::Map::new();
@@ -12,7 +20,7 @@ fn handle_version() -> Result<String, String> {
     Ok(Value::Object(response).to_string())
 }
 
-fn handle_decrypt(request: &Value) -> Result<String, String> {
+fn handle_decrypt(request: &Value, scope: &Scope) -> Result<String, String> {
     let blobs = match request["blobs"].as_array() {
         Some(blobs) => blobs,
         None => return Err("decrypt parameters error".to_string()),
@@ -23,25 +31,46 @@ fn handle_decrypt(request: &Value) -> Result<String, String> {
     let mut data = serde_json::Map::new();
 
     for blob in blobs {
-        if blob["algorithm"] != "AES"
-            || blob["key_length"] != 256
+        if !blob["algorithm"].is_string()
+            || !blob["key_length"].is_u64()
             || blob["encrypted_data"].is_null()
             || blob["iv"].is_null()
         {
             return Err("parameters error".to_string());
         }
 
-        match resources::directory_key_manager::get_key(&String::from(
-            blob["kid"].as_str().unwrap(),
-        ))
-        .map_err(|_| format!("kid: {}'s key not found", blob["kid"].to_string()))
-        .and_then(|key| {
-            let iv = base64::decode(blob["iv"].as_str().unwrap()).unwrap();
-            let encrypted_data = base64::decode(blob["encrypted_data"].as_str().unwrap()).unwrap();
-            aes256_gcm::decrypt(&encrypted_data, key.as_slice(), &iv)
-                .map_err(|_| "decryption failed".to_string())
-                .and_then(|decrypted_data| Ok(decrypted_data))
-        }) {
+        // Blobs without a key version were encrypted before the keys had versions
+        let key_version = blob["key_version"].as_u64().unwrap_or(1) as u32;
+        let kid = &scope.resolve(blob["kid"].as_str().unwrap_or(""))?;
+        let _operation = resources::key_store::begin_operation(kid);
+        match resources::key_store::check_usage(kid, Usage::Decrypt)
+            .and_then(|_| {
+                resources::key_store::get_key_type(kid)
+                    .map_err(|_| format!("kid: {}'s key not found", kid))
+            })
+            .and_then(|key_type| {
+                // The blob is decrypted with the type of key it was encrypted with
+                if key_type.algorithm() != blob["algorithm"]
+                    || key_type.length() as u64 != blob["key_length"]
+                {
+                    return Err(format!(
+                        "kid: {}'s key isn't {}-{}",
+                        kid,
+                        blob["algorithm"].as_str().unwrap(),
+                        blob["key_length"]
+                    ));
+                }
+                // Blobs without a cipher were encrypted with the key type's one
+                let cipher = match blob["cipher"].as_str() {
+                    Some(cipher) => Some(Cipher::parse(cipher)?),
+                    None => None,
+                };
+                let iv = base64::decode(blob["iv"].as_str().unwrap()).unwrap();
+                let encrypted_data =
+                    base64::decode(blob["encrypted_data"].as_str().unwrap()).unwrap();
+                resources::key_store::decrypt(kid, key_version, cipher, &encrypted_data, &iv, &[])
+                    .map_err(|_| "decryption failed".to_string())
+            }) {
             Ok(decrypted_data) => data.insert(
                 blob["encrypted_data"].as_str().unwrap().to_string(),
                 Value::String(base64::encode(decrypted_data)),
@@ -54,7 +83,7 @@ fn handle_decrypt(request: &Value) -> Result<String, String> {
     Ok(Value::Object(response).to_string())
 }
 
-fn handle_getKek(request: &Value) -> Result<String, String> {
+fn handle_getKek(request: &Value, scope: &Scope) -> Result<String, String> {
     let blobs = match request["kids"].as_array() {
         Some(blobs) => blobs,
         None => return Err("get KEK parameters error".to_string()),
@@ -63,18 +92,115 @@ fn handle_getKek(request: &Value) -> Result<String, String> {
     let mut response = serde_json::Map::new();
     response.insert("status".to_string(), Value::String("OK".to_string()));
     let mut data = serde_json::Map::new();
+    let mut versions = serde_json::Map::new();
 
     for index in 0..blobs.len() {
-        let kid = blobs[index].as_str().unwrap();
-        match resources::directory_key_manager::get_key(&String::from(kid))
-            .map_err(|_| format!("kid: {}'s key not found", kid))
-            .and_then(|key| Ok(key))
-        {
-            Ok(key) => data.insert(String::from(kid), Value::String(base64::encode(key))),
+        // The keys are returned under the kids as requested
+        let requested_kid = blobs[index].as_str().unwrap_or("");
+        let kid = &scope.resolve(requested_kid)?;
+        let _operation = resources::key_store::begin_operation(kid);
+        match resources::key_store::check_usage(kid, Usage::Export)
+            .and_then(|_| {
+                resources::key_store::get_key_type(kid)
+                    .map_err(|_| format!("kid: {}'s key not found", kid))
+            })
+            .and_then(|key_type| match key_type.is_symmetric() {
+                // The keys held by an HSM never leave it
+                true => resources::key_store::get_latest_key(kid).map_err(|e| match e.kind() {
+                    io::ErrorKind::PermissionDenied => e.to_string(),
+                    _ => format!("kid: {}'s key not found", kid),
+                }),
+                false => Err(format!("kid: {}'s key isn't a symmetric key", kid)),
+            }) {
+            Ok((version, key)) => {
+                versions.insert(String::from(requested_kid), Value::from(version));
+                data.insert(
+                    String::from(requested_kid),
+                    Value::String(base64::encode(key)),
+                )
+            }
             Err(e) => return Err(e),
         };
     }
     response.insert("data".to_string(), Value::Object(data));
+    response.insert("versions".to_string(), Value::Object(versions));
+
+    Ok(Value::Object(response).to_string())
+}
+
+// The HKDF info binding the derived key to the attested identity: the TEE type, the binding,
+// the values of the claims it names, the caller's label and the key's length, each prefixed
+// by its length. A shorter key isn't the prefix of a longer one for the same identity.
+fn derivation_info(
+    tee: &str,
+    evidence: &Value,
+    binding: &str,
+    label: &str,
+    length: u64,
+) -> Result<Vec<u8>, String> {
+    let claims: &[&str] = match (tee, binding) {
+        (TEE_SGX, "mrEnclave") => &["mrEnclave"],
+        (TEE_SGX, "mrSigner") => &["mrSigner", "productId"],
+        (TEE_CSV, "measure") => &["measure"],
+        _ => return Err(format!("{} keys can't be bound to {:?}", tee, binding)),
+    };
+
+    let mut fields = vec![tee.as_bytes().to_vec(), binding.as_bytes().to_vec()];
+    for claim in claims {
+        match &evidence[claim] {
+            Value::Null => return Err(format!("The evidence has no {}", claim)),
+            value => fields.push(value.to_string().into_bytes()),
+        }
+    }
+    fields.push(label.as_bytes().to_vec());
+    fields.push(length.to_be_bytes().to_vec());
+
+    let mut info = DERIVE_KEY_CONTEXT.to_vec();
+    for field in fields {
+        info.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        info.extend(field);
+    }
+    Ok(info)
+}
+
+fn handle_derive_key(request: &Value, scope: &Scope) -> Result<String, String> {
+    let (tee, evidence) = crate::rats_tls::RatsTls::verified_evidence()
+        .ok_or("Keys are only derived for the attested TEEs".to_string())?;
+    let binding = match request["binding"].as_str() {
+        Some(binding) => binding,
+        None if tee == TEE_CSV => "measure",
+        None => "mrEnclave",
+    };
+    let length = request["length"].as_u64().unwrap_or(DERIVED_KEY_LEN);
+    if length == 0 || length > DERIVED_KEY_MAX_LEN {
+        return Err(format!(
+            "length {} is out of 1..={}",
+            length, DERIVED_KEY_MAX_LEN
+        ));
+    }
+    let info = derivation_info(
+        tee,
+        &evidence,
+        binding,
+        request["label"].as_str().unwrap_or(""),
+        length,
+    )?;
+
+    // Without a key version the latest one is derived from, the TEE records it to derive the same key again
+    let key_version = request["key_version"]
+        .as_u64()
+        .map(|version| version as u32);
+    let kid = &scope.resolve(request["kid"].as_str().unwrap_or(""))?;
+    let _operation = resources::key_store::begin_operation(kid);
+    let (version, key) = resources::key_store::check_usage(kid, Usage::Derive)
+        .and_then(|_| resources::key_store::derive_key(kid, key_version, &info, length as usize))?;
+
+    let mut response = serde_json::Map::new();
+    response.insert("status".to_string(), Value::String("OK".to_string()));
+    let mut data = serde_json::Map::new();
+    data.insert("key".to_string(), Value::String(base64::encode(key)));
+    data.insert("key_version".to_string(), Value::from(version));
+    response.insert("data".to_string(), Value::Object(data));
 
     Ok(Value::Object(response).to_string())
 }
@@ -153,6 +279,57 @@ fn error_message2(e: String) -> Result<String, ()> {
     Ok(msg)
 }
 
+// The namespace the request's kids are resolved in, the one of the tenant the policies bound
+// the peer to, else the default namespace. The request's tenant must be the bound one,
+// only the peers bound to every namespace can pick a tenant.
+fn request_scope(request: &Value, bound: Option<String>) -> Result<Scope, String> {
+    let scope = match &bound {
+        Some(tenant) => Scope::granted(tenant)?,
+        None => Scope::default(),
+    };
+    match request["tenant"].as_str() {
+        None => Ok(scope),
+        Some(tenant) if scope == Scope::global() => Scope::tenant(tenant),
+        Some(tenant) if bound.as_deref() == Some(tenant) => Ok(scope),
+        Some(tenant) => Err(format!("The peer isn't bound to tenant {}", tenant)),
+    }
+}
+
+// The full kid of the requested kid, the policies see the tenant the kid belongs to
+fn resolve_kid(scope: &Scope, kid: &Value) -> Value {
+    match kid.as_str().map(|kid| scope.resolve(kid)) {
+        Some(Ok(kid)) => Value::String(kid),
+        _ => kid.clone(),
+    }
+}
+
+// Resource the request asks for, the policies see it as `input.context.resource`
+fn requested_resource(request: &Value, scope: &Scope) -> Value {
+    match request["command"].as_str().unwrap_or("") {
+        "Decrypt" => match request["blobs"].as_array() {
+            Some(blobs) => Value::Array(
+                blobs
+                    .iter()
+                    .map(|blob| resolve_kid(scope, &blob["kid"]))
+                    .collect(),
+            ),
+            None => Value::Null,
+        },
+        "Get KEK" => match request["kids"].as_array() {
+            Some(kids) => Value::Array(kids.iter().map(|kid| resolve_kid(scope, kid)).collect()),
+            None => request["kids"].clone(),
+        },
+        "Derive Key" => resolve_kid(scope, &request["kid"]),
+        "Get Resource Info" => request["name"].clone(),
+        "Get Policy" => Value::String("Policy".to_string()),
+        "Get Sigstore Config" => Value::String("Sigstore Config".to_string()),
+        "Get GPG Keyring" => Value::String("GPG Keyring".to_string()),
+        "Get Cosign Key" => Value::String("Cosign Key".to_string()),
+        "Get Credential" => Value::String("Credential".to_string()),
+        _ => Value::Null,
+    }
+}
+
 pub fn handle(request: &[u8]) -> Result<(String, u8), String> {
     let parsed_request: Value = match serde_json::from_slice(request) {
         Ok(r) => r,
@@ -160,19 +337,38 @@ pub fn handle(request: &[u8]) -> Result<(String, u8), String> {
     };
     info!("Request: {:?}", parsed_request);
 
+    let scope = match request_scope(&parsed_request, crate::rats_tls::RatsTls::tenant()) {
+        Ok(scope) => scope,
+        Err(e) => return Ok((error_message(e).unwrap(), rats_tls::ACTION_NONE)),
+    };
+
+    let command = parsed_request["command"].as_str().unwrap_or("");
+    if let Err(e) =
+        crate::rats_tls::RatsTls::authorize(command, requested_resource(&parsed_request, &scope))
+    {
+        error!("command {} is denied by policy: {}", command, e);
+        let response = error_message(format!("Denied by policy: {}", e)).unwrap();
+        return Ok((response, rats_tls::ACTION_NONE));
+    }
+
     let response = match parsed_request["command"].as_str().unwrap() {
         "version" => {
             let response = handle_version().unwrap();
             Ok((response, rats_tls::ACTION_NONE))
         }
         "Decrypt" => {
-            let response =
-                handle_decrypt(&parsed_request).unwrap_or_else(|e| error_message(e).unwrap());
+            let response = handle_decrypt(&parsed_request, &scope)
+                .unwrap_or_else(|e| error_message(e).unwrap());
             Ok((response, rats_tls::ACTION_NONE))
         }
         "Get KEK" => {
-            let response =
-                handle_getKek(&parsed_request).unwrap_or_else(|e| error_message(e).unwrap());
+            let response = handle_getKek(&parsed_request, &scope)
+                .unwrap_or_else(|e| error_message(e).unwrap());
+            Ok((response, rats_tls::ACTION_NONE))
+        }
+        "Derive Key" => {
+            let response = handle_derive_key(&parsed_request, &scope)
+                .unwrap_or_else(|e| error_message(e).unwrap());
             Ok((response, rats_tls::ACTION_NONE))
         }
         "echo" => {
@@ -227,6 +423,7 @@ mod tests {
 
     #[test]
     fn test_handle_decrypt() {
+        let _lock = resources::key_store::TEST_LOCK.lock();
         let request = serde_json::json!({
             "blobs": [
                 {
@@ -238,21 +435,66 @@ mod tests {
                 }
             ]
         });
-        let result = handle_decrypt(&request);
+        let result = handle_decrypt(&request, &Scope::global());
         assert!(result.is_err());
     }
 
     #[test]
     fn test_handle_getKek() {
+        let _lock = resources::key_store::TEST_LOCK.lock();
         let request = serde_json::json!({
             "kids": [
                 "kid"
             ]
         });
-        let result = handle_getKek(&request);
+        let result = handle_getKek(&request, &Scope::global());
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_handle_derive_key() {
+        let _lock = resources::key_store::TEST_LOCK.lock();
+        let request = serde_json::json!({ "kid": "kid" });
+        let result = handle_derive_key(&request, &Scope::global());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_derivation_info() {
+        let evidence = serde_json::json!({
+            "mrEnclave": "enclave",
+            "mrSigner": "signer",
+            "productId": 1,
+            "svn": 2
+        });
+        let info = derivation_info(TEE_SGX, &evidence, "mrEnclave", "", 32).unwrap();
+        assert!(info.starts_with(DERIVE_KEY_CONTEXT));
+        assert_eq!(
+            info,
+            derivation_info(TEE_SGX, &evidence, "mrEnclave", "", 32).unwrap()
+        );
+        assert_ne!(
+            info,
+            derivation_info(TEE_SGX, &evidence, "mrEnclave", "db", 32).unwrap()
+        );
+        assert_ne!(
+            info,
+            derivation_info(TEE_SGX, &evidence, "mrSigner", "", 32).unwrap()
+        );
+
+        let other = serde_json::json!({ "mrEnclave": "other" });
+        assert_ne!(
+            info,
+            derivation_info(TEE_SGX, &other, "mrEnclave", "", 32).unwrap()
+        );
+        assert!(derivation_info(TEE_SGX, &other, "mrSigner", "", 32).is_err());
+        assert!(derivation_info(TEE_SGX, &evidence, "measure", "", 32).is_err());
+        assert_ne!(
+            info,
+            derivation_info(TEE_SGX, &evidence, "mrEnclave", "", 16).unwrap()
+        );
+    }
+
     #[test]
     fn test_handle_echo() {
         let request = serde_json::json!({
@@ -289,6 +531,56 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_request_scope() {
+        let request = serde_json::json!({ "command": "Get KEK" });
+        assert_eq!(request_scope(&request, None), Ok(Scope::default()));
+        assert_eq!(
+            request_scope(&request, Some("team-a".to_string())),
+            Scope::tenant("team-a")
+        );
+
+        let request = serde_json::json!({ "command": "Get KEK", "tenant": "team-a" });
+        assert!(request_scope(&request, None).is_err());
+        assert!(request_scope(&request, Some("team-b".to_string())).is_err());
+        assert_eq!(
+            request_scope(&request, Some("team-a".to_string())),
+            Scope::tenant("team-a")
+        );
+        assert_eq!(
+            request_scope(&request, Some("*".to_string())),
+            Scope::tenant("team-a")
+        );
+    }
+
+    #[test]
+    fn test_requested_resource() {
+        let request = serde_json::json!({
+            "command": "Decrypt",
+            "blobs": [{ "kid": "kid1" }, { "kid": "kid2" }]
+        });
+        assert_eq!(
+            requested_resource(&request, &Scope::global()),
+            serde_json::json!(["kid1", "kid2"])
+        );
+
+        let request = serde_json::json!({ "command": "Get KEK", "kids": ["kid1"] });
+        let scope = Scope::tenant("team-a").unwrap();
+        assert_eq!(
+            requested_resource(&request, &scope),
+            serde_json::json!(["team-a/kid1"])
+        );
+
+        let request = serde_json::json!({ "command": "Derive Key", "kid": "root" });
+        assert_eq!(requested_resource(&request, &scope), "team-a/root");
+
+        let request = serde_json::json!({ "command": "Get Policy" });
+        assert_eq!(requested_resource(&request, &Scope::global()), "Policy");
+
+        let request = serde_json::json!({ "command": "echo", "data": "data" });
+        assert!(requested_resource(&request, &Scope::global()).is_null());
+    }
+
     #[test]
     fn test_error_message() {
         let result = error_message(String::from("error"));
@@ -1,5 +1,6 @@
 use crate::attestation_agent::protocol;
 use crate::rats_tls;
+use crate::resources::unseal;
 use std::net::TcpListener;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::{sync::Arc, u64};
@@ -9,6 +10,8 @@ pub const ACTION_DISCONNECT: u8 = 1;
 
 fn handle_client(
     sockfd: RawFd,
+    peer: String,
+    listener_name: &str,
     tls_type: &Option<String>,
     crypto: &Option<String>,
     attester: &Option<String>,
@@ -16,11 +19,27 @@ fn handle_client(
     mutual: bool,
     enclave_id: u64,
 ) -> Result<(), String> {
+    // No key can be handed out before the master key is recovered
+    if unseal::is_sealed() {
+        return Err(format!("verdictd is sealed, refuse {}", peer));
+    }
+
     let tls = rats_tls::RatsTls::new(
         true, enclave_id, tls_type, crypto, attester, verifier, mutual,
     )
     .map_err(|e| format!("new RatsTls failed with error {:?}", e))?;
 
+    // The policies decide on the connection's context besides the evidence
+    rats_tls::RatsTls::set_context(rats_tls::PolicyContext {
+        peer,
+        listener: listener_name.to_string(),
+        tls_type: tls_type.clone().unwrap_or_default(),
+        crypto: crypto.clone().unwrap_or_default(),
+        attester: attester.clone().unwrap_or_default(),
+        verifier: verifier.clone().unwrap_or_default(),
+        ..Default::default()
+    });
+
     /* accept */
     if tls.negotiate(sockfd).is_err() {
         return Err(format!("tls_negotiate() failed, sockfd = {}", sockfd));
@@ -49,12 +68,14 @@ fn handle_client(
 
 pub fn server(
     sockaddr: &str,
+    listener_name: String,
     tls_type: String,
     crypto: String,
     attester: String,
     verifier: String,
     mutual: bool,
 ) {
+    let listener_name = Arc::new(listener_name);
     let tls_type = Arc::new(Some(tls_type));
     let crypto = Arc::new(Some(crypto));
     let attester = Arc::new(Some(attester));
@@ -65,6 +86,7 @@ pub fn server(
     loop {
         let (socket, addr) = listener.accept().unwrap();
         info!("thread for {} {:?}", socket.as_raw_fd(), addr);
+        let listener_name = listener_name.clone();
         let tls_type = tls_type.clone();
         let crypto = crypto.clone();
         let attester = attester.clone();
@@ -72,6 +94,8 @@ pub fn server(
         std::thread::spawn(move || {
             match handle_client(
                 socket.as_raw_fd(),
+                addr.to_string(),
+                &listener_name,
                 &tls_type,
                 &crypto,
                 &attester,
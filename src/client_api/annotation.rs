@@ -2,13 +2,56 @@ extern crate serde;
 
 use self::serde::{Deserialize, Serialize};
 
+// Prefixes the additional data the wrapped data is bound to the annotation with
+const AAD_CONTEXT: &[u8] = b"verdictd annotation";
+
+// Images encrypted before the keys had versions were encrypted with the first one
+fn default_key_version() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AnnotationPacket {
     pub kid: String,
+    // Version of the kid's key the data is wrapped with
+    #[serde(default = "default_key_version")]
+    pub key_version: u32,
     pub wrapped_data: Vec<u8>,
     pub iv: Vec<u8>,
     pub algorithm: String,
     pub key_length: u16,
+    // Cipher the data is wrapped with, the key type's one for the images wrapped before the ciphers could be chosen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<String>,
+    // Image or layer the data is wrapped for, as given along the kid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+    // Whether the wrapped data is bound to the annotation by aad(), the legacy annotations aren't
+    #[serde(default)]
+    pub aad: bool,
+}
+
+impl AnnotationPacket {
+    /// The additional data the wrapped data is authenticated with: the kid, the algorithm,
+    /// the key length, the cipher and the layer, each prefixed by its length. The data wrapped
+    /// for a packet can't be unwrapped under another one. The key version needs no binding,
+    /// another version is another key.
+    pub fn aad(&self) -> Vec<u8> {
+        let fields = [
+            self.kid.as_bytes().to_vec(),
+            self.algorithm.as_bytes().to_vec(),
+            self.key_length.to_be_bytes().to_vec(),
+            self.cipher.as_deref().unwrap_or("").as_bytes().to_vec(),
+            self.layer.as_deref().unwrap_or("").as_bytes().to_vec(),
+        ];
+
+        let mut aad = AAD_CONTEXT.to_vec();
+        for field in fields {
+            aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            aad.extend(field);
+        }
+        aad
+    }
 }
 
 #[cfg(test)]
@@ -19,19 +62,59 @@ mod tests {
     fn test_serialization_deserialization() {
         let packet = AnnotationPacket {
             kid: "test".to_string(),
+            key_version: 2,
             wrapped_data: vec![0x01, 0x02, 0x03],
             iv: vec![0x04, 0x05, 0x06],
             algorithm: "AES".to_string(),
             key_length: 256,
+            cipher: Some("AES-256-CBC-HMAC-SHA256".to_string()),
+            layer: Some("sha256:0123".to_string()),
+            aad: true,
         };
 
         let serialized = serde_json::to_string(&packet).unwrap();
         let deserialized: AnnotationPacket = serde_json::from_str(&serialized).unwrap();
 
         assert_eq!(deserialized.kid, packet.kid);
+        assert_eq!(deserialized.key_version, packet.key_version);
         assert_eq!(deserialized.wrapped_data, packet.wrapped_data);
         assert_eq!(deserialized.iv, packet.iv);
         assert_eq!(deserialized.algorithm, packet.algorithm);
         assert_eq!(deserialized.key_length, packet.key_length);
+        assert_eq!(deserialized.cipher, packet.cipher);
+        assert_eq!(deserialized.layer, packet.layer);
+        assert_eq!(deserialized.aad(), packet.aad());
+    }
+
+    #[test]
+    fn test_deserialization_without_version() {
+        let packet =
+            r#"{"kid":"test","wrapped_data":[1],"iv":[2],"algorithm":"AES","key_length":256}"#;
+        let deserialized: AnnotationPacket = serde_json::from_str(packet).unwrap();
+        assert_eq!(deserialized.key_version, 1);
+        assert_eq!(deserialized.cipher, None);
+        assert!(!deserialized.aad);
+    }
+
+    #[test]
+    fn test_aad() {
+        let packet = |kid: &str, layer: Option<&str>| AnnotationPacket {
+            kid: kid.to_string(),
+            key_version: 1,
+            wrapped_data: vec![],
+            iv: vec![],
+            algorithm: "AES".to_string(),
+            key_length: 256,
+            cipher: Some("AES-256-GCM".to_string()),
+            layer: layer.map(|layer| layer.to_string()),
+            aad: true,
+        };
+
+        let aad = packet("kid", Some("layer")).aad();
+        assert!(aad.starts_with(AAD_CONTEXT));
+        assert_eq!(aad, packet("kid", Some("layer")).aad());
+        assert_ne!(aad, packet("other", Some("layer")).aad());
+        assert_ne!(aad, packet("kid", None).aad());
+        assert_ne!(packet("kidl", None).aad(), packet("kid", Some("l")).aad());
     }
 }
@@ -1,30 +1,55 @@
 use crate::client_api;
+use crate::resources::unseal;
 use tonic::transport::Server;
+use tonic::{Request, Status};
 
+use clientApi::backup_service_server::BackupServiceServer;
 use clientApi::gpg_service_server::GpgServiceServer;
 use clientApi::image_service_server::ImageServiceServer;
 use clientApi::key_manager_service_server::KeyManagerServiceServer;
 use clientApi::opa_service_server::OpaServiceServer;
+use clientApi::unseal_service_server::UnsealServiceServer;
 use client_api::key_provider::keyProvider::key_provider_service_server::KeyProviderServiceServer;
 
 pub mod clientApi {
     tonic::include_proto!("clientapi");
 }
 
+// The services handing out or using the keys are refused until verdictd is unsealed
+fn unsealed(request: Request<()>) -> Result<Request<()>, Status> {
+    match unseal::is_sealed() {
+        true => Err(Status::unavailable("verdictd is sealed")),
+        false => Ok(request),
+    }
+}
+
 pub async fn server(addr: &str) -> Result<(), Box<dyn std::error::Error>> {
     let addr = addr.parse()?;
+    let backup_service = client_api::backup::backupService::default();
     let gpg_service = client_api::gpg::gpgService::default();
     let image_service = client_api::image::imageService::default();
     let key_manager_service = client_api::key_manager::keyManagerService::default();
     let key_provider_service = client_api::key_provider::keyProviderService::default();
     let opa_service = client_api::opa::opaService::default();
+    let unseal_service = client_api::unseal::unsealService::default();
 
     Server::builder()
+        .add_service(BackupServiceServer::with_interceptor(
+            backup_service,
+            unsealed,
+        ))
         .add_service(GpgServiceServer::new(gpg_service))
         .add_service(ImageServiceServer::new(image_service))
-        .add_service(KeyManagerServiceServer::new(key_manager_service))
-        .add_service(KeyProviderServiceServer::new(key_provider_service))
+        .add_service(KeyManagerServiceServer::with_interceptor(
+            key_manager_service,
+            unsealed,
+        ))
+        .add_service(KeyProviderServiceServer::with_interceptor(
+            key_provider_service,
+            unsealed,
+        ))
         .add_service(OpaServiceServer::new(opa_service))
+        .add_service(UnsealServiceServer::new(unseal_service))
         .serve(addr)
         .await?;
 
@@ -0,0 +1,82 @@
+use crate::client_api::api;
+use crate::client_api::key_manager::client_address;
+use crate::resources::audit;
+use crate::resources::backup::{self, Protection, Unlock};
+use tonic::{Request, Response, Status};
+
+use api::clientApi::backup_service_server::BackupService;
+use api::clientApi::{BackupRequest, BackupResponse};
+use api::clientApi::{RestoreRequest, RestoreResponse};
+
+#[derive(Debug, Default)]
+pub struct backupService {}
+
+#[tonic::async_trait]
+impl BackupService for backupService {
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> Result<Response<BackupResponse>, Status> {
+        let client = client_address(&request);
+        let request: BackupRequest = request.into_inner();
+
+        let res = match (request.passphrase.is_empty(), request.publickey.is_empty()) {
+            (false, true) => String::from_utf8(request.passphrase)
+                .map(Protection::Passphrase)
+                .map_err(|_| "parse passphrase failed".to_string()),
+            (true, false) => String::from_utf8(request.publickey)
+                .map(Protection::PublicKey)
+                .map_err(|_| "parse public key failed".to_string()),
+            _ => Err("Either a passphrase or a public key is needed".to_string()),
+        }
+        .and_then(|protection| backup::backup(&protection, request.withoutkeys));
+        let result = res.as_ref().map(|_| ()).map_err(|e| e.clone());
+        audit::record("backup", audit::ALL_KIDS, &client, &result);
+
+        let res = res
+            .and_then(|archive| {
+                let res = BackupResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    archive,
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| BackupResponse {
+                status: format!("Backup failed: {}", e).into_bytes(),
+                archive: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn restore(
+        &self,
+        request: Request<RestoreRequest>,
+    ) -> Result<Response<RestoreResponse>, Status> {
+        let client = client_address(&request);
+        let request: RestoreRequest = request.into_inner();
+
+        let res = match (request.passphrase.is_empty(), request.datakey.is_empty()) {
+            (false, true) => String::from_utf8(request.passphrase)
+                .map(Unlock::Passphrase)
+                .map_err(|_| "parse passphrase failed".to_string()),
+            (true, false) => Ok(Unlock::DataKey(request.datakey)),
+            _ => Err("Either a passphrase or a data key is needed".to_string()),
+        }
+        .and_then(|unlock| backup::restore(&request.archive, &unlock));
+        audit::record("restore-backup", audit::ALL_KIDS, &client, &res);
+
+        let res = res
+            .and_then(|_| {
+                let res = RestoreResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| RestoreResponse {
+                status: format!("Restore failed: {}", e).into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+}
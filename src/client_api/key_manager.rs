@@ -1,38 +1,224 @@
 use crate::client_api::api;
-use crate::resources::directory_key_manager;
+use crate::crypto::key_type::KeyType;
+use crate::resources::audit;
+use crate::resources::import_key;
+use crate::resources::key_store;
+use crate::resources::key_store::{KeyMetadata, Scope, Usage};
 use base64;
-use rand::*;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
 use api::clientApi::key_manager_service_server::KeyManagerService;
 use api::clientApi::{CreateKeyRequest, CreateKeyResponse};
 use api::clientApi::{DeleteKeyRequest, DeleteKeyResponse};
+use api::clientApi::{GetImportKeyRequest, GetImportKeyResponse};
 use api::clientApi::{GetKeyRequest, GetKeyResponse};
+use api::clientApi::{GetPublicKeyRequest, GetPublicKeyResponse};
+use api::clientApi::{ImportKeyRequest, ImportKeyResponse};
+use api::clientApi::{KeyInfo, KeyLabel};
+use api::clientApi::{ListKeysRequest, ListKeysResponse};
+use api::clientApi::{RestoreKeyRequest, RestoreKeyResponse};
+use api::clientApi::{RotateKeyRequest, RotateKeyResponse};
+use api::clientApi::{SetKeyLabelsRequest, SetKeyLabelsResponse};
+use api::clientApi::{SetKeyUsagesRequest, SetKeyUsagesResponse};
+use api::clientApi::{SignRequest, SignResponse};
 
 #[derive(Debug, Default)]
 pub struct keyManagerService {}
 
+// Address of the client API caller, recorded by the audit log
+pub(crate) fn client_address<T>(request: &Request<T>) -> String {
+    request
+        .remote_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// gRPC metadata carrying the caller's credential, "Bearer <TOKEN>"
+const AUTHORIZATION_METADATA: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+lazy_static! {
+    // The tenant each credential is bound to, keyed by the SHA-256 digest of its token
+    static ref CREDENTIALS: RwLock<HashMap<Vec<u8>, String>> = RwLock::new(HashMap::new());
+}
+
+/// Load the credentials from the json file {"<TOKEN>": "<TENANT>", ...}, the requests
+/// carrying a token manage the keys of its tenant, every tenant's for "*". Return how many are loaded.
+pub fn load_credentials(path: &str) -> Result<usize, String> {
+    let credentials: HashMap<String, String> = fs::read_to_string(path)
+        .map_err(|e| format!("Read {} failed: {}", path, e))
+        .and_then(|credentials| {
+            serde_json::from_str(&credentials).map_err(|e| format!("Parse {} failed: {}", path, e))
+        })?;
+
+    let mut loaded = HashMap::new();
+    for (token, tenant) in credentials {
+        if token.is_empty() {
+            return Err("A credential's token is empty".to_string());
+        }
+        Scope::granted(&tenant)?;
+        loaded.insert(Sha256::digest(token.as_bytes()).to_vec(), tenant);
+    }
+
+    let count = loaded.len();
+    *CREDENTIALS.write() = loaded;
+    Ok(count)
+}
+
+// Requests carrying a credential reach the keys of the tenant it's bound to,
+// the others only reach the default namespace
+pub(crate) fn request_scope<T>(request: &Request<T>) -> Result<Scope, String> {
+    let authorization = match request.metadata().get(AUTHORIZATION_METADATA) {
+        Some(authorization) => authorization,
+        None => return Ok(Scope::default()),
+    };
+    let token = authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix(BEARER_PREFIX))
+        .ok_or("parse authorization failed".to_string())?;
+
+    match CREDENTIALS
+        .read()
+        .get(Sha256::digest(token.as_bytes()).as_slice())
+    {
+        Some(tenant) => Scope::granted(tenant),
+        None => Err("unknown credential".to_string()),
+    }
+}
+
+// The full kid of the uuid requested within the scope
+fn resolve_kid(scope: &Result<Scope, String>, uuid: Vec<u8>) -> Result<String, String> {
+    let kid = String::from_utf8(uuid).map_err(|_| "parse uuid failed".to_string())?;
+    scope.clone()?.resolve(&kid)
+}
+
+fn parse_labels(labels: Vec<KeyLabel>) -> Result<BTreeMap<String, String>, String> {
+    labels
+        .into_iter()
+        .map(|label| {
+            let name = String::from_utf8(label.name).map_err(|_| "parse label name failed")?;
+            let value = String::from_utf8(label.value).map_err(|_| "parse label value failed")?;
+            if name.is_empty() {
+                return Err("label name is empty".to_string());
+            }
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn parse_usages(usages: Vec<Vec<u8>>) -> Result<BTreeSet<Usage>, String> {
+    usages
+        .into_iter()
+        .map(|usage| {
+            String::from_utf8(usage)
+                .map_err(|_| "parse usage failed".to_string())
+                .and_then(|usage| Usage::parse(&usage))
+        })
+        .collect()
+}
+
+// The metadata of a new key, which can be used for everything but deriving unless its usages are given
+fn new_metadata(
+    key_type: KeyType,
+    labels: BTreeMap<String, String>,
+    usages: BTreeSet<Usage>,
+) -> KeyMetadata {
+    let mut metadata = KeyMetadata::new(key_type);
+    metadata.labels = labels;
+    if !usages.is_empty() {
+        metadata.usages = usages;
+    }
+    metadata
+}
+
+// The requested key type, AES-256 if no algorithm is given
+fn parse_key_type(algorithm: Vec<u8>, length: u32) -> Result<KeyType, String> {
+    let algorithm = String::from_utf8(algorithm).map_err(|_| "parse algorithm failed")?;
+    match algorithm.is_empty() {
+        true => Ok(KeyType::Aes256),
+        false => KeyType::parse(&algorithm, length),
+    }
+}
+
+fn import(request: ImportKeyRequest, kid: &str) -> Result<(), String> {
+    let wrap_algorithm =
+        String::from_utf8(request.wrapalgorithm).map_err(|_| "parse wrap algorithm failed")?;
+    let key_type = parse_key_type(request.algorithm, request.length)?;
+    let labels = parse_labels(request.labels)?;
+    let usages = parse_usages(request.usages)?;
+
+    let key = import_key::unwrap(
+        &wrap_algorithm,
+        &request.wrappedkey,
+        &request.ephemeralkey,
+        &request.iv,
+    )?;
+    key_type.check(&key)?;
+
+    key_store::add_key(kid, &key, &new_metadata(key_type, labels, usages))
+}
+
+fn key_info(kid: String, metadata: KeyMetadata) -> KeyInfo {
+    KeyInfo {
+        kid: kid.into_bytes(),
+        algorithm: metadata.algorithm.as_bytes().to_vec(),
+        length: metadata.length,
+        created: metadata.created,
+        lastused: metadata.last_used,
+        state: metadata.state().as_bytes().to_vec(),
+        purgeafter: metadata.purge_after.unwrap_or_default(),
+        version: metadata.version,
+        usages: metadata
+            .usages
+            .iter()
+            .map(|usage| usage.as_str().as_bytes().to_vec())
+            .collect(),
+        labels: metadata
+            .labels
+            .into_iter()
+            .map(|(name, value)| KeyLabel {
+                name: name.into_bytes(),
+                value: value.into_bytes(),
+            })
+            .collect(),
+    }
+}
+
 #[tonic::async_trait]
 impl KeyManagerService for keyManagerService {
     async fn create_key(
         &self,
-        _request: Request<CreateKeyRequest>,
+        request: Request<CreateKeyRequest>,
     ) -> Result<Response<CreateKeyResponse>, Status> {
-        let kid = Uuid::new_v4().to_string();
-        // generate a new key file with a new random key
-        let mut key: [u8; 32] = [0; 32];
-        rand::rngs::OsRng.fill_bytes(&mut key);
-        let res = directory_key_manager::set_key(&kid, &key)
+        let scope = request_scope(&request);
+        let request: CreateKeyRequest = request.into_inner();
+        let kid = resolve_kid(&scope, Uuid::new_v4().to_string().into_bytes());
+        let res = kid
+            .clone()
+            .and_then(|kid| {
+                let key_type = parse_key_type(request.algorithm, request.length)?;
+                let labels = parse_labels(request.labels)?;
+                let usages = parse_usages(request.usages)?;
+                // generate a new random key
+                key_store::generate_key(&kid, &new_metadata(key_type, labels, usages))
+                    .map_err(|e| e.to_string())
+            })
             .and_then(|_| {
                 let res = CreateKeyResponse {
                     status: "OK".as_bytes().to_vec(),
-                    uuid: kid.into_bytes(),
+                    uuid: kid.unwrap_or_default().into_bytes(),
                 };
                 Ok(res)
             })
-            .unwrap_or_else(|_| CreateKeyResponse {
-                status: "Greate key failed".as_bytes().to_vec(),
+            .unwrap_or_else(|e| CreateKeyResponse {
+                status: format!("Greate key failed: {}", e).into_bytes(),
                 uuid: "".as_bytes().to_vec(),
             });
 
@@ -43,11 +229,12 @@ impl KeyManagerService for keyManagerService {
         &self,
         request: Request<GetKeyRequest>,
     ) -> Result<Response<GetKeyResponse>, Status> {
-        let kid = String::from_utf8(request.into_inner().uuid)
+        let scope = request_scope(&request);
+        let kid = resolve_kid(&scope, request.into_inner().uuid)
             .unwrap_or_else(|_| "00000000-0000-0000-0000-000000000000".to_string());
         info!("kid: {}", kid);
 
-        let res = directory_key_manager::get_key(&kid)
+        let res = key_store::get_key(&kid)
             .and_then(|data| {
                 let res = GetKeyResponse {
                     status: "OK".as_bytes().to_vec(),
@@ -65,11 +252,293 @@ impl KeyManagerService for keyManagerService {
 
     async fn delete_key(
         &self,
-        _request: Request<DeleteKeyRequest>,
+        request: Request<DeleteKeyRequest>,
     ) -> Result<Response<DeleteKeyResponse>, Status> {
-        let res = DeleteKeyResponse {
-            status: "Not implemented".as_bytes().to_vec(),
+        let client = client_address(&request);
+        let scope = request_scope(&request);
+        let request: DeleteKeyRequest = request.into_inner();
+
+        let kid = resolve_kid(&scope, request.uuid);
+        let res = kid
+            .clone()
+            .and_then(|kid| key_store::delete_key(&kid, request.graceperiod));
+        let action = match request.graceperiod {
+            0 => "delete",
+            _ => "soft-delete",
         };
+        audit::record(action, &kid.unwrap_or_default(), &client, &res);
+
+        let res = res
+            .and_then(|_| {
+                let res = DeleteKeyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| DeleteKeyResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn restore_key(
+        &self,
+        request: Request<RestoreKeyRequest>,
+    ) -> Result<Response<RestoreKeyResponse>, Status> {
+        let client = client_address(&request);
+        let scope = request_scope(&request);
+        let request: RestoreKeyRequest = request.into_inner();
+
+        let kid = resolve_kid(&scope, request.uuid);
+        let res = kid.clone().and_then(|kid| key_store::restore_key(&kid));
+        audit::record("restore", &kid.unwrap_or_default(), &client, &res);
+
+        let res = res
+            .and_then(|_| {
+                let res = RestoreKeyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| RestoreKeyResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn get_import_key(
+        &self,
+        request: Request<GetImportKeyRequest>,
+    ) -> Result<Response<GetImportKeyResponse>, Status> {
+        let request: GetImportKeyRequest = request.into_inner();
+
+        let res = String::from_utf8(request.wrapalgorithm)
+            .map_err(|_| "parse wrap algorithm failed".to_string())
+            .and_then(|wrap_algorithm| import_key::public_key(&wrap_algorithm))
+            .and_then(|public_key| {
+                let res = GetImportKeyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    publickey: public_key.into_bytes(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| GetImportKeyResponse {
+                status: e.into_bytes(),
+                publickey: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn import_key(
+        &self,
+        request: Request<ImportKeyRequest>,
+    ) -> Result<Response<ImportKeyResponse>, Status> {
+        let client = client_address(&request);
+        let scope = request_scope(&request);
+        let request: ImportKeyRequest = request.into_inner();
+
+        let kid = match request.uuid.is_empty() {
+            true => resolve_kid(&scope, Uuid::new_v4().to_string().into_bytes()),
+            false => resolve_kid(&scope, request.uuid.clone()),
+        };
+        let res = kid.clone().and_then(|kid| import(request, &kid));
+        let kid = kid.unwrap_or_default();
+        audit::record("import", &kid, &client, &res);
+
+        let res = res
+            .and_then(|_| {
+                let res = ImportKeyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    uuid: kid.into_bytes(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| ImportKeyResponse {
+                status: e.into_bytes(),
+                uuid: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn get_public_key(
+        &self,
+        request: Request<GetPublicKeyRequest>,
+    ) -> Result<Response<GetPublicKeyResponse>, Status> {
+        let scope = request_scope(&request);
+        let request: GetPublicKeyRequest = request.into_inner();
+
+        let res = resolve_kid(&scope, request.uuid)
+            .and_then(|kid| {
+                let key_type = key_store::get_key_type(&kid)?;
+                let key = key_store::get_key(&kid).map_err(|_| "key is not exist".to_string())?;
+                key_type.public_key(&key)
+            })
+            .and_then(|public_key| {
+                let res = GetPublicKeyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    publickey: public_key.into_bytes(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| GetPublicKeyResponse {
+                status: e.into_bytes(),
+                publickey: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn sign(&self, request: Request<SignRequest>) -> Result<Response<SignResponse>, Status> {
+        let client = client_address(&request);
+        let scope = request_scope(&request);
+        let request: SignRequest = request.into_inner();
+
+        let kid = resolve_kid(&scope, request.uuid);
+        let res = kid.clone().and_then(|kid| {
+            key_store::check_usage(&kid, Usage::Sign)?;
+            let key_type = key_store::get_key_type(&kid)?;
+            let _operation = key_store::begin_operation(&kid);
+            let key = key_store::get_key(&kid).map_err(|_| "key is not exist".to_string())?;
+            key_type.sign(&key, &request.data)
+        });
+        audit::record(
+            "sign",
+            &kid.unwrap_or_default(),
+            &client,
+            &res.clone().map(|_| ()),
+        );
+
+        let res = res
+            .and_then(|signature| {
+                let res = SignResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    signature,
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| SignResponse {
+                status: e.into_bytes(),
+                signature: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn rotate_key(
+        &self,
+        request: Request<RotateKeyRequest>,
+    ) -> Result<Response<RotateKeyResponse>, Status> {
+        let client = client_address(&request);
+        let scope = request_scope(&request);
+        let request: RotateKeyRequest = request.into_inner();
+
+        let kid = resolve_kid(&scope, request.uuid);
+        let res = kid.clone().and_then(|kid| key_store::rotate_key(&kid));
+        audit::record(
+            "rotate",
+            &kid.unwrap_or_default(),
+            &client,
+            &res.clone().map(|_| ()),
+        );
+
+        let res = res
+            .and_then(|version| {
+                let res = RotateKeyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    version,
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| RotateKeyResponse {
+                status: e.into_bytes(),
+                version: 0,
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn set_key_labels(
+        &self,
+        request: Request<SetKeyLabelsRequest>,
+    ) -> Result<Response<SetKeyLabelsResponse>, Status> {
+        let scope = request_scope(&request);
+        let request: SetKeyLabelsRequest = request.into_inner();
+
+        let res = resolve_kid(&scope, request.uuid)
+            .and_then(|kid| {
+                let labels = parse_labels(request.labels)?;
+                key_store::set_labels(&kid, labels)
+            })
+            .and_then(|_| {
+                let res = SetKeyLabelsResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| SetKeyLabelsResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn set_key_usages(
+        &self,
+        request: Request<SetKeyUsagesRequest>,
+    ) -> Result<Response<SetKeyUsagesResponse>, Status> {
+        let client = client_address(&request);
+        let scope = request_scope(&request);
+        let request: SetKeyUsagesRequest = request.into_inner();
+
+        let kid = resolve_kid(&scope, request.uuid);
+        let res = kid.clone().and_then(|kid| {
+            let usages = parse_usages(request.usages)?;
+            key_store::set_usages(&kid, usages)
+        });
+        audit::record("set-usages", &kid.unwrap_or_default(), &client, &res);
+
+        let res = res
+            .and_then(|_| {
+                let res = SetKeyUsagesResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| SetKeyUsagesResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn list_keys(
+        &self,
+        request: Request<ListKeysRequest>,
+    ) -> Result<Response<ListKeysResponse>, Status> {
+        let scope = request_scope(&request);
+        let request: ListKeysRequest = request.into_inner();
+
+        let res = parse_labels(request.labels)
+            .and_then(|labels| key_store::list_keys(&scope?, &labels))
+            .and_then(|keys| {
+                let res = ListKeysResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    keys: keys
+                        .into_iter()
+                        .map(|(kid, metadata)| key_info(kid, metadata))
+                        .collect(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| ListKeysResponse {
+                status: e.into_bytes(),
+                keys: vec![],
+            });
+
         Ok(Response::new(res))
     }
 }
@@ -87,4 +556,69 @@ mod tests {
         let response = service.get_key(Request::new(request)).await.unwrap();
         assert_eq!(response.get_ref().status, b"key is not exist");
     }
+
+    #[tokio::test]
+    async fn test_restore_key_not_found() {
+        let service = keyManagerService {};
+        let request = RestoreKeyRequest {
+            uuid: b"notexist".to_vec(),
+        };
+        let response = service.restore_key(Request::new(request)).await.unwrap();
+        assert_eq!(response.get_ref().status, b"kid notexist not found");
+    }
+
+    #[test]
+    fn test_request_scope() {
+        let path = "/tmp/verdictd-test-credentials.json";
+        fs::write(path, r#"{"token-a": "team-a", "token-admin": "*"}"#).unwrap();
+        assert_eq!(load_credentials(path), Ok(2));
+
+        let scoped = |authorization: Option<&str>| {
+            let mut request = Request::new(());
+            if let Some(authorization) = authorization {
+                request
+                    .metadata_mut()
+                    .insert(AUTHORIZATION_METADATA, authorization.parse().unwrap());
+            }
+            request_scope(&request)
+        };
+        assert_eq!(scoped(None), Ok(Scope::default()));
+        assert_eq!(scoped(Some("Bearer token-a")), Scope::tenant("team-a"));
+        assert_eq!(scoped(Some("Bearer token-admin")), Ok(Scope::global()));
+        assert!(scoped(Some("Bearer token-b")).is_err());
+        assert!(scoped(Some("token-a")).is_err());
+    }
+
+    #[test]
+    fn test_parse_key_type() {
+        assert_eq!(parse_key_type(vec![], 0), Ok(KeyType::Aes256));
+        assert_eq!(parse_key_type(b"AES".to_vec(), 128), Ok(KeyType::Aes128));
+        assert!(parse_key_type(b"DES".to_vec(), 64).is_err());
+    }
+
+    #[test]
+    fn test_parse_usages() {
+        let usages = parse_usages(vec![b"unwrap".to_vec(), b"wrap".to_vec()]).unwrap();
+        assert_eq!(
+            usages.into_iter().collect::<Vec<Usage>>(),
+            vec![Usage::Wrap, Usage::Unwrap]
+        );
+        assert!(parse_usages(vec![b"copy".to_vec()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = vec![KeyLabel {
+            name: b"team".to_vec(),
+            value: b"a".to_vec(),
+        }];
+        let labels = parse_labels(labels).unwrap();
+        assert_eq!(labels.get("team"), Some(&"a".to_string()));
+
+        let labels = vec![KeyLabel {
+            name: vec![],
+            value: b"a".to_vec(),
+        }];
+        assert!(parse_labels(labels).is_err());
+    }
 }
@@ -1,9 +1,13 @@
 use crate::client_api::annotation;
+use crate::client_api::key_manager::request_scope;
 use crate::client_api::messages::*;
-use crate::crypto::aes256_gcm;
-use crate::resources::directory_key_manager;
+use crate::crypto::cipher::Cipher;
+use crate::crypto::key_type::KeyType;
+use crate::resources::key_store;
+use crate::resources::key_store::{KeyMetadata, Usage};
 use base64;
-use rand::*;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
@@ -14,18 +18,35 @@ pub mod keyProvider {
     tonic::include_proto!("keyprovider");
 }
 
+lazy_static! {
+    // The cipher the data is wrapped with, the wrapping key type's one if None
+    static ref WRAP_CIPHER: RwLock<Option<Cipher>> = RwLock::new(None);
+}
+
+/// Wrap the data with the cipher, the keys of other types wrap with their key type's one
+pub fn set_wrap_cipher(cipher: Cipher) {
+    *WRAP_CIPHER.write() = Some(cipher);
+}
+
+// The cipher the key wraps with, None for the asymmetric keys
+fn wrap_cipher(key_type: KeyType) -> Option<Cipher> {
+    WRAP_CIPHER
+        .read()
+        .filter(|cipher| cipher.key_type() == key_type)
+        .or(key_type.cipher())
+}
+
 #[derive(Debug, Default)]
 pub struct keyProviderService {}
 
-const IV_LEN: usize = 12;
-const KEY_LEN: usize = 32;
-
 #[tonic::async_trait]
 impl KeyProviderService for keyProviderService {
     async fn wrap_key(
         &self,
         request: Request<KeyProviderKeyWrapProtocolInput>,
     ) -> Result<Response<KeyProviderKeyWrapProtocolOutput>, Status> {
+        // The kids are resolved in the namespace the request's credential is bound to
+        let scope = request_scope(&request).map_err(Status::unauthenticated)?;
         let wrap_command =
             String::from_utf8(request.into_inner().key_provider_key_wrap_protocol_input)
                 .and_then(|request| Ok(serde_json::from_str::<KeyProviderInput>(&request[..])))
@@ -44,6 +65,7 @@ impl KeyProviderService for keyProviderService {
         info!("wrap_command: {:?}", wrap_command);
 
         let mut kid = Uuid::new_v4().to_string();
+        let mut layer = None;
         let ec = wrap_command.keywrapparams.ec.unwrap();
         let optsdata = wrap_command.keywrapparams.optsdata.unwrap();
         if !ec.Parameters.is_empty() {
@@ -52,38 +74,51 @@ impl KeyProviderService for keyProviderService {
                     .unwrap();
                 break;
             }
+            // <KID>:<LAYER> wraps the data for the image or layer named LAYER
+            if let Some((requested_kid, requested_layer)) = kid.split_once(':') {
+                layer = Some(requested_layer.to_string());
+                kid = requested_kid.to_string();
+            }
+            kid = scope.resolve(&kid).map_err(Status::permission_denied)?;
         } else {
-            // generate a new key file with a new random key
-            let mut key = [0; KEY_LEN];
-            rand::rngs::OsRng.fill_bytes(&mut key);
-            directory_key_manager::set_key(&kid, &key)?;
+            // generate a new key file with a new random AES-256 key
+            kid = scope.resolve(&kid).map_err(Status::permission_denied)?;
+            key_store::generate_key(&kid, &KeyMetadata::new(KeyType::Aes256))?;
         }
-        let mut iv = [0; IV_LEN];
-        rand::rngs::OsRng.fill_bytes(&mut iv);
-
-        let encrypted_data = directory_key_manager::get_key(&kid)
-            .and_then(|key| {
-                info!("key: {:?}", key);
-                let encrypted_data =
-                    aes256_gcm::encrypt(&base64::decode(optsdata).unwrap(), key.as_slice(), &iv)
-                        .unwrap_or_else(|e| {
-                            error!("encrypt data failed with error:{:?}", e);
-                            vec![0]
-                        });
-                Ok(encrypted_data)
-            })
-            .unwrap_or_else(|_| {
-                error!("get encryption key faied");
-                vec![0]
-            });
+        // The key can't be deleted while it's wrapping the data
+        let _operation = key_store::begin_operation(&kid);
 
-        let annotation = annotation::AnnotationPacket {
+        // A key which can't wrap the data fails the request, the image would never be decrypted
+        key_store::check_usage(&kid, Usage::Wrap).map_err(Status::permission_denied)?;
+        let key_type = key_store::get_key_type(&kid).map_err(Status::internal)?;
+        let optsdata = base64::decode(optsdata)
+            .map_err(|_| Status::invalid_argument("base64 decode optsdata failed"))?;
+
+        let cipher = wrap_cipher(key_type);
+        let mut annotation = annotation::AnnotationPacket {
             kid: kid.to_string(),
-            wrapped_data: encrypted_data,
-            iv: iv.to_vec(),
-            algorithm: String::from("AES"),
-            key_length: 256,
+            key_version: 0,
+            wrapped_data: vec![],
+            iv: vec![],
+            algorithm: key_type.algorithm().to_string(),
+            key_length: key_type.length() as u16,
+            cipher: cipher.map(|cipher| cipher.as_str().to_string()),
+            layer,
+            // The wrapped data is bound to the annotation unless the cipher can't authenticate it
+            aad: cipher.map_or(key_type.binds_aad(), |cipher| cipher.is_authenticated()),
+        };
+        let aad = match annotation.aad {
+            true => annotation.aad(),
+            false => vec![],
         };
+        let (version, encrypted_data, iv) = key_store::encrypt(&kid, cipher, &optsdata, &aad)
+            .map_err(|e| {
+                error!("encrypt data failed with error:{:?}", e);
+                Status::internal(format!("encrypt data failed: {}", e))
+            })?;
+        annotation.key_version = version;
+        annotation.wrapped_data = encrypted_data;
+        annotation.iv = iv;
 
         let key_wrap_output = KeyWrapOutput {
             keywrapresults: KeyWrapResults {
@@ -107,6 +142,7 @@ impl KeyProviderService for keyProviderService {
         &self,
         request: Request<KeyProviderKeyWrapProtocolInput>,
     ) -> Result<Response<KeyProviderKeyWrapProtocolOutput>, Status> {
+        let scope = request_scope(&request).map_err(Status::unauthenticated)?;
         let annotation =
             String::from_utf8(request.into_inner().key_provider_key_wrap_protocol_input)
                 .and_then(|request| Ok(serde_json::from_str::<KeyProviderInput>(&request[..])))
@@ -134,25 +170,51 @@ impl KeyProviderService for keyProviderService {
 
         info!("unwrap's annotation: {:?}", &annotation);
 
-        let decrypted_data = serde_json::from_str::<annotation::AnnotationPacket>(&annotation[..])
-            .and_then(|annotation| {
-                let decrypted_data = directory_key_manager::get_key(&annotation.kid)
-                    .and_then(|key| {
-                        let a = aes256_gcm::decrypt(
-                            &annotation.wrapped_data[..],
-                            key.as_slice(),
-                            &annotation.iv[..],
-                        )
-                        .unwrap_or_else(|e| {
-                            error!("decrypt data failed with error:{:?}", e);
-                            vec![0]
-                        });
-                        Ok(a)
-                    })
-                    .unwrap();
-                Ok(decrypted_data)
-            })
-            .unwrap();
+        let annotation = serde_json::from_str::<annotation::AnnotationPacket>(&annotation[..])
+            .map_err(|e| Status::invalid_argument(format!("parse annotation failed: {}", e)))?;
+        // The annotation names the full kid, it must be in the caller's namespace
+        if !scope.contains(&annotation.kid) {
+            return Err(Status::permission_denied(format!(
+                "kid {} is out of the caller's namespace",
+                annotation.kid
+            )));
+        }
+        let _operation = key_store::begin_operation(&annotation.kid);
+
+        // A key which can't unwrap the data fails the request, like it does wrapping
+        key_store::check_usage(&annotation.kid, Usage::Unwrap)
+            .map_err(Status::permission_denied)?;
+        let key_type = key_store::get_key_type(&annotation.kid).map_err(Status::internal)?;
+        // The data is unwrapped the way it was wrapped
+        if key_type.algorithm() != annotation.algorithm
+            || key_type.length() != annotation.key_length as u32
+        {
+            return Err(Status::invalid_argument(format!(
+                "kid {}'s key isn't {}-{}",
+                annotation.kid, annotation.algorithm, annotation.key_length
+            )));
+        }
+        let cipher = match &annotation.cipher {
+            Some(cipher) => Some(Cipher::parse(cipher).map_err(Status::invalid_argument)?),
+            None => None,
+        };
+        // The legacy annotations were wrapped without aad
+        let aad = match annotation.aad {
+            true => annotation.aad(),
+            false => vec![],
+        };
+        let decrypted_data = key_store::decrypt(
+            &annotation.kid,
+            annotation.key_version,
+            cipher,
+            &annotation.wrapped_data,
+            &annotation.iv,
+            &aad,
+        )
+        .map_err(|e| {
+            error!("decrypt data failed with error:{:?}", e);
+            Status::internal(format!("decrypt data failed: {}", e))
+        })?;
 
         let key_unwrap_output = KeyUnwrapOutput {
             keyunwrapresults: KeyUnwrapResults {
@@ -170,3 +232,59 @@ impl KeyProviderService for keyProviderService {
         Ok(Response::new(reply))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_api::key_manager::load_credentials;
+    use std::fs;
+
+    fn unwrap_request(kid: &str, authorization: &str) -> Request<KeyProviderKeyWrapProtocolInput> {
+        let annotation = annotation::AnnotationPacket {
+            kid: kid.to_string(),
+            key_version: 0,
+            wrapped_data: vec![],
+            iv: vec![],
+            algorithm: "AES".to_string(),
+            key_length: 256,
+            cipher: None,
+            layer: None,
+            aad: true,
+        };
+        let input = serde_json::json!({
+            "op": "keyunwrap",
+            "keywrapparams": { "ec": null, "optsdata": null },
+            "keyunwrapparams": {
+                "dc": null,
+                "annotation": base64::encode(serde_json::to_string(&annotation).unwrap())
+            }
+        });
+        let mut request = Request::new(KeyProviderKeyWrapProtocolInput {
+            key_provider_key_wrap_protocol_input: input.to_string().into_bytes(),
+        });
+        request
+            .metadata_mut()
+            .insert("authorization", authorization.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_unwrap_other_tenant() {
+        let path = "/tmp/verdictd-test-provider-credentials.json";
+        fs::write(path, r#"{"token-a": "team-a", "token-admin": "*"}"#).unwrap();
+        assert_eq!(load_credentials(path), Ok(2));
+
+        let service = keyProviderService {};
+        let status = service
+            .un_wrap_key(unwrap_request("team-b/key", "Bearer token-a"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let status = service
+            .un_wrap_key(unwrap_request("team-a/key", "Bearer token-unknown"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+}
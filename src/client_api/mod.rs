@@ -1,8 +1,10 @@
 pub mod annotation;
 pub mod api;
+pub mod backup;
 pub mod gpg;
 pub mod image;
 pub mod key_manager;
 pub mod key_provider;
 pub mod messages;
 pub mod opa;
+pub mod unseal;
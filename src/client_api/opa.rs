@@ -1,18 +1,265 @@
 use crate::client_api::api;
 use crate::policy_engine;
 use crate::resources;
+use crate::resources::opa_schema;
 use tonic::{Request, Response, Status};
 
 use api::clientApi::opa_service_server::OpaService;
+use api::clientApi::{AddCsvReferenceRequest, AddCsvReferenceResponse};
+use api::clientApi::{AddSgxReferenceRequest, AddSgxReferenceResponse};
+use api::clientApi::{AddSgxSigstructRequest, AddSgxSigstructResponse};
+use api::clientApi::{ClearOpaShadowPolicyRequest, ClearOpaShadowPolicyResponse};
+use api::clientApi::{DeleteOpaPolicyRequest, DeleteOpaPolicyResponse};
+use api::clientApi::{DeleteOpaReferenceRequest, DeleteOpaReferenceResponse};
 use api::clientApi::{ExportOpaPolicyRequest, ExportOpaPolicyResponse};
 use api::clientApi::{ExportOpaReferenceRequest, ExportOpaReferenceResponse};
+use api::clientApi::{GetOpaPolicySetRequest, GetOpaPolicySetResponse, OpaPolicyRef};
+use api::clientApi::{ListOpaPoliciesRequest, ListOpaPoliciesResponse};
+use api::clientApi::{ListOpaReferencesRequest, ListOpaReferencesResponse};
+use api::clientApi::{
+    ListOpaShadowPoliciesRequest, ListOpaShadowPoliciesResponse, OpaShadowPolicy,
+};
+use api::clientApi::{OpaTestResult, RunOpaTestsRequest, RunOpaTestsResponse};
+use api::clientApi::{PromoteOpaShadowPolicyRequest, PromoteOpaShadowPolicyResponse};
+use api::clientApi::{RemoveCsvReferenceRequest, RemoveCsvReferenceResponse};
+use api::clientApi::{RemoveSgxReferenceRequest, RemoveSgxReferenceResponse};
+use api::clientApi::{RevokeReferenceRequest, RevokeReferenceResponse};
 use api::clientApi::{SetOpaPolicyRequest, SetOpaPolicyResponse};
+use api::clientApi::{SetOpaPolicySetRequest, SetOpaPolicySetResponse};
 use api::clientApi::{SetOpaReferenceRequest, SetOpaReferenceResponse};
+use api::clientApi::{SetOpaShadowPolicyRequest, SetOpaShadowPolicyResponse};
+use api::clientApi::{SetSgxMinimumSvnRequest, SetSgxMinimumSvnResponse};
 use api::clientApi::{TestOpaRequest, TestOpaResponse};
+use api::clientApi::{UnrevokeReferenceRequest, UnrevokeReferenceResponse};
 
 #[derive(Debug, Default)]
 pub struct opaService {}
 
+// Fetch a policy/reference/tests content, either carried by the request or stored in verdictd
+fn load_content(
+    local: bool,
+    name: Vec<u8>,
+    content: Vec<u8>,
+    what: &str,
+) -> Result<String, String> {
+    if local == true {
+        String::from_utf8(content)
+            .map_err(|_| format!("parse {}content failed", what))
+            .and_then(|content| match content.is_empty() {
+                true => Err(format!("parse {}content failed", what)),
+                false => Ok(content),
+            })
+    } else {
+        String::from_utf8(name)
+            .map_err(|_| format!("parse {}name failed", what))
+            .and_then(|name| match name.is_empty() {
+                true => Err(format!("parse {}name failed", what)),
+                false => resources::opa::export(&name)
+                    .map_err(|e| format!("export {} {} failed: {}", what, name, e)),
+            })
+    }
+}
+
+// Name of the reference to patch, the TEE type's default reference if it's not given
+fn reference_name(name: Vec<u8>, default: &str) -> Result<String, String> {
+    String::from_utf8(name)
+        .map_err(|_| "parse referencename failed".to_string())
+        .and_then(|name| match name.is_empty() {
+            true => Ok(default.to_string()),
+            false => Ok(name),
+        })
+}
+
+// Apply the typed SGX reference change to mrEnclave and/or mrSigner
+fn patch_sgx_reference(
+    name: Vec<u8>,
+    mrenclave: Vec<u8>,
+    mrsigner: Vec<u8>,
+    patch: impl Fn(&mut serde_json::Value, &str, &str) -> Result<(), String>,
+) -> Result<(), String> {
+    let name = reference_name(name, resources::opa::OPA_DATA_SGX)?;
+    let mrenclave = String::from_utf8(mrenclave).map_err(|_| "parse mrenclave failed")?;
+    let mrsigner = String::from_utf8(mrsigner).map_err(|_| "parse mrsigner failed")?;
+    if mrenclave.is_empty() && mrsigner.is_empty() {
+        return Err("mrenclave or mrsigner is required".to_string());
+    }
+
+    resources::opa::update_reference(&name, Some(opa_schema::TEE_SGX), |reference| {
+        if !mrenclave.is_empty() {
+            patch(reference, "mrEnclave", &mrenclave)?;
+        }
+        if !mrsigner.is_empty() {
+            patch(reference, "mrSigner", &mrsigner)?;
+        }
+        Ok(())
+    })
+}
+
+fn patch_csv_reference(
+    name: Vec<u8>,
+    measure: Vec<u8>,
+    patch: impl Fn(&mut serde_json::Value, &str, &str) -> Result<(), String>,
+) -> Result<(), String> {
+    let name = reference_name(name, resources::opa::OPA_DATA_CSV)?;
+    let measure = String::from_utf8(measure).map_err(|_| "parse measure failed")?;
+    if measure.is_empty() {
+        return Err("measure is required".to_string());
+    }
+
+    resources::opa::update_reference(&name, Some(opa_schema::TEE_CSV), |reference| {
+        patch(reference, "measure", &measure)
+    })
+}
+
+// Validity window of an added reference value, 0 means unbounded
+fn validity(not_before: u64, not_after: u64) -> Result<resources::opa::Validity, String> {
+    if not_before != 0 && not_after != 0 && not_before >= not_after {
+        return Err("notbefore must be earlier than notafter".to_string());
+    }
+
+    Ok(resources::opa::Validity {
+        not_before: Some(not_before).filter(|t| *t != 0),
+        not_after: Some(not_after).filter(|t| *t != 0),
+    })
+}
+
+// Add (revoke) or remove a value from the revocation list's kind
+fn patch_revocations(
+    kind: Vec<u8>,
+    value: Vec<u8>,
+    revoke: bool,
+    patch: impl Fn(&mut serde_json::Value, &str, &str) -> Result<(), String>,
+) -> Result<(), String> {
+    let kind = String::from_utf8(kind).map_err(|_| "parse kind failed")?;
+    if !["mrEnclave", "mrSigner", "measure"].contains(&kind.as_str()) {
+        return Err(format!(
+            "Unknown kind: {:?}, expect mrEnclave, mrSigner or measure",
+            kind
+        ));
+    }
+    let value = String::from_utf8(value).map_err(|_| "parse value failed")?;
+    if value.is_empty() {
+        return Err("value is required".to_string());
+    }
+    // A revocation must not succeed while a policy in use lets the revoked value in,
+    // unrevoking can't let anything in through such a policy
+    if revoke {
+        let ignoring = resources::opa::ignoring_revocations(&kind)?;
+        if !ignoring.is_empty() {
+            return Err(format!(
+                "{} don't check the revocation list's {}, update them first",
+                ignoring.join(", "),
+                kind
+            ));
+        }
+    }
+
+    resources::opa::update_reference(
+        resources::opa::OPA_REVOCATIONS,
+        Some(opa_schema::SCHEMA_REVOCATIONS),
+        |revocations| patch(revocations, &kind, &value),
+    )
+}
+
+fn parse_tee(tee: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(tee)
+        .map_err(|_| "parse tee failed".to_string())
+        .and_then(|tee| match resources::opa::verifier_files(&tee) {
+            Some(_) => Ok(tee),
+            None => Err(format!("Unknown TEE type: {:?}", tee)),
+        })
+}
+
+fn parse_policy_set(
+    mode: Vec<u8>,
+    policies: Vec<OpaPolicyRef>,
+) -> Result<resources::opa_policy_set::PolicySet, String> {
+    let mode = String::from_utf8(mode).map_err(|_| "parse mode failed")?;
+    let policies = policies
+        .into_iter()
+        .map(|policy_ref| {
+            let policy = String::from_utf8(policy_ref.policyname)
+                .map_err(|_| "parse policyname failed".to_string())?;
+            let reference = String::from_utf8(policy_ref.referencename)
+                .map_err(|_| "parse referencename failed".to_string())?;
+            Ok(resources::opa_policy_set::PolicyRef { policy, reference })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(resources::opa_policy_set::PolicySet {
+        mode: resources::opa_policy_set::Mode::parse(&mode)?,
+        policies,
+    })
+}
+
+// Replace the TEE type's active policy, and reference if the shadow has its own, with the shadow's
+fn promote_shadow_policy(tee: &str) -> Result<(), String> {
+    let (active_policy, active_reference) = resources::opa::verifier_files(tee).unwrap();
+    let shadow = policy_engine::opa::shadow::get(tee)?
+        .ok_or(format!("No shadow policy is registered for {}", tee))?;
+
+    let policy = resources::opa::export(&shadow.policy)?;
+    let previous_reference = match &shadow.reference {
+        Some(reference) => {
+            let content = resources::opa::export(reference)?;
+            let previous = resources::opa::export(active_reference)?;
+            resources::opa::set_reference(active_reference, Some(tee), &content)?;
+            Some(previous)
+        }
+        None => None,
+    };
+    if let Err(e) = resources::opa::set_policy(active_policy, &policy) {
+        // The active policy keeps deciding with the reference it was written for
+        if let Some(previous) = previous_reference {
+            if let Err(restore) =
+                resources::opa::set_reference(active_reference, Some(tee), &previous)
+            {
+                error!("restore {} failed: {}", active_reference, restore);
+            }
+        }
+        return Err(e);
+    }
+
+    info!(
+        "promote shadow policy {} (disagreements: {}/{}) to {}",
+        shadow.policy, shadow.disagreements, shadow.evaluations, active_policy
+    );
+    policy_engine::opa::shadow::unregister(tee)?;
+    Ok(())
+}
+
+// Run the candidate policy's unit tests, succeed only if there are tests and all of them pass
+fn check_policy_tests(
+    name: &str,
+    policy: &str,
+    reference_name: &str,
+    tests: &str,
+) -> Result<(), String> {
+    let tests = match tests.is_empty() {
+        true => resources::opa::export(&resources::opa::test_name(name))
+            .map_err(|e| format!("Load policy tests failed: {}", e))?,
+        false => tests.to_string(),
+    };
+    let reference = resources::opa::export(reference_name)
+        .map_err(|e| format!("Load reference {} failed: {}", reference_name, e))?;
+
+    let results = policy_engine::opa::opa_engine::run_tests(policy, &reference, &tests)
+        .map_err(|e| format!("Run policy tests failed: {}", e))?;
+    if results.is_empty() {
+        return Err("No policy tests found".to_string());
+    }
+
+    let failed: Vec<String> = results
+        .iter()
+        .filter(|result| !result.pass)
+        .map(|result| format!("{}.{}", result.package, result.name))
+        .collect();
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Policy tests failed: {}", failed.join(", "))),
+    }
+}
+
 #[tonic::async_trait]
 impl OpaService for opaService {
     async fn set_opa_policy(
@@ -30,6 +277,25 @@ impl OpaService for opaService {
             &empty
         });
 
+        if request.runtests == true {
+            let reference_name = std::str::from_utf8(&request.referencename).unwrap_or_else(|_| {
+                error!("parse referencename failed");
+                &empty
+            });
+            let tests = std::str::from_utf8(&request.tests).unwrap_or_else(|_| {
+                error!("parse tests failed");
+                &empty
+            });
+
+            if let Err(e) = check_policy_tests(name, content, reference_name, tests) {
+                error!("refuse to set policy {}: {}", name, e);
+                let res = SetOpaPolicyResponse {
+                    status: e.into_bytes(),
+                };
+                return Ok(Response::new(res));
+            }
+        }
+
         let res = resources::opa::set_policy(name, content)
             .and_then(|_| {
                 let res = SetOpaPolicyResponse {
@@ -84,9 +350,18 @@ impl OpaService for opaService {
             &empty
         });
 
+        let tee = std::str::from_utf8(&request.tee).unwrap_or_else(|_| {
+            error!("parse tee failed");
+            &empty
+        });
+
         info!("content: {}", content);
 
-        let res = resources::opa::set_reference(name, content)
+        let tee = match tee.is_empty() {
+            true => None,
+            false => Some(tee),
+        };
+        let res = resources::opa::set_reference(name, tee, content)
             .and_then(|_| {
                 let res = SetOpaReferenceResponse {
                     status: "OK".as_bytes().to_vec(),
@@ -100,6 +375,234 @@ impl OpaService for opaService {
         Ok(Response::new(res))
     }
 
+    async fn add_sgx_reference(
+        &self,
+        request: Request<AddSgxReferenceRequest>,
+    ) -> Result<Response<AddSgxReferenceResponse>, Status> {
+        let request: AddSgxReferenceRequest = request.into_inner();
+
+        let res = validity(request.notbefore, request.notafter)
+            .and_then(|validity| {
+                patch_sgx_reference(
+                    request.name,
+                    request.mrenclave,
+                    request.mrsigner,
+                    |reference, key, value| {
+                        resources::opa::add_reference_value(reference, key, value, validity)
+                    },
+                )
+            })
+            .and_then(|_| {
+                let res = AddSgxReferenceResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| AddSgxReferenceResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn remove_sgx_reference(
+        &self,
+        request: Request<RemoveSgxReferenceRequest>,
+    ) -> Result<Response<RemoveSgxReferenceResponse>, Status> {
+        let request: RemoveSgxReferenceRequest = request.into_inner();
+
+        let res = patch_sgx_reference(
+            request.name,
+            request.mrenclave,
+            request.mrsigner,
+            resources::opa::remove_reference_value,
+        )
+        .and_then(|_| {
+            let res = RemoveSgxReferenceResponse {
+                status: "OK".as_bytes().to_vec(),
+            };
+            Ok(res)
+        })
+        .unwrap_or_else(|e| RemoveSgxReferenceResponse {
+            status: e.into_bytes(),
+        });
+
+        Ok(Response::new(res))
+    }
+
+    async fn add_sgx_sigstruct(
+        &self,
+        request: Request<AddSgxSigstructRequest>,
+    ) -> Result<Response<AddSgxSigstructResponse>, Status> {
+        let request: AddSgxSigstructRequest = request.into_inner();
+
+        let res = resources::sigstruct::parse(&request.sigstruct)
+            .and_then(|identity| {
+                let validity = validity(request.notbefore, request.notafter)?;
+                let name = reference_name(request.name, resources::opa::OPA_DATA_SGX)?;
+                resources::opa::update_reference(&name, Some(opa_schema::TEE_SGX), |reference| {
+                    resources::sigstruct::add_to_reference(
+                        reference,
+                        &identity,
+                        validity,
+                        request.setsvn,
+                    )
+                })?;
+                Ok(identity)
+            })
+            .and_then(|identity| {
+                let res = AddSgxSigstructResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    mrenclave: identity.mr_enclave.into_bytes(),
+                    mrsigner: identity.mr_signer.into_bytes(),
+                    productid: identity.product_id as u32,
+                    svn: identity.svn as u32,
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| AddSgxSigstructResponse {
+                status: e.into_bytes(),
+                mrenclave: vec![],
+                mrsigner: vec![],
+                productid: 0,
+                svn: 0,
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn set_sgx_minimum_svn(
+        &self,
+        request: Request<SetSgxMinimumSvnRequest>,
+    ) -> Result<Response<SetSgxMinimumSvnResponse>, Status> {
+        let request: SetSgxMinimumSvnRequest = request.into_inner();
+
+        let res = reference_name(request.name, resources::opa::OPA_DATA_SGX)
+            .and_then(|name| {
+                resources::opa::update_reference(&name, Some(opa_schema::TEE_SGX), |reference| {
+                    reference["svn"] = serde_json::json!(request.svn);
+                    Ok(())
+                })
+            })
+            .and_then(|_| {
+                let res = SetSgxMinimumSvnResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| SetSgxMinimumSvnResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn add_csv_reference(
+        &self,
+        request: Request<AddCsvReferenceRequest>,
+    ) -> Result<Response<AddCsvReferenceResponse>, Status> {
+        let request: AddCsvReferenceRequest = request.into_inner();
+
+        let res = validity(request.notbefore, request.notafter)
+            .and_then(|validity| {
+                patch_csv_reference(request.name, request.measure, |reference, key, value| {
+                    resources::opa::add_reference_value(reference, key, value, validity)
+                })
+            })
+            .and_then(|_| {
+                let res = AddCsvReferenceResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| AddCsvReferenceResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn remove_csv_reference(
+        &self,
+        request: Request<RemoveCsvReferenceRequest>,
+    ) -> Result<Response<RemoveCsvReferenceResponse>, Status> {
+        let request: RemoveCsvReferenceRequest = request.into_inner();
+
+        let res = patch_csv_reference(
+            request.name,
+            request.measure,
+            resources::opa::remove_reference_value,
+        )
+        .and_then(|_| {
+            let res = RemoveCsvReferenceResponse {
+                status: "OK".as_bytes().to_vec(),
+            };
+            Ok(res)
+        })
+        .unwrap_or_else(|e| RemoveCsvReferenceResponse {
+            status: e.into_bytes(),
+        });
+
+        Ok(Response::new(res))
+    }
+
+    async fn revoke_reference(
+        &self,
+        request: Request<RevokeReferenceRequest>,
+    ) -> Result<Response<RevokeReferenceResponse>, Status> {
+        let request: RevokeReferenceRequest = request.into_inner();
+
+        let res = patch_revocations(
+            request.kind,
+            request.value,
+            true,
+            |revocations, kind, value| {
+                resources::opa::add_reference_value(
+                    revocations,
+                    kind,
+                    value,
+                    resources::opa::Validity::default(),
+                )
+            },
+        )
+        .and_then(|_| {
+            let res = RevokeReferenceResponse {
+                status: "OK".as_bytes().to_vec(),
+            };
+            Ok(res)
+        })
+        .unwrap_or_else(|e| RevokeReferenceResponse {
+            status: e.into_bytes(),
+        });
+
+        Ok(Response::new(res))
+    }
+
+    async fn unrevoke_reference(
+        &self,
+        request: Request<UnrevokeReferenceRequest>,
+    ) -> Result<Response<UnrevokeReferenceResponse>, Status> {
+        let request: UnrevokeReferenceRequest = request.into_inner();
+
+        let res = patch_revocations(
+            request.kind,
+            request.value,
+            false,
+            resources::opa::remove_reference_value,
+        )
+        .and_then(|_| {
+            let res = UnrevokeReferenceResponse {
+                status: "OK".as_bytes().to_vec(),
+            };
+            Ok(res)
+        })
+        .unwrap_or_else(|e| UnrevokeReferenceResponse {
+            status: e.into_bytes(),
+        });
+
+        Ok(Response::new(res))
+    }
+
     async fn export_opa_reference(
         &self,
         request: Request<ExportOpaReferenceRequest>,
@@ -125,6 +628,262 @@ impl OpaService for opaService {
         Ok(Response::new(res))
     }
 
+    async fn list_opa_policies(
+        &self,
+        _request: Request<ListOpaPoliciesRequest>,
+    ) -> Result<Response<ListOpaPoliciesResponse>, Status> {
+        let res = resources::opa::list_policies()
+            .and_then(|names| {
+                let res = ListOpaPoliciesResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    names: names.into_iter().map(|name| name.into_bytes()).collect(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| ListOpaPoliciesResponse {
+                status: e.into_bytes(),
+                names: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn list_opa_references(
+        &self,
+        _request: Request<ListOpaReferencesRequest>,
+    ) -> Result<Response<ListOpaReferencesResponse>, Status> {
+        let res = resources::opa::list_references()
+            .and_then(|names| {
+                let res = ListOpaReferencesResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    names: names.into_iter().map(|name| name.into_bytes()).collect(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| ListOpaReferencesResponse {
+                status: e.into_bytes(),
+                names: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn delete_opa_policy(
+        &self,
+        request: Request<DeleteOpaPolicyRequest>,
+    ) -> Result<Response<DeleteOpaPolicyResponse>, Status> {
+        let name = String::from_utf8(request.into_inner().name).unwrap_or_else(|_| {
+            error!("parse policyname failed");
+            "".to_string()
+        });
+        info!("delete policy: {}", name);
+
+        let res = match policy_engine::opa::shadow::is_shadow_policy(&name) {
+            true => Err(format!("{} is in use as a shadow policy", name)),
+            false => resources::opa::delete_policy(&name),
+        }
+        .and_then(|_| {
+            let res = DeleteOpaPolicyResponse {
+                status: "OK".as_bytes().to_vec(),
+            };
+            Ok(res)
+        })
+        .unwrap_or_else(|e| DeleteOpaPolicyResponse {
+            status: e.into_bytes(),
+        });
+
+        Ok(Response::new(res))
+    }
+
+    async fn delete_opa_reference(
+        &self,
+        request: Request<DeleteOpaReferenceRequest>,
+    ) -> Result<Response<DeleteOpaReferenceResponse>, Status> {
+        let name = String::from_utf8(request.into_inner().name).unwrap_or_else(|_| {
+            error!("parse referencename failed");
+            "".to_string()
+        });
+        info!("delete reference: {}", name);
+
+        let res = resources::opa::delete_reference(&name)
+            .and_then(|_| {
+                let res = DeleteOpaReferenceResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| DeleteOpaReferenceResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn set_opa_shadow_policy(
+        &self,
+        request: Request<SetOpaShadowPolicyRequest>,
+    ) -> Result<Response<SetOpaShadowPolicyResponse>, Status> {
+        let request: SetOpaShadowPolicyRequest = request.into_inner();
+
+        let res = parse_tee(request.tee)
+            .and_then(|tee| {
+                let policyname = String::from_utf8(request.policyname)
+                    .map_err(|_| "parse policyname failed".to_string())?;
+                let referencename = String::from_utf8(request.referencename)
+                    .map_err(|_| "parse referencename failed".to_string())?;
+
+                // Both files must exist, evaluations would only count errors otherwise
+                resources::opa::export(&policyname)
+                    .map_err(|e| format!("export policy {} failed: {}", policyname, e))?;
+                let referencename = match referencename.is_empty() {
+                    true => None,
+                    false => {
+                        resources::opa::export(&referencename).map_err(|e| {
+                            format!("export reference {} failed: {}", referencename, e)
+                        })?;
+                        Some(referencename)
+                    }
+                };
+
+                policy_engine::opa::shadow::register(&tee, &policyname, referencename.as_deref())
+            })
+            .and_then(|_| {
+                let res = SetOpaShadowPolicyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| SetOpaShadowPolicyResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn clear_opa_shadow_policy(
+        &self,
+        request: Request<ClearOpaShadowPolicyRequest>,
+    ) -> Result<Response<ClearOpaShadowPolicyResponse>, Status> {
+        let res = parse_tee(request.into_inner().tee)
+            .and_then(|tee| {
+                policy_engine::opa::shadow::unregister(&tee)?
+                    .ok_or(format!("No shadow policy is registered for {}", tee))
+            })
+            .and_then(|_| {
+                let res = ClearOpaShadowPolicyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| ClearOpaShadowPolicyResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn promote_opa_shadow_policy(
+        &self,
+        request: Request<PromoteOpaShadowPolicyRequest>,
+    ) -> Result<Response<PromoteOpaShadowPolicyResponse>, Status> {
+        let res = parse_tee(request.into_inner().tee)
+            .and_then(|tee| promote_shadow_policy(&tee))
+            .and_then(|_| {
+                let res = PromoteOpaShadowPolicyResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| PromoteOpaShadowPolicyResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn list_opa_shadow_policies(
+        &self,
+        _request: Request<ListOpaShadowPoliciesRequest>,
+    ) -> Result<Response<ListOpaShadowPoliciesResponse>, Status> {
+        let res = policy_engine::opa::shadow::list()
+            .map(|shadows| ListOpaShadowPoliciesResponse {
+                status: "OK".as_bytes().to_vec(),
+                shadows: shadows
+                    .into_iter()
+                    .map(|(tee, shadow)| OpaShadowPolicy {
+                        tee: tee.into_bytes(),
+                        policyname: shadow.policy.into_bytes(),
+                        referencename: shadow.reference.unwrap_or_default().into_bytes(),
+                        evaluations: shadow.evaluations,
+                        disagreements: shadow.disagreements,
+                        errors: shadow.errors,
+                    })
+                    .collect(),
+            })
+            .unwrap_or_else(|e| ListOpaShadowPoliciesResponse {
+                status: e.into_bytes(),
+                shadows: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn set_opa_policy_set(
+        &self,
+        request: Request<SetOpaPolicySetRequest>,
+    ) -> Result<Response<SetOpaPolicySetResponse>, Status> {
+        let request: SetOpaPolicySetRequest = request.into_inner();
+
+        let res = parse_tee(request.tee)
+            .and_then(|tee| {
+                let set = parse_policy_set(request.mode, request.policies)?;
+                resources::opa_policy_set::set(&tee, set)
+            })
+            .and_then(|_| {
+                let res = SetOpaPolicySetResponse {
+                    status: "OK".as_bytes().to_vec(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| SetOpaPolicySetResponse {
+                status: e.into_bytes(),
+            });
+
+        Ok(Response::new(res))
+    }
+
+    async fn get_opa_policy_set(
+        &self,
+        request: Request<GetOpaPolicySetRequest>,
+    ) -> Result<Response<GetOpaPolicySetResponse>, Status> {
+        let request: GetOpaPolicySetRequest = request.into_inner();
+
+        let res = parse_tee(request.tee)
+            .and_then(|tee| resources::opa_policy_set::get(&tee))
+            .and_then(|set| {
+                let res = GetOpaPolicySetResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    mode: set.mode.as_str().as_bytes().to_vec(),
+                    policies: set
+                        .policies
+                        .into_iter()
+                        .map(|policy_ref| OpaPolicyRef {
+                            policyname: policy_ref.policy.into_bytes(),
+                            referencename: policy_ref.reference.into_bytes(),
+                        })
+                        .collect(),
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| GetOpaPolicySetResponse {
+                status: e.into_bytes(),
+                mode: vec![],
+                policies: vec![],
+            });
+
+        Ok(Response::new(res))
+    }
+
     async fn test_opa(
         &self,
         request: Request<TestOpaRequest>,
@@ -216,6 +975,62 @@ impl OpaService for opaService {
             status: msg.as_bytes().to_vec(),
         };
 
+        Ok(Response::new(res))
+    }
+    async fn run_opa_tests(
+        &self,
+        request: Request<RunOpaTestsRequest>,
+    ) -> Result<Response<RunOpaTestsResponse>, Status> {
+        let request: RunOpaTestsRequest = request.into_inner();
+
+        let res = load_content(
+            request.policylocal,
+            request.policyname,
+            request.policycontent,
+            "policy",
+        )
+        .and_then(|policy| {
+            load_content(
+                request.referencelocal,
+                request.referencename,
+                request.referencecontent,
+                "reference",
+            )
+            .and_then(|reference| Ok((policy, reference)))
+        })
+        .and_then(|(policy, reference)| {
+            load_content(
+                request.testslocal,
+                request.testsname,
+                request.testscontent,
+                "tests",
+            )
+            .and_then(|tests| Ok((policy, reference, tests)))
+        })
+        .and_then(|(policy, reference, tests)| {
+            policy_engine::opa::opa_engine::run_tests(&policy, &reference, &tests)
+                .map_err(|e| format!("run_tests error: {}", e))
+        })
+        .and_then(|results| {
+            let res = RunOpaTestsResponse {
+                status: "OK".as_bytes().to_vec(),
+                results: results
+                    .into_iter()
+                    .map(|result| OpaTestResult {
+                        package: result.package.into_bytes(),
+                        name: result.name.into_bytes(),
+                        pass: result.pass,
+                        error: result.error.into_bytes(),
+                    })
+                    .collect(),
+            };
+            Ok(res)
+        })
+        .unwrap_or_else(|e| RunOpaTestsResponse {
+            status: e.into_bytes(),
+            results: vec![],
+        });
+
         Ok(Response::new(res))
     }
 }
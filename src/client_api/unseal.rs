@@ -0,0 +1,46 @@
+use crate::client_api::api;
+use crate::client_api::key_manager::client_address;
+use crate::resources::{audit, unseal};
+use tonic::{Request, Response, Status};
+
+use api::clientApi::unseal_service_server::UnsealService;
+use api::clientApi::{UnsealRequest, UnsealResponse};
+
+#[derive(Debug, Default)]
+pub struct unsealService {}
+
+#[tonic::async_trait]
+impl UnsealService for unsealService {
+    async fn unseal(
+        &self,
+        request: Request<UnsealRequest>,
+    ) -> Result<Response<UnsealResponse>, Status> {
+        let client = client_address(&request);
+        let request: UnsealRequest = request.into_inner();
+
+        let res = String::from_utf8(request.share)
+            .map_err(|_| "parse share failed".to_string())
+            .and_then(|share| unseal::unseal(&share));
+        let result = res.as_ref().map(|_| ()).map_err(|e| e.clone());
+        audit::record("unseal", audit::ALL_KIDS, &client, &result);
+
+        let res = res
+            .and_then(|progress| {
+                let res = UnsealResponse {
+                    status: "OK".as_bytes().to_vec(),
+                    received: progress.received,
+                    threshold: progress.threshold,
+                    sealed: progress.sealed,
+                };
+                Ok(res)
+            })
+            .unwrap_or_else(|e| UnsealResponse {
+                status: format!("Unseal failed: {}", e).into_bytes(),
+                received: 0,
+                threshold: 0,
+                sealed: unseal::is_sealed(),
+            });
+
+        Ok(Response::new(res))
+    }
+}
@@ -1,23 +1,44 @@
-use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::aead::{Aead, NewAead, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 
 pub fn encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    encrypt_with_aad(data, key, iv, &[])
+}
+
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    decrypt_with_aad(encrypted_data, key, iv, &[])
+}
+
+// The additional data isn't encrypted but authenticated: the data only decrypts with the same aad.
+// An empty aad is the same as none.
+pub fn encrypt_with_aad(data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
     let encrypting_key = Key::from_slice(key);
     let cipher = Aes256Gcm::new(encrypting_key);
     let nonce = Nonce::from_slice(iv);
     let encrypted_data = cipher
-        .encrypt(nonce, data.as_ref())
+        .encrypt(nonce, Payload { msg: data, aad })
         .map_err(|e| format!("Encrypt data failed: {:?}", e).to_string());
 
     encrypted_data
 }
 
-pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+pub fn decrypt_with_aad(
+    encrypted_data: &[u8],
+    key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, String> {
     let decrypting_key = Key::from_slice(key);
     let cipher = Aes256Gcm::new(decrypting_key);
     let nonce = Nonce::from_slice(iv);
     let plain_text = cipher
-        .decrypt(nonce, encrypted_data.as_ref())
+        .decrypt(
+            nonce,
+            Payload {
+                msg: encrypted_data,
+                aad,
+            },
+        )
         .map_err(|e| format!("Decrypt data failed: {:?}", e).to_string());
 
     plain_text
@@ -39,4 +60,22 @@ mod tests {
         let decrypted_data = decrypt(&encrypted_data.unwrap(), key, iv);
         assert!(decrypted_data.is_ok(), "decrypt() failed");
     }
+
+    #[test]
+    fn test_aad() {
+        let key = b"01234567890123456789012345678901";
+        let iv = b"012345678901";
+        let data = b"test_data";
+
+        let encrypted_data = encrypt_with_aad(data, key, iv, b"kid").unwrap();
+        assert_eq!(
+            decrypt_with_aad(&encrypted_data, key, iv, b"kid").unwrap(),
+            data.to_vec()
+        );
+        assert!(decrypt_with_aad(&encrypted_data, key, iv, b"other").is_err());
+        assert!(decrypt(&encrypted_data, key, iv).is_err());
+
+        let encrypted_data = encrypt(data, key, iv).unwrap();
+        assert!(decrypt_with_aad(&encrypted_data, key, iv, &[]).is_ok());
+    }
 }
@@ -0,0 +1,268 @@
+use crate::crypto::aes256_cbc;
+use crate::crypto::aes256_gcm;
+use crate::crypto::key_type::KeyType;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes128Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use crypto::aes;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+const GCM_IV_LEN: usize = 12;
+const AES_BLOCK_LEN: usize = 16;
+const HMAC_SHA256_LEN: usize = 32;
+// HKDF info of the encryption and the MAC keys of AES-256-CBC-HMAC-SHA256, both derived from the key
+const CBC_ENCRYPTION_KEY_INFO: &[u8] = b"verdictd AES-256-CBC-HMAC-SHA256 encryption";
+const CBC_MAC_KEY_INFO: &[u8] = b"verdictd AES-256-CBC-HMAC-SHA256 authentication";
+
+/// The ciphers the symmetric keys encrypt data with, named by their algorithm identifier
+/// in the Decrypt requests and the image annotations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    // AES-256-CBC with PKCS#7 padding, then HMAC-SHA256 over the aad, the iv, the encrypted data
+    // and the aad's length in bits
+    Aes256CbcHmacSha256,
+    // Not authenticated, only for the consumers which can't do otherwise
+    Aes256Ctr,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub const ALL: [Cipher; 5] = [
+        Cipher::Aes128Gcm,
+        Cipher::Aes256Gcm,
+        Cipher::Aes256CbcHmacSha256,
+        Cipher::Aes256Ctr,
+        Cipher::ChaCha20Poly1305,
+    ];
+
+    pub fn parse(name: &str) -> Result<Cipher, String> {
+        Cipher::ALL
+            .iter()
+            .find(|known| known.as_str() == name)
+            .copied()
+            .ok_or(format!(
+                "Unknown cipher: {:?}, expect AES-128-GCM, AES-256-GCM, \
+                 AES-256-CBC-HMAC-SHA256, AES-256-CTR or ChaCha20-Poly1305",
+                name
+            ))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cipher::Aes128Gcm => "AES-128-GCM",
+            Cipher::Aes256Gcm => "AES-256-GCM",
+            Cipher::Aes256CbcHmacSha256 => "AES-256-CBC-HMAC-SHA256",
+            Cipher::Aes256Ctr => "AES-256-CTR",
+            Cipher::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    }
+
+    /// The type of the keys the cipher encrypts with
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            Cipher::Aes128Gcm => KeyType::Aes128,
+            Cipher::Aes256Gcm | Cipher::Aes256CbcHmacSha256 | Cipher::Aes256Ctr => KeyType::Aes256,
+            Cipher::ChaCha20Poly1305 => KeyType::ChaCha20Poly1305,
+        }
+    }
+
+    pub fn iv_len(&self) -> usize {
+        match self {
+            Cipher::Aes256CbcHmacSha256 | Cipher::Aes256Ctr => AES_BLOCK_LEN,
+            _ => GCM_IV_LEN,
+        }
+    }
+
+    /// Whether the cipher authenticates the data it encrypts, and the additional data
+    pub fn is_authenticated(&self) -> bool {
+        !matches!(self, Cipher::Aes256Ctr)
+    }
+
+    /// Encrypt the data with a random iv, return the encrypted data and the iv.
+    /// The aad isn't encrypted but authenticated, the data only decrypts with the same aad.
+    pub fn encrypt(
+        &self,
+        key: &[u8],
+        data: &[u8],
+        aad: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>), String> {
+        self.key_type().check(key)?;
+        self.check_aad(aad)?;
+        let mut iv = vec![0; self.iv_len()];
+        rand::rngs::OsRng.fill_bytes(&mut iv);
+        let payload = Payload { msg: data, aad };
+
+        let encrypted_data = match self {
+            Cipher::Aes128Gcm => Aes128Gcm::new_from_slice(key)
+                .map_err(|e| e.to_string())?
+                .encrypt(iv.as_slice().into(), payload),
+            Cipher::Aes256Gcm => {
+                let encrypted_data = aes256_gcm::encrypt_with_aad(data, key, &iv, aad)?;
+                return Ok((encrypted_data, iv));
+            }
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key)
+                .map_err(|e| e.to_string())?
+                .encrypt(iv.as_slice().into(), payload),
+            Cipher::Aes256CbcHmacSha256 => {
+                let (encryption_key, mac_key) = cbc_keys(key)?;
+                let mut encrypted_data = aes256_cbc::encrypt(data, &encryption_key, &iv)
+                    .map_err(|e| format!("Encrypt data failed: {:?}", e))?;
+                let tag = cbc_mac(&mac_key, aad, &iv, &encrypted_data)?.finalize();
+                encrypted_data.extend_from_slice(&tag.into_bytes());
+                return Ok((encrypted_data, iv));
+            }
+            Cipher::Aes256Ctr => return Ok((ctr(key, &iv, data), iv)),
+        }
+        .map_err(|e| format!("Encrypt data failed: {:?}", e))?;
+
+        Ok((encrypted_data, iv))
+    }
+
+    pub fn decrypt(
+        &self,
+        key: &[u8],
+        encrypted_data: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        self.key_type().check(key)?;
+        self.check_aad(aad)?;
+        if iv.len() != self.iv_len() {
+            return Err(format!(
+                "The iv of {} must be {} bytes",
+                self.as_str(),
+                self.iv_len()
+            ));
+        }
+        let payload = Payload {
+            msg: encrypted_data,
+            aad,
+        };
+
+        match self {
+            Cipher::Aes128Gcm => Aes128Gcm::new_from_slice(key)
+                .map_err(|e| e.to_string())?
+                .decrypt(iv.into(), payload),
+            Cipher::Aes256Gcm => return aes256_gcm::decrypt_with_aad(encrypted_data, key, iv, aad),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key)
+                .map_err(|e| e.to_string())?
+                .decrypt(iv.into(), payload),
+            Cipher::Aes256CbcHmacSha256 => {
+                if encrypted_data.len() < HMAC_SHA256_LEN {
+                    return Err("Decrypt data failed: no tag".to_string());
+                }
+                let (encrypted_data, tag) =
+                    encrypted_data.split_at(encrypted_data.len() - HMAC_SHA256_LEN);
+                let (encryption_key, mac_key) = cbc_keys(key)?;
+                cbc_mac(&mac_key, aad, iv, encrypted_data)?
+                    .verify_slice(tag)
+                    .map_err(|_| "Decrypt data failed: the tag doesn't match".to_string())?;
+                return aes256_cbc::decrypt(encrypted_data, &encryption_key, iv)
+                    .map_err(|e| format!("Decrypt data failed: {:?}", e));
+            }
+            Cipher::Aes256Ctr => return Ok(ctr(key, iv, encrypted_data)),
+        }
+        .map_err(|e| format!("Decrypt data failed: {:?}", e))
+    }
+
+    fn check_aad(&self, aad: &[u8]) -> Result<(), String> {
+        match self.is_authenticated() || aad.is_empty() {
+            true => Ok(()),
+            false => Err(format!(
+                "{} can't authenticate additional data",
+                self.as_str()
+            )),
+        }
+    }
+}
+
+fn cbc_keys(key: &[u8]) -> Result<([u8; 32], [u8; 32]), String> {
+    let hkdf = Hkdf::<Sha256>::new(None, key);
+    let mut encryption_key = [0u8; 32];
+    let mut mac_key = [0u8; 32];
+    hkdf.expand(CBC_ENCRYPTION_KEY_INFO, &mut encryption_key)
+        .and_then(|_| hkdf.expand(CBC_MAC_KEY_INFO, &mut mac_key))
+        .map_err(|e| format!("Derive the AES-256-CBC-HMAC-SHA256 keys failed: {}", e))?;
+    Ok((encryption_key, mac_key))
+}
+
+// The aad's length at the end tells where the aad stops and the iv starts
+fn cbc_mac(
+    mac_key: &[u8],
+    aad: &[u8],
+    iv: &[u8],
+    encrypted_data: &[u8],
+) -> Result<Hmac<Sha256>, String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).map_err(|e| e.to_string())?;
+    mac.update(aad);
+    mac.update(iv);
+    mac.update(encrypted_data);
+    mac.update(&(aad.len() as u64 * 8).to_be_bytes());
+    Ok(mac)
+}
+
+// CTR mode is its own inverse
+fn ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0; data.len()];
+    aes::ctr(aes::KeySize::KeySize256, key, iv).process(data, &mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for cipher in Cipher::ALL {
+            assert_eq!(Cipher::parse(cipher.as_str()), Ok(cipher));
+        }
+        assert!(Cipher::parse("AES").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        for cipher in Cipher::ALL {
+            let key = cipher.key_type().generate().unwrap();
+            let (encrypted_data, iv) = cipher.encrypt(&key, b"test_data", &[]).unwrap();
+            assert_eq!(iv.len(), cipher.iv_len());
+            let decrypted_data = cipher.decrypt(&key, &encrypted_data, &iv, &[]).unwrap();
+            assert_eq!(decrypted_data, b"test_data");
+
+            let mut tampered = encrypted_data.clone();
+            tampered[0] ^= 1;
+            assert_eq!(
+                cipher.decrypt(&key, &tampered, &iv, &[]).is_err(),
+                cipher.is_authenticated()
+            );
+            assert!(cipher
+                .decrypt(&key, &encrypted_data, &iv[1..], &[])
+                .is_err());
+            assert!(cipher.encrypt(&key[1..], b"test_data", &[]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_aad() {
+        for cipher in Cipher::ALL {
+            let key = cipher.key_type().generate().unwrap();
+            if !cipher.is_authenticated() {
+                assert!(cipher.encrypt(&key, b"test_data", b"kid").is_err());
+                continue;
+            }
+
+            let (encrypted_data, iv) = cipher.encrypt(&key, b"test_data", b"kid").unwrap();
+            let decrypted_data = cipher.decrypt(&key, &encrypted_data, &iv, b"kid").unwrap();
+            assert_eq!(decrypted_data, b"test_data");
+            assert!(cipher
+                .decrypt(&key, &encrypted_data, &iv, b"other")
+                .is_err());
+            assert!(cipher.decrypt(&key, &encrypted_data, &iv, &[]).is_err());
+        }
+    }
+}
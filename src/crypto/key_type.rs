@@ -0,0 +1,319 @@
+use crate::crypto::aes256_gcm;
+use crate::crypto::cipher::Cipher;
+use hkdf::Hkdf;
+use p256::ecdsa::signature::Signer;
+use rand::RngCore;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey};
+use sha2::{Digest, Sha256};
+
+pub const ALGORITHM_AES: &str = "AES";
+pub const ALGORITHM_CHACHA20_POLY1305: &str = "ChaCha20-Poly1305";
+pub const ALGORITHM_RSA: &str = "RSA";
+pub const ALGORITHM_EC: &str = "EC";
+
+const IV_LEN: usize = 12;
+// SEC1 uncompressed P-256 point prefixing the data wrapped with an EC key
+const EC_POINT_LEN: usize = 65;
+// HKDF info of the key derived from the ECDH shared secret of the data wrapped with an EC key
+const ECDH_KDF_INFO: &[u8] = b"verdictd ECDH-ES";
+const NO_RSA_AAD: &str = "RSA keys can't authenticate additional data";
+
+/// Type of a stored key, named by the algorithm and length recorded in the key metadata.
+/// Symmetric keys are stored raw, the asymmetric key pairs as PKCS#8 DER private keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+    Aes128,
+    Aes256,
+    ChaCha20Poly1305,
+    // Modulus length in bits
+    Rsa(u32),
+    // NIST P-256
+    EcP256,
+}
+
+impl KeyType {
+    pub fn parse(algorithm: &str, length: u32) -> Result<KeyType, String> {
+        match (algorithm, length) {
+            (ALGORITHM_AES, 128) => Ok(KeyType::Aes128),
+            (ALGORITHM_AES, 256) => Ok(KeyType::Aes256),
+            (ALGORITHM_CHACHA20_POLY1305, 256) => Ok(KeyType::ChaCha20Poly1305),
+            (ALGORITHM_RSA, 2048) | (ALGORITHM_RSA, 3072) | (ALGORITHM_RSA, 4096) => {
+                Ok(KeyType::Rsa(length))
+            }
+            (ALGORITHM_EC, 256) => Ok(KeyType::EcP256),
+            _ => Err(format!(
+                "Unsupported key type: {}-{}, expect AES-128, AES-256, ChaCha20-Poly1305-256, \
+                 RSA-2048, RSA-3072, RSA-4096 or EC-256",
+                algorithm, length
+            )),
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            KeyType::Aes128 | KeyType::Aes256 => ALGORITHM_AES,
+            KeyType::ChaCha20Poly1305 => ALGORITHM_CHACHA20_POLY1305,
+            KeyType::Rsa(_) => ALGORITHM_RSA,
+            KeyType::EcP256 => ALGORITHM_EC,
+        }
+    }
+
+    pub fn length(&self) -> u32 {
+        match self {
+            KeyType::Aes128 => 128,
+            KeyType::Aes256 | KeyType::ChaCha20Poly1305 | KeyType::EcP256 => 256,
+            KeyType::Rsa(bits) => *bits,
+        }
+    }
+
+    pub fn is_symmetric(&self) -> bool {
+        !matches!(self, KeyType::Rsa(_) | KeyType::EcP256)
+    }
+
+    /// The cipher the symmetric keys encrypt with when none is specified
+    pub fn cipher(&self) -> Option<Cipher> {
+        match self {
+            KeyType::Aes128 => Some(Cipher::Aes128Gcm),
+            KeyType::Aes256 => Some(Cipher::Aes256Gcm),
+            KeyType::ChaCha20Poly1305 => Some(Cipher::ChaCha20Poly1305),
+            KeyType::Rsa(_) | KeyType::EcP256 => None,
+        }
+    }
+
+    /// New random key material
+    pub fn generate(&self) -> Result<Vec<u8>, String> {
+        let mut rng = rand::rngs::OsRng;
+        match self {
+            KeyType::Rsa(bits) => RsaPrivateKey::new(&mut rng, *bits as usize)
+                .and_then(|key| key.to_pkcs8_der().map_err(|e| e.into()))
+                .map(|der| der.as_bytes().to_vec())
+                .map_err(|e| format!("Generate the RSA key failed: {}", e)),
+            KeyType::EcP256 => p256::SecretKey::random(&mut rng)
+                .to_pkcs8_der()
+                .map(|der| der.as_bytes().to_vec())
+                .map_err(|e| format!("Generate the EC key failed: {}", e)),
+            _ => {
+                let mut key = vec![0; self.length() as usize / 8];
+                rng.fill_bytes(&mut key);
+                Ok(key)
+            }
+        }
+    }
+
+    /// Whether the key material is a key of this type
+    pub fn check(&self, key: &[u8]) -> Result<(), String> {
+        match self {
+            KeyType::Rsa(bits) => {
+                let size = rsa_key(key)?.size() * 8;
+                match size == *bits as usize {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "The RSA key is {} bits long, expect {}",
+                        size, bits
+                    )),
+                }
+            }
+            KeyType::EcP256 => ec_key(key).map(|_| ()),
+            _ => match key.len() * 8 == self.length() as usize {
+                true => Ok(()),
+                false => Err(format!(
+                    "The key is {} bits long, expect {}",
+                    key.len() * 8,
+                    self.length()
+                )),
+            },
+        }
+    }
+
+    /// Encrypt the data, return the encrypted data and the iv it was encrypted with.
+    /// The symmetric keys encrypt with their cipher, RSA keys with OAEP SHA-256, EC keys with
+    /// AES-256-GCM by the key derived from the ECDH of an ephemeral key, whose public key
+    /// prefixes the encrypted data. RSA keys and AES-256-CTR can't authenticate the aad.
+    pub fn encrypt(
+        &self,
+        key: &[u8],
+        data: &[u8],
+        aad: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let mut rng = rand::rngs::OsRng;
+        match self {
+            KeyType::Rsa(_) if !aad.is_empty() => Err(NO_RSA_AAD.to_string()),
+            KeyType::Rsa(_) => {
+                let encrypted_data = rsa_key(key)?
+                    .to_public_key()
+                    .encrypt(&mut rng, PaddingScheme::new_oaep::<Sha256>(), data)
+                    .map_err(|e| format!("Encrypt data failed: {}", e))?;
+                Ok((encrypted_data, vec![]))
+            }
+            KeyType::EcP256 => {
+                let mut iv = vec![0; IV_LEN];
+                rng.fill_bytes(&mut iv);
+                let ephemeral = p256::ecdh::EphemeralSecret::random(&mut rng);
+                let shared = ephemeral.diffie_hellman(&ec_key(key)?.public_key());
+                let kek = derive_kek(shared.raw_secret_bytes())?;
+                let mut encrypted_data = p256::EncodedPoint::from(ephemeral.public_key())
+                    .as_bytes()
+                    .to_vec();
+                encrypted_data.extend(aes256_gcm::encrypt_with_aad(data, &kek, &iv, aad)?);
+                Ok((encrypted_data, iv))
+            }
+            _ => self.symmetric_cipher()?.encrypt(key, data, aad),
+        }
+    }
+
+    pub fn decrypt(
+        &self,
+        key: &[u8],
+        encrypted_data: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            KeyType::Rsa(_) if !aad.is_empty() => Err(NO_RSA_AAD.to_string()),
+            KeyType::Rsa(_) => rsa_key(key)?
+                .decrypt(PaddingScheme::new_oaep::<Sha256>(), encrypted_data)
+                .map_err(|e| format!("Decrypt data failed: {}", e)),
+            KeyType::EcP256 => {
+                if iv.len() != IV_LEN {
+                    return Err(format!("The iv must be {} bytes", IV_LEN));
+                }
+                if encrypted_data.len() < EC_POINT_LEN {
+                    return Err("Decrypt data failed: no ephemeral key".to_string());
+                }
+                let (point, encrypted_data) = encrypted_data.split_at(EC_POINT_LEN);
+                let ephemeral_key = p256::PublicKey::from_sec1_bytes(point)
+                    .map_err(|e| format!("Parse the ephemeral key failed: {}", e))?;
+                let shared = p256::ecdh::diffie_hellman(
+                    ec_key(key)?.to_nonzero_scalar(),
+                    ephemeral_key.as_affine(),
+                );
+                let kek = derive_kek(shared.raw_secret_bytes())?;
+                aes256_gcm::decrypt_with_aad(encrypted_data, &kek, iv, aad)
+            }
+            _ => self
+                .symmetric_cipher()?
+                .decrypt(key, encrypted_data, iv, aad),
+        }
+    }
+
+    /// Whether the key authenticates the additional data along the data it encrypts
+    pub fn binds_aad(&self) -> bool {
+        match self {
+            KeyType::Rsa(_) => false,
+            KeyType::EcP256 => true,
+            _ => self
+                .cipher()
+                .map_or(false, |cipher| cipher.is_authenticated()),
+        }
+    }
+
+    fn symmetric_cipher(&self) -> Result<Cipher, String> {
+        self.cipher()
+            .ok_or(format!("{} keys have no cipher", self.algorithm()))
+    }
+
+    /// The PEM encoded public key of a key pair
+    pub fn public_key(&self, key: &[u8]) -> Result<String, String> {
+        match self {
+            KeyType::Rsa(_) => rsa_key(key)?
+                .to_public_key()
+                .to_public_key_pem(LineEnding::LF),
+            KeyType::EcP256 => ec_key(key)?.public_key().to_public_key_pem(LineEnding::LF),
+            _ => return Err(format!("{} keys have no public key", self.algorithm())),
+        }
+        .map_err(|e| format!("Encode the public key failed: {}", e))
+    }
+
+    /// Sign the data, RSA keys with PKCS#1 v1.5 SHA-256, EC keys with DER encoded ECDSA SHA-256
+    pub fn sign(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            KeyType::Rsa(_) => rsa_key(key)?
+                .sign(
+                    PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+                    &Sha256::digest(data),
+                )
+                .map_err(|e| format!("Sign data failed: {}", e)),
+            KeyType::EcP256 => {
+                let signature: p256::ecdsa::Signature =
+                    p256::ecdsa::SigningKey::from(ec_key(key)?).sign(data);
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+            _ => Err(format!("{} keys can't sign", self.algorithm())),
+        }
+    }
+}
+
+fn rsa_key(key: &[u8]) -> Result<RsaPrivateKey, String> {
+    RsaPrivateKey::from_pkcs8_der(key).map_err(|e| format!("Parse the RSA key failed: {}", e))
+}
+
+fn ec_key(key: &[u8]) -> Result<p256::SecretKey, String> {
+    p256::SecretKey::from_pkcs8_der(key).map_err(|e| format!("Parse the EC key failed: {}", e))
+}
+
+fn derive_kek(shared_secret: &[u8]) -> Result<[u8; 32], String> {
+    let mut kek = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(ECDH_KDF_INFO, &mut kek)
+        .map_err(|e| format!("Derive the key-encryption key failed: {}", e))?;
+    Ok(kek)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(KeyType::parse("AES", 128), Ok(KeyType::Aes128));
+        assert_eq!(KeyType::parse("RSA", 3072), Ok(KeyType::Rsa(3072)));
+        assert!(KeyType::parse("AES", 192).is_err());
+        assert!(KeyType::parse("DES", 64).is_err());
+
+        let key_type = KeyType::parse("ChaCha20-Poly1305", 256).unwrap();
+        assert_eq!(key_type.algorithm(), ALGORITHM_CHACHA20_POLY1305);
+        assert_eq!(key_type.length(), 256);
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        for key_type in [
+            KeyType::Aes128,
+            KeyType::Aes256,
+            KeyType::ChaCha20Poly1305,
+            KeyType::Rsa(2048),
+            KeyType::EcP256,
+        ] {
+            let key = key_type.generate().unwrap();
+            assert!(key_type.check(&key).is_ok());
+
+            let (encrypted_data, iv) = key_type.encrypt(&key, b"test_data", &[]).unwrap();
+            let decrypted_data = key_type.decrypt(&key, &encrypted_data, &iv, &[]).unwrap();
+            assert_eq!(decrypted_data, b"test_data");
+
+            match key_type.binds_aad() {
+                true => {
+                    let (encrypted_data, iv) =
+                        key_type.encrypt(&key, b"test_data", b"kid").unwrap();
+                    assert!(key_type.decrypt(&key, &encrypted_data, &iv, b"kid").is_ok());
+                    assert!(key_type.decrypt(&key, &encrypted_data, &iv, &[]).is_err());
+                }
+                false => assert!(key_type.encrypt(&key, b"test_data", b"kid").is_err()),
+            }
+        }
+
+        assert!(KeyType::Aes256.check(&[0u8; 16]).is_err());
+        assert!(KeyType::EcP256.check(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        let key = KeyType::EcP256.generate().unwrap();
+        assert!(KeyType::EcP256.public_key(&key).is_ok());
+        assert!(KeyType::EcP256.sign(&key, b"test_data").is_ok());
+
+        let key = KeyType::Aes256.generate().unwrap();
+        assert!(KeyType::Aes256.sign(&key, b"test_data").is_err());
+    }
+}
@@ -1,2 +1,5 @@
 pub mod aes256_cbc;
 pub mod aes256_gcm;
+pub mod cipher;
+pub mod key_type;
+pub mod shamir;
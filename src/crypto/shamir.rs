@@ -0,0 +1,134 @@
+use rand::RngCore;
+
+/// A share of the secret: the value at x of the random polynomial of every secret byte
+#[derive(Clone, Debug, PartialEq)]
+pub struct Share {
+    pub x: u8,
+    pub y: Vec<u8>,
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1, without secret dependent branches
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= (b & 1).wrapping_neg() & a;
+        a = (a << 1) ^ ((a >> 7).wrapping_neg() & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+// a^254 is the inverse of a non zero a
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Split the secret into shares, any threshold of them recover it while fewer reveal nothing
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>, String> {
+    if threshold < 2 || threshold > shares {
+        return Err(format!(
+            "Threshold {} of {} shares, expect 2 <= threshold <= shares",
+            threshold, shares
+        ));
+    }
+
+    let mut split: Vec<Share> = (1..=shares)
+        .map(|x| Share {
+            x,
+            y: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+    let mut coefficients = vec![0u8; threshold as usize];
+    for byte in secret {
+        coefficients[0] = *byte;
+        rand::rngs::OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in split.iter_mut() {
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |y, coefficient| mul(y, share.x) ^ coefficient);
+            share.y.push(y);
+        }
+    }
+    coefficients
+        .iter_mut()
+        .for_each(|coefficient| *coefficient = 0);
+
+    Ok(split)
+}
+
+/// Recover the secret from the shares by Lagrange interpolation at 0
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>, String> {
+    let len = match shares.first() {
+        Some(share) => share.y.len(),
+        None => return Err("No share to combine".to_string()),
+    };
+    for (i, share) in shares.iter().enumerate() {
+        if share.x == 0 || share.y.len() != len {
+            return Err(format!("Share {} is broken", share.x));
+        }
+        if shares[..i].iter().any(|other| other.x == share.x) {
+            return Err(format!("Share {} is given twice", share.x));
+        }
+    }
+
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            shares
+                .iter()
+                .filter(|other| other.x != share.x)
+                .fold(1, |basis, other| {
+                    mul(basis, mul(other.x, inv(other.x ^ share.x)))
+                })
+        })
+        .collect();
+    Ok((0..len)
+        .map(|i| {
+            shares
+                .iter()
+                .zip(basis.iter())
+                .fold(0, |secret, (share, basis)| secret ^ mul(share.y[i], *basis))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inv() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let secret = b"01234567890123456789012345678901";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        assert_eq!(combine(&shares[..3]).unwrap(), secret.to_vec());
+        assert_eq!(combine(&shares[2..]).unwrap(), secret.to_vec());
+        let picked = vec![shares[4].clone(), shares[0].clone(), shares[2].clone()];
+        assert_eq!(combine(&picked).unwrap(), secret.to_vec());
+        assert_ne!(combine(&shares[..2]).unwrap(), secret.to_vec());
+
+        let twice = vec![shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert!(combine(&twice).is_err());
+        assert!(split(secret, 1, 5).is_err());
+        assert!(split(secret, 4, 3).is_err());
+    }
+}
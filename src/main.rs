@@ -19,6 +19,9 @@ extern crate log;
 
 shadow!(build);
 
+// Seconds between two purges of the soft deleted keys
+const KEY_PURGE_INTERVAL: u64 = 60;
+
 #[tokio::main]
 async fn main() {
     env_logger::builder()
@@ -40,6 +43,7 @@ async fn main() {
             return;
         }
     }
+    resources::opa::check_outdated();
 
     match gpg::default() {
         Ok(_) => {}
@@ -69,6 +73,15 @@ async fn main() {
                 .help("Work in listen mode")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("listener_name")
+                .long("listener-name")
+                .value_name("name")
+                .help(
+                    "Specify the listener's name seen by the policies, the listen addr by default",
+                )
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("tls")
                 .long("tls")
@@ -103,6 +116,81 @@ async fn main() {
                 .long("mutual")
                 .help("Work in mutual mode"),
         )
+        .arg(
+            Arg::with_name("opa_timeout")
+                .long("opa-timeout")
+                .value_name("milliseconds")
+                .help("Specify the deadline of a policy evaluation, 5000 by default, 0 for unbounded")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("opa_memory_limit")
+                .long("opa-memory-limit")
+                .value_name("MiB")
+                .help("Specify the live heap of the policy engine above which the policy evaluations are cancelled, 256 by default, 0 for unbounded")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key_store")
+                .long("key-store")
+                .value_name("backend")
+                .help("Specify the key storage backend: directory (default), sqlite or pkcs11")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key_store_path")
+                .long("key-store-path")
+                .value_name("path")
+                .help("Specify the key store's location, /opt/verdictd/keys/ for directory and /opt/verdictd/keys.db for sqlite by default, the PKCS#11 module for pkcs11, /usr/lib/softhsm/libsofthsm2.so by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pkcs11_token")
+                .long("pkcs11-token")
+                .value_name("label")
+                .help("Specify the label of the PKCS#11 token holding the keys, verdictd by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pkcs11_pin")
+                .long("pkcs11-pin")
+                .value_name("source")
+                .help("Log in the PKCS#11 token with the user PIN loaded from file:<PATH> or env:<VARIABLE>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("master_key")
+                .long("master-key")
+                .value_name("source")
+                .help("Wrap the stored keys with the master key loaded from file:<PATH>, env:<VARIABLE> or stdin")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("split_master_key")
+                .long("split-master-key")
+                .value_name("THRESHOLD-of-SHARES")
+                .help("Split the master key into SHARES shares, THRESHOLD of which unseal verdictd, print them and exit")
+                .requires("master_key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sealed")
+                .long("sealed")
+                .help("Start sealed, the attestation and key services are refused until the master key's shares are submitted")
+                .conflicts_with_all(&["master_key", "migrate_keys"]),
+        )
+        .arg(
+            Arg::with_name("migrate_keys")
+                .long("migrate-keys")
+                .help("Wrap the keys stored in plaintext with the master key and exit"),
+        )
+        .arg(
+            Arg::with_name("wrap_cipher")
+                .long("wrap-cipher")
+                .value_name("cipher")
+                .help("Specify the cipher the key provider wraps the image keys with: AES-128-GCM, AES-256-GCM, AES-256-CBC-HMAC-SHA256, AES-256-CTR or ChaCha20-Poly1305")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("client_api")
                 .long("client-api")
@@ -110,12 +198,23 @@ async fn main() {
                 .help("Specify the client API's listen addr")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("client_api_credentials")
+                .long("client-api-credentials")
+                .value_name("PATH")
+                .help("Specify the json file binding the client API's bearer tokens to the tenants whose keys they manage, \"*\" for every tenant")
+                .takes_value(true),
+        )
         .get_matches();
 
     let sockaddr = match matches.is_present("listen") {
         true => matches.value_of("listen").unwrap().to_string(),
         false => "127.0.0.1:1234".to_string(),
     };
+    let listener_name = match matches.is_present("listener_name") {
+        true => matches.value_of("listener_name").unwrap().to_string(),
+        false => sockaddr.clone(),
+    };
     let tls_type = match matches.is_present("tls") {
         true => matches.value_of("tls").unwrap().to_string(),
         false => "".to_string(),
@@ -134,10 +233,134 @@ async fn main() {
     };
 
     let mutual = matches.is_present("mutual");
+
+    let key_store = match matches.is_present("key_store") {
+        true => matches.value_of("key_store").unwrap().to_string(),
+        false => key_store::BACKEND_DIRECTORY.to_string(),
+    };
+    let pkcs11_login = match matches.value_of("pkcs11_pin") {
+        Some(source) => match pkcs11_key_manager::read_pin(source) {
+            Ok(pin) => Some(key_store::Pkcs11Login {
+                token: matches
+                    .value_of("pkcs11_token")
+                    .unwrap_or(pkcs11_key_manager::VERDICTD_PKCS11_TOKEN)
+                    .to_string(),
+                pin,
+            }),
+            Err(e) => {
+                error!("key store: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    match key_store::init(&key_store, matches.value_of("key_store_path"), pkcs11_login) {
+        Ok(_) => {}
+        Err(e) => {
+            error!("key store: {}", e);
+            return;
+        }
+    }
+    if matches.is_present("master_key") {
+        let key = match master_key::load(matches.value_of("master_key").unwrap()) {
+            Ok(key) => key,
+            Err(e) => {
+                error!("master key: {}", e);
+                return;
+            }
+        };
+        if let Some(spec) = matches.value_of("split_master_key") {
+            match unseal::split(&key, spec) {
+                Ok(shares) => shares.iter().for_each(|share| println!("{}", share)),
+                Err(e) => error!("split master key: {}", e),
+            }
+            return;
+        }
+        if let Err(e) = unseal::check_master_key(&key) {
+            error!("master key: {}", e);
+            return;
+        }
+        key_store::set_master_key(key);
+    }
+    if matches.is_present("sealed") {
+        match unseal::seal() {
+            Ok(_) => info!("verdictd is sealed until the master key's shares are submitted"),
+            Err(e) => {
+                error!("seal: {}", e);
+                return;
+            }
+        }
+    }
+    if matches.is_present("migrate_keys") {
+        match key_store::migrate() {
+            Ok(migrated) => info!("{} keys are migrated", migrated),
+            Err(e) => error!("migrate keys: {}", e),
+        }
+        return;
+    }
+
+    if let Some(cipher) = matches.value_of("wrap_cipher") {
+        match crypto::cipher::Cipher::parse(cipher) {
+            Ok(cipher) => client_api::key_provider::set_wrap_cipher(cipher),
+            Err(e) => {
+                error!("wrap cipher: {}", e);
+                return;
+            }
+        }
+    }
+
+    if let Some(path) = matches.value_of("client_api_credentials") {
+        match client_api::key_manager::load_credentials(path) {
+            Ok(count) => info!("{} client API credentials are loaded", count),
+            Err(e) => {
+                error!("client API credentials: {}", e);
+                return;
+            }
+        }
+    }
+
+    // Purge the soft deleted keys once their grace period is over
+    std::thread::spawn(|| loop {
+        let purged = key_store::purge_expired();
+        if purged > 0 {
+            info!("{} soft deleted keys are purged", purged);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(KEY_PURGE_INTERVAL));
+    });
+
+    let opa_limits = policy_engine::opa::opa_engine::Limits {
+        timeout_ms: match matches.is_present("opa_timeout") {
+            true => matches
+                .value_of("opa_timeout")
+                .unwrap()
+                .parse::<u64>()
+                .expect("opa-timeout is not a number"),
+            false => policy_engine::opa::opa_engine::DEFAULT_TIMEOUT_MS,
+        },
+        memory_limit: match matches.is_present("opa_memory_limit") {
+            true => {
+                matches
+                    .value_of("opa_memory_limit")
+                    .unwrap()
+                    .parse::<u64>()
+                    .expect("opa-memory-limit is not a number")
+                    * 1024
+                    * 1024
+            }
+            false => policy_engine::opa::opa_engine::DEFAULT_MEMORY_LIMIT,
+        },
+    };
+    policy_engine::opa::opa_engine::set_limits(opa_limits);
     std::thread::spawn(move || {
         info!("Listen addr: {}", sockaddr);
         attestation_agent::rats_tls::server(
-            &sockaddr, tls_type, crypto, attester, verifier, mutual,
+            &sockaddr,
+            listener_name,
+            tls_type,
+            crypto,
+            attester,
+            verifier,
+            mutual,
         );
     });
 
@@ -68,7 +68,12 @@ typedef struct { void *data; GoInt len; GoInt cap; } GoSlice;
 extern "C" {
 #endif
 
-extern char* makeDecisionGo(GoString policy, GoString data, GoString input);
+extern void freeCStringGo(char* str);
+
+// Evaluate the policy within timeoutMs milliseconds and memoryLimit bytes of heap growth,
+// 0 means unbounded
+extern char* makeDecisionGo(GoString policy, GoString data, GoString input, GoInt64 timeoutMs, GoInt64 memoryLimit);
+extern char* runTestsGo(GoString policy, GoString tests, GoString data);
 
 #ifdef __cplusplus
 }
@@ -1 +1,2 @@
 pub mod opa_engine;
+pub mod shadow;
@@ -1,18 +1,125 @@
 package main
 
+// #include <stdlib.h>
 import "C"
 
 import (
 	"context"
 	"encoding/json"
+	"fmt"
+	"runtime"
+	"runtime/metrics"
 	"strings"
+	"sync"
+	"sync/atomic"
+	"time"
+	"unsafe"
 
+	"github.com/open-policy-agent/opa/ast"
 	"github.com/open-policy-agent/opa/rego"
 	"github.com/open-policy-agent/opa/storage/inmem"
+	"github.com/open-policy-agent/opa/tester"
 )
 
+// Bytes of the heap held by objects, live or not yet swept
+const heapObjectsMetric = "/memory/classes/heap/objects:bytes"
+
+// How often the heap is checked during an evaluation
+const memoryCheckInterval = 10 * time.Millisecond
+
+// A single watcher collects the garbage at once, the others see its result
+var gcMutex sync.Mutex
+
+func heapObjects() uint64 {
+	sample := []metrics.Sample{{Name: heapObjectsMetric}}
+	metrics.Read(sample)
+	return sample[0].Value.Uint64()
+}
+
+// Whether the live objects of the Go heap exceed limit bytes. The heap is collected before
+// deciding it does, so the garbage not yet swept doesn't count.
+func heapExceeds(limit uint64) bool {
+	if heapObjects() <= limit {
+		return false
+	}
+	gcMutex.Lock()
+	defer gcMutex.Unlock()
+	if heapObjects() <= limit {
+		return false
+	}
+	runtime.GC()
+	return heapObjects() > limit
+}
+
+// Cancel the evaluation once the Go heap holds more than limit bytes of live objects.
+// This is a guard of the whole process' heap, not a limit of a single evaluation:
+// the heap is shared by every evaluation running, all of them are cancelled when it's exceeded.
+func watchMemory(ctx context.Context, cancel context.CancelFunc, limit uint64, exceeded *int32) {
+	ticker := time.NewTicker(memoryCheckInterval)
+	defer ticker.Stop()
+
+	for {
+		select {
+		case <-ctx.Done():
+			return
+		case <-ticker.C:
+			if heapExceeds(limit) {
+				atomic.StoreInt32(exceeded, 1)
+				cancel()
+				return
+			}
+		}
+	}
+}
+
+// A context cancelled after timeoutMs milliseconds, or once the Go heap holds more than
+// memoryLimit bytes of live objects, which sets exceeded. 0 means unbounded.
+func boundedContext(timeoutMs int64, memoryLimit int64, exceeded *int32) (context.Context, context.CancelFunc) {
+	var ctx context.Context
+	var cancel context.CancelFunc
+	if timeoutMs > 0 {
+		ctx, cancel = context.WithTimeout(context.Background(), time.Duration(timeoutMs)*time.Millisecond)
+	} else {
+		ctx, cancel = context.WithCancel(context.Background())
+	}
+	if memoryLimit > 0 {
+		go watchMemory(ctx, cancel, uint64(memoryLimit), exceeded)
+	}
+	return ctx, cancel
+}
+
+// Why the bounded context was cancelled, empty if it wasn't
+func exceededReason(ctx context.Context, exceeded *int32, timeoutMs int64, memoryLimit int64) string {
+	if atomic.LoadInt32(exceeded) == 1 {
+		return fmt.Sprintf("policy evaluation cancelled, the policy engine's heap exceeded %d bytes", memoryLimit)
+	}
+	if ctx.Err() == context.DeadlineExceeded {
+		return fmt.Sprintf("policy evaluation timed out after %d ms", timeoutMs)
+	}
+	return ""
+}
+
+// A deny decision, for evaluations that couldn't complete
+func denyDecision(reason string) *C.char {
+	decision, err := json.Marshal(map[string]interface{}{
+		"allow":  false,
+		"reason": reason,
+	})
+	if err != nil {
+		return C.CString("Marshal decision error.")
+	}
+	return C.CString(string(decision))
+}
+
+//export freeCStringGo
+func freeCStringGo(str *C.char) {
+	C.free(unsafe.Pointer(str))
+}
+
+// Evaluate the policy within timeoutMs milliseconds while the Go heap stays under memoryLimit
+// bytes of live objects, 0 means unbounded
 //export makeDecisionGo
-func makeDecisionGo(policy string, data string, input string) *C.char {
+func makeDecisionGo(policy string, data string, input string, timeoutMs int64, memoryLimit int64) *C.char {
 	// Deserialize the message in json format
 	input_map := make(map[string]interface{})
 	err := json.Unmarshal([]byte(input), &input_map)
@@ -37,18 +144,28 @@ func makeDecisionGo(policy string, data string, input string) *C.char {
 		rego.Store(store),
 	)
 
+	// Both the preparation and the evaluation are bounded, the query is
+	// cancelled as soon as the context is done
+	var memoryExceeded int32
+	ctx, cancel := boundedContext(timeoutMs, memoryLimit, &memoryExceeded)
+	defer cancel()
+
 	// Create a prepared query that can be evaluated.
-	ctx := context.Background()
+	var rs rego.ResultSet
 	query, err := r.PrepareForEval(ctx)
-	if err != nil {
-		return C.CString(err.Error())
+	if err == nil {
+		// Make opa query
+		rs, err = query.Eval(ctx, rego.EvalInput(input_map))
 	}
-
-	// Make opa query
-	rs, err := query.Eval(ctx, rego.EvalInput(input_map))
 	if err != nil {
+		if reason := exceededReason(ctx, &memoryExceeded, timeoutMs, memoryLimit); reason != "" {
+			return denyDecision(reason)
+		}
 		return C.CString(err.Error())
 	}
+	if len(rs) == 0 {
+		return C.CString("Undefined decision.")
+	}
 
 	// Transform the processed decision into the format rust hopes for
 	inputOPA := rs[0].Expressions[0].Value.(map[string]interface{})
@@ -63,6 +180,10 @@ func makeDecisionGo(policy string, data string, input string) *C.char {
 	decisionMap := make(map[string]interface{})
 	decisionMap["parseInfo"] = parseInfo
 	decisionMap["allow"] = dataOPA["allow"]
+	// The tenant whose keys the peer reaches, if the policy binds it to one
+	if tenant, ok := dataOPA["tenant"]; ok {
+		decisionMap["tenant"] = tenant
+	}
 
 	decision, err := json.Marshal(decisionMap)
 	if err != nil {
@@ -75,4 +196,76 @@ func makeDecisionGo(policy string, data string, input string) *C.char {
 	return C.CString(res)
 }
 
+// Run the tests within timeoutMs milliseconds and under the memoryLimit heap guard, as makeDecisionGo.
+// Tests that couldn't complete fail the run with the reason.
+//export runTestsGo
+func runTestsGo(policy string, tests string, data string, timeoutMs int64, memoryLimit int64) *C.char {
+	data_map := make(map[string]interface{})
+	err := json.Unmarshal([]byte(data), &data_map)
+	if err != nil {
+		return C.CString("Unmarshal data error.")
+	}
+	store := inmem.NewFromObject(data_map)
+
+	// The policy and its tests are compiled together so that the test
+	// rules can reference the policy's rules directly
+	policy_module, err := ast.ParseModule("policy.rego", policy)
+	if err != nil {
+		return C.CString(err.Error())
+	}
+	tests_module, err := ast.ParseModule("policy_test.rego", tests)
+	if err != nil {
+		return C.CString(err.Error())
+	}
+	modules := map[string]*ast.Module{
+		"policy.rego":      policy_module,
+		"policy_test.rego": tests_module,
+	}
+
+	var memoryExceeded int32
+	ctx, cancel := boundedContext(timeoutMs, memoryLimit, &memoryExceeded)
+	defer cancel()
+
+	txn, err := store.NewTransaction(ctx)
+	if err != nil {
+		return C.CString(err.Error())
+	}
+	defer store.Abort(context.Background(), txn)
+
+	ch, err := tester.NewRunner().SetStore(store).SetModules(modules).RunTests(ctx, txn)
+	if err != nil {
+		if reason := exceededReason(ctx, &memoryExceeded, timeoutMs, memoryLimit); reason != "" {
+			return C.CString(reason)
+		}
+		return C.CString(err.Error())
+	}
+
+	// Collect the per-test results in the format rust hopes for
+	results := make([]map[string]interface{}, 0)
+	for r := range ch {
+		result := make(map[string]interface{})
+		result["package"] = r.Package
+		result["name"] = r.Name
+		result["pass"] = !r.Fail && r.Error == nil
+		result["error"] = ""
+		if r.Error != nil {
+			result["error"] = r.Error.Error()
+		}
+		results = append(results, result)
+	}
+	if reason := exceededReason(ctx, &memoryExceeded, timeoutMs, memoryLimit); reason != "" {
+		return C.CString(reason)
+	}
+
+	resultsMap := make(map[string]interface{})
+	resultsMap["results"] = results
+
+	res, err := json.Marshal(resultsMap)
+	if err != nil {
+		return C.CString("Marshal test results error.")
+	}
+
+	return C.CString(string(res))
+}
+
 func main() {}
@@ -1,11 +1,71 @@
 use crate::resources::opa;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::ffi::CStr;
 use std::os::raw::c_char;
 
 // Link import cgo function
 #[link(name = "opa")]
 extern "C" {
-    pub fn makeDecisionGo(policy: GoString, data: GoString, input: GoString) -> *mut c_char;
+    pub fn makeDecisionGo(
+        policy: GoString,
+        data: GoString,
+        input: GoString,
+        timeout_ms: i64,
+        memory_limit: i64,
+    ) -> *mut c_char;
+    pub fn runTestsGo(
+        policy: GoString,
+        tests: GoString,
+        data: GoString,
+        timeout_ms: i64,
+        memory_limit: i64,
+    ) -> *mut c_char;
+    pub fn freeCStringGo(str: *mut c_char);
+}
+
+/// Bounds of the policy evaluations, 0 means unbounded
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    // Deadline of a single evaluation
+    pub timeout_ms: u64,
+    // Guard of the policy engine's whole heap in bytes of live objects, not a limit of a single
+    // evaluation: the evaluations running are cancelled once the heap exceeds it
+    pub memory_limit: u64,
+}
+
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_MEMORY_LIMIT: u64 = 256 * 1024 * 1024;
+
+lazy_static! {
+    static ref LIMITS: RwLock<Limits> = RwLock::new(Limits {
+        timeout_ms: DEFAULT_TIMEOUT_MS,
+        memory_limit: DEFAULT_MEMORY_LIMIT,
+    });
+}
+
+pub fn set_limits(limits: Limits) {
+    info!("opa evaluation limits: {:?}", limits);
+    *LIMITS.write() = limits;
+}
+
+pub fn limits() -> Limits {
+    *LIMITS.read()
+}
+
+/// Result of a single Rego unit test rule
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TestResult {
+    pub package: String,
+    pub name: String,
+    pub pass: bool,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TestResults {
+    results: Vec<TestResult>,
 }
 
 /// String structure passed into cgo
@@ -26,7 +86,7 @@ pub fn make_decision(policy_name: &str, data_name: &str, input: &str) -> Result<
         n: policy.len() as isize,
     };
 
-    let data = opa::export(data_name)?;
+    let data = opa::with_revocations(opa::export(data_name)?)?;
     let data_go = GoString {
         p: data.as_ptr() as *const i8,
         n: data.len() as isize,
@@ -37,13 +97,7 @@ pub fn make_decision(policy_name: &str, data_name: &str, input: &str) -> Result<
         n: input.len() as isize,
     };
 
-    // Call the function exported by cgo and process the returned decision
-    let decision_buf: *mut c_char = unsafe { makeDecisionGo(policy_go, data_go, input_go) };
-    let decision_str: &CStr = unsafe { CStr::from_ptr(decision_buf) };
-    decision_str
-        .to_str()
-        .map_err(|e| e.to_string())
-        .and_then(|str| Ok(str.to_string()))
+    decide(policy_go, data_go, input_go)
 }
 
 pub fn make_decision_ext(
@@ -66,6 +120,7 @@ pub fn make_decision_ext(
     } else {
         opa::export(reference_name).unwrap()
     };
+    let reference = opa::with_revocations(reference)?;
 
     let policy_go = GoString {
         p: policy.as_str().as_ptr() as *const i8,
@@ -82,11 +137,88 @@ pub fn make_decision_ext(
         n: input.len() as isize,
     };
 
-    // Call the function exported by cgo and process the returned decision
-    let decision_buf: *mut c_char = unsafe { makeDecisionGo(policy_go, reference_go, input_go) };
-    let decision_str: &CStr = unsafe { CStr::from_ptr(decision_buf) };
-    decision_str
+    decide(policy_go, reference_go, input_go)
+}
+
+// Make the decision with the policy and data contents as they are, the stored revocations
+// aren't added to the data
+pub fn make_decision_with(policy: &str, data: &str, input: &str) -> Result<String, String> {
+    let policy_go = GoString {
+        p: policy.as_ptr() as *const i8,
+        n: policy.len() as isize,
+    };
+
+    let data_go = GoString {
+        p: data.as_ptr() as *const i8,
+        n: data.len() as isize,
+    };
+
+    let input_go = GoString {
+        p: input.as_ptr() as *const i8,
+        n: input.len() as isize,
+    };
+
+    decide(policy_go, data_go, input_go)
+}
+
+// Call the function exported by cgo within the evaluation limits, and process the returned decision.
+// A decision that couldn't complete in time or memory is a deny carrying the reason.
+fn decide(policy: GoString, data: GoString, input: GoString) -> Result<String, String> {
+    let limits = limits();
+    let decision_buf: *mut c_char = unsafe {
+        makeDecisionGo(
+            policy,
+            data,
+            input,
+            limits.timeout_ms as i64,
+            limits.memory_limit as i64,
+        )
+    };
+    take_string(decision_buf)
+}
+
+// Copy the string allocated by cgo, and free it
+fn take_string(buf: *mut c_char) -> Result<String, String> {
+    let str = unsafe { CStr::from_ptr(buf) }
         .to_str()
         .map_err(|e| e.to_string())
-        .and_then(|str| Ok(str.to_string()))
+        .and_then(|str| Ok(str.to_string()));
+    unsafe { freeCStringGo(buf) };
+    str
+}
+
+// Run the `test_` rules in tests against the policy and reference contents, within the
+// evaluation limits. Tests that couldn't complete in time or memory fail with the reason.
+pub fn run_tests(policy: &str, reference: &str, tests: &str) -> Result<Vec<TestResult>, String> {
+    let policy_go = GoString {
+        p: policy.as_ptr() as *const i8,
+        n: policy.len() as isize,
+    };
+
+    let tests_go = GoString {
+        p: tests.as_ptr() as *const i8,
+        n: tests.len() as isize,
+    };
+
+    let reference_go = GoString {
+        p: reference.as_ptr() as *const i8,
+        n: reference.len() as isize,
+    };
+
+    // Call the function exported by cgo, anything but the results json is an error message
+    let limits = limits();
+    let results_buf: *mut c_char = unsafe {
+        runTestsGo(
+            policy_go,
+            tests_go,
+            reference_go,
+            limits.timeout_ms as i64,
+            limits.memory_limit as i64,
+        )
+    };
+    take_string(results_buf).and_then(|str| {
+        serde_json::from_str::<TestResults>(&str)
+            .map_err(|_| str.to_string())
+            .and_then(|res| Ok(res.results))
+    })
 }
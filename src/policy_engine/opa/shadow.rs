@@ -0,0 +1,219 @@
+use crate::policy_engine::opa::opa_engine;
+use crate::resources::file;
+use crate::resources::opa;
+use crate::resources::opa_schema;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Shadow evaluations running at once, the ones beyond it are dropped rather than queued
+const MAX_IN_FLIGHT: usize = 4;
+
+/// A candidate policy evaluated alongside the active policy of a TEE type,
+/// its decision is never enforced, only compared with the active decision
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShadowPolicy {
+    pub policy: String,
+    // Reference used by the candidate, the active reference if it's None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    // The counters are kept in memory, they restart from zero with verdictd
+    #[serde(skip)]
+    pub evaluations: u64,
+    #[serde(skip)]
+    pub disagreements: u64,
+    #[serde(skip)]
+    pub errors: u64,
+}
+
+impl ShadowPolicy {
+    fn is(&self, other: &ShadowPolicy) -> bool {
+        self.policy == other.policy && self.reference == other.reference
+    }
+}
+
+lazy_static! {
+    // Counters of the shadow policies indexed by TEE type, the registrations
+    // themselves are stored in OPA_SHADOW_POLICIES
+    static ref COUNTERS: Mutex<HashMap<String, ShadowPolicy>> = Mutex::new(HashMap::new());
+    static ref IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+}
+
+// The registered shadow policies indexed by TEE type, the caller holds FILE_LOCK
+fn read() -> Result<BTreeMap<String, ShadowPolicy>, String> {
+    let src = String::from(opa::OPA_PATH) + opa::OPA_SHADOW_POLICIES;
+    if !Path::new(&src).exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    file::export_string(&src)
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Load {} failed: {}", opa::OPA_SHADOW_POLICIES, e))
+}
+
+fn load() -> Result<BTreeMap<String, ShadowPolicy>, String> {
+    let lock = opa::FILE_LOCK.read();
+    assert_eq!(*lock, 0);
+    read()
+}
+
+// Apply the change to the stored registrations, the caller holds FILE_LOCK
+fn write(shadows: &BTreeMap<String, ShadowPolicy>) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(shadows).map_err(|e| e.to_string())?;
+    opa_schema::validate(opa_schema::tee_of(opa::OPA_SHADOW_POLICIES), &content)?;
+
+    file::set(
+        &(String::from(opa::OPA_PATH) + opa::OPA_SHADOW_POLICIES),
+        &content,
+    )
+}
+
+// The registration with the counters counted for it, stale counters are dropped
+fn with_counters(
+    counters: &mut HashMap<String, ShadowPolicy>,
+    tee: &str,
+    shadow: ShadowPolicy,
+) -> ShadowPolicy {
+    match counters.get(tee) {
+        Some(counted) if counted.is(&shadow) => counted.clone(),
+        _ => {
+            counters.remove(tee);
+            shadow
+        }
+    }
+}
+
+pub fn register(tee: &str, policy: &str, reference: Option<&str>) -> Result<(), String> {
+    info!("register shadow policy {} for {}", policy, tee);
+    let lock = opa::FILE_LOCK.write();
+    assert_eq!(*lock, 0);
+
+    let mut shadows = read()?;
+    shadows.insert(
+        tee.to_string(),
+        ShadowPolicy {
+            policy: policy.to_string(),
+            reference: reference.map(|reference| reference.to_string()),
+            evaluations: 0,
+            disagreements: 0,
+            errors: 0,
+        },
+    );
+    write(&shadows)?;
+    COUNTERS.lock().remove(tee);
+    Ok(())
+}
+
+pub fn unregister(tee: &str) -> Result<Option<ShadowPolicy>, String> {
+    let lock = opa::FILE_LOCK.write();
+    assert_eq!(*lock, 0);
+
+    let mut shadows = read()?;
+    let shadow = match shadows.remove(tee) {
+        Some(shadow) => shadow,
+        None => return Ok(None),
+    };
+    write(&shadows)?;
+    Ok(Some(with_counters(&mut COUNTERS.lock(), tee, shadow)))
+}
+
+pub fn get(tee: &str) -> Result<Option<ShadowPolicy>, String> {
+    Ok(load()?
+        .remove(tee)
+        .map(|shadow| with_counters(&mut COUNTERS.lock(), tee, shadow)))
+}
+
+pub fn list() -> Result<Vec<(String, ShadowPolicy)>, String> {
+    let shadows = load()?;
+    let mut counters = COUNTERS.lock();
+    Ok(shadows
+        .into_iter()
+        .map(|(tee, shadow)| {
+            let shadow = with_counters(&mut counters, &tee, shadow);
+            (tee, shadow)
+        })
+        .collect())
+}
+
+pub fn is_shadow_policy(policy: &str) -> bool {
+    match load() {
+        Ok(shadows) => shadows.values().any(|shadow| shadow.policy == policy),
+        // Refuse to touch anything while the registrations are unreadable
+        Err(_) => true,
+    }
+}
+
+/// Evaluate the TEE type's shadow policy, if any, with the input the active policy
+/// decided on in the background, the attestation doesn't wait for it
+pub fn observe(tee: &'static str, reference: &'static str, input: String, allow: bool) {
+    let shadow = match get(tee) {
+        Ok(Some(shadow)) => shadow,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("shadow policy of {} not evaluated: {}", tee, e);
+            return;
+        }
+    };
+
+    if IN_FLIGHT.fetch_add(1, Ordering::SeqCst) >= MAX_IN_FLIGHT {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        debug!(
+            "shadow policy {} not evaluated, {} evaluations are running",
+            shadow.policy, MAX_IN_FLIGHT
+        );
+        return;
+    }
+    std::thread::spawn(move || {
+        evaluate(tee, &shadow, reference, &input, allow);
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+// Evaluate the shadow policy and count whether its decision agrees with the active one
+fn evaluate(tee: &str, shadow: &ShadowPolicy, reference: &str, input: &str, allow: bool) {
+    let reference = shadow.reference.as_deref().unwrap_or(reference);
+
+    let shadow_allow = opa_engine::make_decision(&shadow.policy, reference, input)
+        .and_then(|res| {
+            serde_json::from_str::<serde_json::Value>(&res)
+                .map_err(|_| format!("Json unmashall failed: {}", res))
+        })
+        .and_then(|res| match res["reason"].as_str() {
+            // The evaluation didn't complete, its deny isn't a decision to compare
+            Some(reason) => Err(reason.to_string()),
+            None => Ok(res["allow"] == true),
+        });
+
+    let mut counters = COUNTERS.lock();
+    // The shadow policy may have been replaced while it was being evaluated
+    let counted = counters
+        .entry(tee.to_string())
+        .or_insert_with(|| shadow.clone());
+    if !counted.is(shadow) {
+        return;
+    }
+    counted.evaluations += 1;
+    match shadow_allow {
+        Ok(shadow_allow) if shadow_allow == allow => {}
+        Ok(shadow_allow) => {
+            counted.disagreements += 1;
+            // The input carries the peer's evidence, only its digest is logged
+            warn!(
+                "shadow policy {} disagrees with the active {} policy: active allow: {}, shadow allow: {}, input sha256: {}",
+                shadow.policy,
+                tee,
+                allow,
+                shadow_allow,
+                base64::encode(Sha256::digest(input.as_bytes()))
+            );
+        }
+        Err(e) => {
+            counted.errors += 1;
+            warn!("shadow policy {} evaluation failed: {}", shadow.policy, e);
+        }
+    }
+}
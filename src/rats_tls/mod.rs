@@ -6,13 +6,44 @@ use crate::policy_engine;
 use crate::resources;
 use base64;
 use foreign_types::{ForeignType, ForeignTypeRef, Opaque};
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::RefCell;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::io::RawFd;
 use std::ptr::NonNull;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 mod ffi;
 use ffi::*;
 
+/// Context of the connection under verification, the policies see it as `input.context`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PolicyContext {
+    pub peer: String,
+    pub listener: String,
+    // Instance types verdictd was configured with, empty if rats-tls selects it
+    pub tls_type: String,
+    pub crypto: String,
+    pub attester: String,
+    pub verifier: String,
+    // Seconds since the unix epoch when the decision is made
+    pub time: u64,
+    // Requested command and resource, they are null during the negotiation
+    pub command: Option<String>,
+    pub resource: Option<Value>,
+}
+
+thread_local! {
+    // rats-tls calls the verification callback on the thread which negotiates,
+    // and every connection is served by its own thread
+    static POLICY_CONTEXT: RefCell<PolicyContext> = RefCell::new(PolicyContext::default());
+    // Evidence accepted during the negotiation, it's evaluated again for every request
+    static VERIFIED_EVIDENCE: RefCell<Option<(&'static str, Value)>> = RefCell::new(None);
+    // Tenant the policies bound the peer to during the negotiation
+    static TENANT: RefCell<Option<String>> = RefCell::new(None);
+}
+
 pub struct RatsTlsRef(Opaque);
 
 unsafe impl ForeignTypeRef for RatsTlsRef {
@@ -159,6 +190,136 @@ impl RatsTls {
         }
     }
 
+    /// Set the context of the connection served by the current thread,
+    /// it must be called before negotiate()
+    pub fn set_context(context: PolicyContext) {
+        POLICY_CONTEXT.with(|ctx| *ctx.borrow_mut() = context);
+        VERIFIED_EVIDENCE.with(|evidence| *evidence.borrow_mut() = None);
+        TENANT.with(|tenant| *tenant.borrow_mut() = None);
+    }
+
+    /// Evaluate the evidence accepted during the negotiation again with the requested
+    /// command and resource, nothing is evaluated if the peer wasn't attested
+    pub fn authorize(command: &str, resource: Value) -> Result<(), String> {
+        let evidence = VERIFIED_EVIDENCE.with(|evidence| evidence.borrow().clone());
+        match evidence {
+            Some((tee, evidence)) => {
+                POLICY_CONTEXT.with(|ctx| {
+                    let mut ctx = ctx.borrow_mut();
+                    ctx.command = Some(command.to_string());
+                    ctx.resource = Some(resource);
+                });
+                Self::decide(tee, &evidence, false).map(|_| ())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The TEE type and the evidence accepted during the negotiation, None if the peer wasn't attested
+    pub fn verified_evidence() -> Option<(&'static str, Value)> {
+        VERIFIED_EVIDENCE.with(|evidence| evidence.borrow().clone())
+    }
+
+    /// The tenant the policies bound the peer to during the negotiation, None if they didn't
+    pub fn tenant() -> Option<String> {
+        TENANT.with(|tenant| tenant.borrow().clone())
+    }
+
+    // Enforce the combined decision of the TEE type's policy set, return the tenant
+    // the allowing policies bind the peer to. The TEE type's shadow policy only observes
+    // the handshake's decision, once per attestation and off the handshake path.
+    fn decide(
+        tee: &'static str,
+        evidence: &Value,
+        handshake: bool,
+    ) -> Result<Option<String>, String> {
+        let (_, baseline_reference) =
+            resources::opa::verifier_files(tee).ok_or(format!("No policy for TEE type {}", tee))?;
+        let set = resources::opa_policy_set::get(tee)?;
+
+        let mut input = evidence.clone();
+        input["context"] = POLICY_CONTEXT.with(|ctx| {
+            let mut ctx = ctx.borrow_mut();
+            ctx.time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0);
+            serde_json::to_value(&*ctx).unwrap_or(Value::Null)
+        });
+        let input = input.to_string();
+
+        let decisions: Vec<Result<Option<String>, String>> = set
+            .policies
+            .iter()
+            .map(|policy_ref| {
+                Self::evaluate(&policy_ref.policy, &policy_ref.reference, &input)
+                    .map_err(|e| format!("{}: {}", policy_ref.policy, e))
+            })
+            .collect();
+        let allows: Vec<bool> = decisions.iter().map(|res| res.is_ok()).collect();
+        let res = match set.mode.combine(&allows) {
+            true => Self::tenant_of(&decisions),
+            false => {
+                let denials: Vec<String> =
+                    decisions.into_iter().filter_map(|res| res.err()).collect();
+                Err(denials.join("; "))
+            }
+        };
+
+        if handshake {
+            policy_engine::opa::shadow::observe(tee, baseline_reference, input, res.is_ok());
+        }
+
+        res
+    }
+
+    // The allowing policies binding a tenant must agree on it
+    fn tenant_of(decisions: &[Result<Option<String>, String>]) -> Result<Option<String>, String> {
+        let mut tenants = decisions
+            .iter()
+            .filter_map(|res| res.clone().ok().flatten());
+        let tenant = tenants.next();
+        match tenants.find(|other| Some(other) != tenant.as_ref()) {
+            Some(other) => Err(format!(
+                "the policies bind the peer to tenants {} and {}",
+                tenant.unwrap_or_default(),
+                other
+            )),
+            None => Ok(tenant),
+        }
+    }
+
+    fn evaluate(policy: &str, reference: &str, input: &str) -> Result<Option<String>, String> {
+        policy_engine::opa::opa_engine::make_decision(policy, reference, input)
+            .map_err(|e| format!("make_decision error: {}", e))
+            .and_then(|res| {
+                serde_json::from_str(&res).map_err(|_| "Json unmashall failed".to_string())
+            })
+            .and_then(|res: serde_json::Value| {
+                if res["allow"] == true {
+                    match &res["tenant"] {
+                        Value::Null => Ok(None),
+                        Value::String(tenant) => Ok(Some(tenant.clone())),
+                        tenant => Err(format!("the tenant {} isn't a string", tenant)),
+                    }
+                } else {
+                    error!("parseInfo: {}", res["parseInfo"].to_string());
+                    match res["reason"].as_str() {
+                        Some(reason) => Err(format!("decision is false: {}", reason)),
+                        None => Err("decision is false".to_string()),
+                    }
+                }
+            })
+    }
+
+    fn verify(tee: &'static str, evidence: Value) -> Result<(), String> {
+        Self::decide(tee, &evidence, true).and_then(|tenant| {
+            VERIFIED_EVIDENCE.with(|verified| *verified.borrow_mut() = Some((tee, evidence)));
+            TENANT.with(|bound| *bound.borrow_mut() = tenant);
+            Ok(())
+        })
+    }
+
     fn sgx_callback(ev: rtls_sgx_evidence_t) -> Result<(), String> {
         let mr_enclave =
             base64::encode(unsafe { std::slice::from_raw_parts(ev.mr_enclave, 32).to_vec() });
@@ -172,21 +333,7 @@ impl RatsTls {
             "svn": ev.security_version
         });
 
-        policy_engine::opa::opa_engine::make_decision(
-            resources::opa::OPA_POLICY_SGX,
-            resources::opa::OPA_DATA_SGX,
-            &input.to_string(),
-        )
-        .map_err(|e| format!("make_decision error: {}", e))
-        .and_then(|res| serde_json::from_str(&res).map_err(|_| "Json unmashall failed".to_string()))
-        .and_then(|res: serde_json::Value| {
-            if res["allow"] == true {
-                Ok(())
-            } else {
-                error!("parseInfo: {}", res["parseInfo"].to_string());
-                Err("decision is false".to_string())
-            }
-        })
+        Self::verify(resources::opa_schema::TEE_SGX, input)
     }
 
     fn csv_callback(ev: rtls_csv_evidence_t) -> Result<(), String> {
@@ -195,21 +342,7 @@ impl RatsTls {
 
         let input = serde_json::json!({ "measure": measure_b64 });
 
-        policy_engine::opa::opa_engine::make_decision(
-            resources::opa::OPA_POLICY_CSV,
-            resources::opa::OPA_DATA_CSV,
-            &input.to_string(),
-        )
-        .map_err(|e| format!("make_decision error: {}", e))
-        .and_then(|res| serde_json::from_str(&res).map_err(|_| "Json unmashall failed".to_string()))
-        .and_then(|res: serde_json::Value| {
-            if res["allow"] == true {
-                Ok(())
-            } else {
-                error!("parseInfo: {}", res["parseInfo"].to_string());
-                Err("decision is false".to_string())
-            }
-        })
+        Self::verify(resources::opa_schema::TEE_CSV, input)
     }
 
     #[no_mangle]
@@ -221,6 +354,7 @@ impl RatsTls {
         } else if unsafe { (*evidence).type_ } == enclave_evidence_type_t_CSV {
             Self::csv_callback(unsafe { (*evidence).__bindgen_anon_1.csv })
         } else {
+            // TDX evidence carries no measurement yet, there's no TDX policy or reference to decide with
             Err("Not implemented".to_string())
         };
 
@@ -0,0 +1,59 @@
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fs;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const AUDIT_LOG: &str = "/opt/verdictd/audit.log";
+// Kid of the operations on all the keys at once, such as the backups
+pub const ALL_KIDS: &str = "*";
+
+lazy_static! {
+    // Serialize the appends so records never interleave
+    static ref AUDIT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A key management operation, appended to the audit log as a json line
+#[derive(Serialize, Debug)]
+pub struct Record<'a> {
+    pub time: u64,
+    pub action: &'a str,
+    pub kid: &'a str,
+    // Address of the client API caller, "verdictd" for the operations verdictd runs by itself
+    pub client: &'a str,
+    pub result: String,
+}
+
+pub fn record(action: &str, kid: &str, client: &str, result: &Result<(), String>) {
+    let record = Record {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0),
+        action,
+        kid,
+        client,
+        result: match result {
+            Ok(_) => "OK".to_string(),
+            Err(e) => e.clone(),
+        },
+    };
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line + "\n",
+        Err(e) => {
+            error!("serialize audit record failed: {}", e);
+            return;
+        }
+    };
+
+    let _lock = AUDIT_LOCK.lock();
+    let res = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(AUDIT_LOG)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = res {
+        error!("write audit record {} failed: {}", line.trim_end(), e);
+    }
+}
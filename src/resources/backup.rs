@@ -0,0 +1,428 @@
+use crate::crypto::aes256_gcm;
+use crate::resources::key_store::{self, KeyBackup};
+use crate::resources::{gpg, image, opa};
+use hkdf::Hkdf;
+use hmac::Hmac;
+use p256::pkcs8::DecodePublicKey;
+use rand::RngCore;
+use rsa::{PaddingScheme, PublicKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the archive format, restore refuses the archives of other versions
+pub const BACKUP_VERSION: u32 = 1;
+
+/// The data key is wrapped with AES-256-GCM by the key derived from the passphrase with PBKDF2-HMAC-SHA256
+pub const PROTECTION_PASSPHRASE: &str = "passphrase";
+/// The data key is encrypted with the operator's RSA public key, OAEP padded with SHA-256
+pub const PROTECTION_RSA_OAEP: &str = "RSA-OAEP";
+/// The data key is wrapped with AES-256-GCM by the key derived with HKDF-SHA256 from
+/// the ECDH of an ephemeral P-256 key and the operator's EC public key
+pub const PROTECTION_ECDH: &str = "ECDH-ES";
+/// HKDF info of the key derived from the ECDH shared secret
+pub const ECDH_KDF_INFO: &[u8] = b"verdictd backup";
+
+const PBKDF2_ROUNDS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const DATA_KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+
+// The directories backed up, by their name in the archive
+const DIRECTORIES: [(&str, &str); 3] = [
+    ("opa", opa::OPA_PATH),
+    ("gpg", gpg::GPG_PATH),
+    ("image", image::IMAGE_PATH),
+];
+
+/// How the archive's data key is protected
+pub enum Protection {
+    Passphrase(String),
+    // PEM encoded RSA or P-256 public key
+    PublicKey(String),
+}
+
+/// What the archive's data key is recovered with: the passphrase, or the data key itself
+/// unwrapped by the holder of the private key
+pub enum Unlock {
+    Passphrase(String),
+    DataKey(Vec<u8>),
+}
+
+// The backed up state, encrypted in the envelope
+#[derive(Serialize, Deserialize, Debug)]
+struct Archive {
+    version: u32,
+    created: u64,
+    keys: Vec<KeyBackup>,
+    // Base64 encoded contents, by "<directory>/<file name>"
+    files: BTreeMap<String, String>,
+}
+
+/// The encrypted archive: the archive is encrypted with AES-256-GCM by a random data key,
+/// wrapped as protection says. The binary fields are base64 encoded.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Envelope {
+    pub version: u32,
+    pub protection: String,
+    #[serde(default)]
+    pub salt: String,
+    #[serde(default)]
+    pub iterations: u32,
+    pub wrapped_key: String,
+    #[serde(default)]
+    pub ephemeral_key: String,
+    #[serde(default)]
+    pub key_iv: String,
+    pub iv: String,
+    pub data: String,
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    rand::rngs::OsRng.fill_bytes(&mut data);
+    data
+}
+
+fn decode(name: &str, data: &str) -> Result<Vec<u8>, String> {
+    base64::decode(data).map_err(|e| format!("The archive's {} is broken: {}", name, e))
+}
+
+// AES-256-GCM decryption of the archive's fields, whose lengths can't be trusted
+fn decrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    if key.len() != DATA_KEY_LEN || iv.len() != IV_LEN {
+        return Err("The archive's key or iv is broken".to_string());
+    }
+    aes256_gcm::decrypt(data, key, iv)
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
+    let mut key = vec![0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+fn ecdh_key(shared_secret: &[u8]) -> Result<Vec<u8>, String> {
+    let mut key = vec![0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(ECDH_KDF_INFO, &mut key)
+        .map_err(|e| format!("Derive the key-encryption key failed: {}", e))?;
+    Ok(key)
+}
+
+fn seal(plaintext: &[u8], protection: &Protection, rounds: u32) -> Result<Envelope, String> {
+    let data_key = random(DATA_KEY_LEN);
+    let iv = random(IV_LEN);
+    let data = aes256_gcm::encrypt(plaintext, &data_key, &iv)?;
+    let mut envelope = Envelope {
+        version: BACKUP_VERSION,
+        iv: base64::encode(&iv),
+        data: base64::encode(data),
+        ..Default::default()
+    };
+
+    match protection {
+        Protection::Passphrase(passphrase) => {
+            let salt = random(SALT_LEN);
+            let key_iv = random(IV_LEN);
+            let kek = passphrase_key(passphrase, &salt, rounds);
+            envelope.protection = PROTECTION_PASSPHRASE.to_string();
+            envelope.salt = base64::encode(&salt);
+            envelope.iterations = rounds;
+            envelope.wrapped_key = base64::encode(aes256_gcm::encrypt(&data_key, &kek, &key_iv)?);
+            envelope.key_iv = base64::encode(&key_iv);
+        }
+        Protection::PublicKey(pem) => {
+            if let Ok(public_key) = RsaPublicKey::from_public_key_pem(pem) {
+                let wrapped_key = public_key
+                    .encrypt(
+                        &mut rand::rngs::OsRng,
+                        PaddingScheme::new_oaep::<Sha256>(),
+                        &data_key,
+                    )
+                    .map_err(|e| format!("Wrap the data key failed: {}", e))?;
+                envelope.protection = PROTECTION_RSA_OAEP.to_string();
+                envelope.wrapped_key = base64::encode(wrapped_key);
+            } else {
+                let public_key = p256::PublicKey::from_public_key_pem(pem)
+                    .map_err(|_| "The public key is neither an RSA nor a P-256 key".to_string())?;
+                let ephemeral = p256::ecdh::EphemeralSecret::random(&mut rand::rngs::OsRng);
+                let shared = ephemeral.diffie_hellman(&public_key);
+                let kek = ecdh_key(shared.raw_secret_bytes())?;
+                let key_iv = random(IV_LEN);
+                envelope.protection = PROTECTION_ECDH.to_string();
+                envelope.wrapped_key =
+                    base64::encode(aes256_gcm::encrypt(&data_key, &kek, &key_iv)?);
+                envelope.ephemeral_key =
+                    base64::encode(p256::EncodedPoint::from(ephemeral.public_key()));
+                envelope.key_iv = base64::encode(&key_iv);
+            }
+        }
+    }
+    Ok(envelope)
+}
+
+fn open(envelope: &Envelope, unlock: &Unlock) -> Result<Vec<u8>, String> {
+    if envelope.version != BACKUP_VERSION {
+        return Err(format!(
+            "Unsupported archive version {}, expect {}",
+            envelope.version, BACKUP_VERSION
+        ));
+    }
+
+    let data_key = match (envelope.protection.as_str(), unlock) {
+        (PROTECTION_PASSPHRASE, Unlock::Passphrase(passphrase)) => {
+            if envelope.iterations == 0 {
+                return Err("The archive's iterations is broken".to_string());
+            }
+            let kek = passphrase_key(
+                passphrase,
+                &decode("salt", &envelope.salt)?,
+                envelope.iterations,
+            );
+            decrypt(
+                &decode("wrapped key", &envelope.wrapped_key)?,
+                &kek,
+                &decode("key iv", &envelope.key_iv)?,
+            )
+            .map_err(|_| "Wrong passphrase".to_string())?
+        }
+        (PROTECTION_PASSPHRASE, Unlock::DataKey(_)) => {
+            return Err("The archive is protected by a passphrase".to_string())
+        }
+        (PROTECTION_RSA_OAEP | PROTECTION_ECDH, Unlock::DataKey(data_key)) => data_key.clone(),
+        (PROTECTION_RSA_OAEP | PROTECTION_ECDH, Unlock::Passphrase(_)) => {
+            return Err(format!(
+                "The archive is protected by a public key ({}), unwrap its data key with the private key",
+                envelope.protection
+            ))
+        }
+        (protection, _) => return Err(format!("Unknown archive protection: {:?}", protection)),
+    };
+
+    decrypt(
+        &decode("data", &envelope.data)?,
+        &data_key,
+        &decode("iv", &envelope.iv)?,
+    )
+    .map_err(|_| "Decrypt the archive failed".to_string())
+}
+
+fn check_file_name(name: &str) -> Result<(), String> {
+    match name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        true => Err(format!("Invalid file name in the archive: {:?}", name)),
+        false => Ok(()),
+    }
+}
+
+fn read_directory(
+    directory: &str,
+    path: &str,
+    files: &mut BTreeMap<String, String>,
+) -> Result<(), String> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let entries = fs::read_dir(path).map_err(|e| format!("Read {} failed: {}", path, e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Read {} failed: {}", path, e))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || !entry.path().is_file() {
+            continue;
+        }
+        let content =
+            fs::read(entry.path()).map_err(|e| format!("Read {}{} failed: {}", path, name, e))?;
+        files.insert(format!("{}/{}", directory, name), base64::encode(content));
+    }
+    Ok(())
+}
+
+/// Back up the keys, the OPA policies and references, the GPG keyring and the image configurations
+/// into an encrypted archive. without_keys allows the backup of a key store which doesn't export its keys.
+pub fn backup(protection: &Protection, without_keys: bool) -> Result<Vec<u8>, String> {
+    let keys = key_store::export_keys(without_keys)?;
+
+    let mut files = BTreeMap::new();
+    {
+        let _opa = opa::FILE_LOCK.read();
+        let _gpg = gpg::FILE_LOCK.read();
+        let _image = image::FILE_LOCK.read();
+        for (directory, path) in DIRECTORIES {
+            read_directory(directory, path, &mut files)?;
+        }
+    }
+
+    let archive = Archive {
+        version: BACKUP_VERSION,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0),
+        keys,
+        files,
+    };
+    let plaintext = serde_json::to_vec(&archive).map_err(|e| e.to_string())?;
+    let envelope = seal(&plaintext, protection, PBKDF2_ROUNDS)?;
+    serde_json::to_vec(&envelope).map_err(|e| e.to_string())
+}
+
+fn staging_path(path: &str) -> String {
+    format!("{}.restore", path.trim_end_matches('/'))
+}
+
+fn previous_path(path: &str) -> String {
+    format!("{}.previous", path.trim_end_matches('/'))
+}
+
+// Write the archive's files next to the directories they replace
+fn stage(files: &BTreeMap<String, Vec<u8>>) -> Result<(), String> {
+    for (_, path) in DIRECTORIES {
+        // Left over by an interrupted restoration
+        for leftover in [staging_path(path), previous_path(path)] {
+            if Path::new(&leftover).exists() {
+                fs::remove_dir_all(&leftover)
+                    .map_err(|e| format!("Remove {} failed: {}", leftover, e))?;
+            }
+        }
+        let staging = staging_path(path);
+        fs::create_dir_all(&staging).map_err(|e| format!("Create {} failed: {}", staging, e))?;
+    }
+    for (name, content) in files {
+        let (directory, name) = name.split_once('/').unwrap_or_default();
+        let (_, path) = DIRECTORIES
+            .iter()
+            .find(|(known, _)| *known == directory)
+            .ok_or(format!("Unknown directory in the archive: {:?}", directory))?;
+        let file = format!("{}/{}", staging_path(path), name);
+        fs::write(&file, content).map_err(|e| format!("Write {} failed: {}", file, e))?;
+    }
+    Ok(())
+}
+
+fn unstage() {
+    for (_, path) in DIRECTORIES {
+        let _ = fs::remove_dir_all(staging_path(path));
+    }
+}
+
+// Replace the directories by the staged ones, all of them or none
+fn swap() -> Result<(), String> {
+    let mut swapped: Vec<&str> = vec![];
+    for (_, path) in DIRECTORIES {
+        let live = path.trim_end_matches('/');
+        let res = match Path::new(live).exists() {
+            true => fs::rename(live, previous_path(path)),
+            false => Ok(()),
+        }
+        .and_then(|_| fs::rename(staging_path(path), live));
+        if let Err(e) = res {
+            for path in swapped.into_iter().rev() {
+                let live = path.trim_end_matches('/');
+                let _ = fs::rename(live, staging_path(path));
+                let _ = fs::rename(previous_path(path), live);
+            }
+            return Err(format!("Replace {} failed: {}", live, e));
+        }
+        swapped.push(path);
+    }
+
+    for (_, path) in DIRECTORIES {
+        let _ = fs::remove_dir_all(previous_path(path));
+    }
+    Ok(())
+}
+
+/// Restore the state backed up in the archive: the directories are replaced by the archive's ones,
+/// the archived keys replace the keys with the same kids. Nothing is changed if any of it fails.
+pub fn restore(data: &[u8], unlock: &Unlock) -> Result<(), String> {
+    let envelope: Envelope =
+        serde_json::from_slice(data).map_err(|e| format!("Parse the archive failed: {}", e))?;
+    let plaintext = open(&envelope, unlock)?;
+    let archive: Archive = serde_json::from_slice(&plaintext)
+        .map_err(|e| format!("Parse the archive failed: {}", e))?;
+    if archive.version != BACKUP_VERSION {
+        return Err(format!(
+            "Unsupported archive version {}, expect {}",
+            archive.version, BACKUP_VERSION
+        ));
+    }
+
+    let mut files = BTreeMap::new();
+    for (name, content) in &archive.files {
+        let (_, file_name) = name
+            .split_once('/')
+            .ok_or(format!("Invalid file name in the archive: {:?}", name))?;
+        check_file_name(file_name)?;
+        files.insert(name.clone(), decode(name, content)?);
+    }
+
+    let _opa = opa::FILE_LOCK.write();
+    let _gpg = gpg::FILE_LOCK.write();
+    let _image = image::FILE_LOCK.write();
+    let res = stage(&files).and_then(|_| key_store::import_keys(&archive.keys, swap));
+    if res.is_err() {
+        unstage();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::pkcs8::{EncodePublicKey, LineEnding};
+
+    #[test]
+    fn test_passphrase() {
+        let passphrase = Protection::Passphrase("test passphrase".to_string());
+        let envelope = seal(b"test_archive", &passphrase, 1000).unwrap();
+        assert_eq!(envelope.protection, PROTECTION_PASSPHRASE);
+        assert_eq!(envelope.iterations, 1000);
+
+        let unlock = Unlock::Passphrase("test passphrase".to_string());
+        assert_eq!(open(&envelope, &unlock).unwrap(), b"test_archive".to_vec());
+        let unlock = Unlock::Passphrase("wrong passphrase".to_string());
+        assert!(open(&envelope, &unlock).is_err());
+        assert!(open(&envelope, &Unlock::DataKey(vec![0u8; 32])).is_err());
+    }
+
+    #[test]
+    fn test_ecdh() {
+        let secret = p256::SecretKey::random(&mut rand::rngs::OsRng);
+        let pem = secret
+            .public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let envelope = seal(b"test_archive", &Protection::PublicKey(pem), 1000).unwrap();
+        assert_eq!(envelope.protection, PROTECTION_ECDH);
+
+        // The holder of the private key unwraps the data key
+        let ephemeral =
+            p256::PublicKey::from_sec1_bytes(&base64::decode(&envelope.ephemeral_key).unwrap())
+                .unwrap();
+        let shared = p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), ephemeral.as_affine());
+        let kek = ecdh_key(shared.raw_secret_bytes()).unwrap();
+        let data_key = aes256_gcm::decrypt(
+            &base64::decode(&envelope.wrapped_key).unwrap(),
+            &kek,
+            &base64::decode(&envelope.key_iv).unwrap(),
+        )
+        .unwrap();
+
+        let unlock = Unlock::DataKey(data_key);
+        assert_eq!(open(&envelope, &unlock).unwrap(), b"test_archive".to_vec());
+        assert!(open(&envelope, &Unlock::DataKey(vec![0u8; 32])).is_err());
+        let unlock = Unlock::Passphrase("test passphrase".to_string());
+        assert!(open(&envelope, &unlock).is_err());
+    }
+
+    #[test]
+    fn test_check_file_name() {
+        assert!(check_file_name("sgxPolicy.rego").is_ok());
+        assert!(check_file_name("../keys").is_err());
+        assert!(check_file_name(".hidden").is_err());
+        assert!(check_file_name("").is_err());
+    }
+}
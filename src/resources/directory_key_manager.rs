@@ -1,28 +1,164 @@
+use crate::resources::key_store::KeyStore;
 use std::fs;
 use std::io;
+use std::io::prelude::*;
+use std::path::Path;
 
-const VERDICTD_KEY_PATH: &str = "/opt/verdictd/keys/";
+pub const VERDICTD_KEY_PATH: &str = "/opt/verdictd/keys/";
 
-pub fn get_key(kid: &String) -> Result<Vec<u8>, io::Error> {
-    let path = VERDICTD_KEY_PATH.to_string() + kid;
-    info!("get key from keyFile: {}", path);
+// Subdirectory of the key directory holding the keys' metadata, <kid>.json
+const METADATA_DIR: &str = ".meta/";
 
-    let data = fs::read(path);
-    match data {
-        Ok(key) => Ok(key),
-        Err(e) => {
-            error!("Get kid:{}'s key failed, err: {}", kid, e.to_string());
-            Err(e)
+// Subdirectory of the key directory holding the tenants' subdirectories, apart from
+// the default namespace's keys so a tenant and a kid can share a name
+const TENANTS_DIR: &str = ".tenants/";
+
+/// Keys stored as raw bytes in a file named after the kid, the keys of
+/// a tenant ("<tenant>/<kid>") in the tenant's subdirectory of .tenants/
+pub struct DirectoryKeyStore {
+    path: String,
+}
+
+impl DirectoryKeyStore {
+    pub fn new(path: &str) -> DirectoryKeyStore {
+        let mut path = path.to_string();
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        DirectoryKeyStore { path }
+    }
+
+    // The path of the kid's key, which must stay inside the key directory
+    fn key_path(&self, kid: &str) -> io::Result<String> {
+        let components: Vec<&str> = kid.split('/').collect();
+        if components.len() > 2
+            || components
+                .iter()
+                .any(|name| name.is_empty() || name.starts_with('.') || name.contains('\\'))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid kid: {:?}", kid),
+            ));
         }
+        match components.len() {
+            1 => Ok(self.path.clone() + kid),
+            _ => Ok(self.path.clone() + TENANTS_DIR + kid),
+        }
+    }
+
+    fn metadata_path(&self, kid: &str) -> io::Result<String> {
+        self.key_path(kid)?;
+        Ok(self.path.clone() + METADATA_DIR + kid + ".json")
+    }
+}
+
+// Create the directories of the file, the tenant's subdirectory
+fn create_parent(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
     }
 }
 
-pub fn set_key(kid: &String, key: &[u8]) -> std::io::Result<()> {
-    let path = VERDICTD_KEY_PATH.to_string() + kid;
-    info!("set key for keyFile: {}", path);
+fn file_names(path: &Path) -> io::Result<Vec<(String, bool)>> {
+    Ok(fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let is_dir = entry.path().is_dir();
+            entry
+                .file_name()
+                .into_string()
+                .ok()
+                .map(|name| (name, is_dir))
+        })
+        .collect())
+}
 
-    fs::write(path, key).expect("Unable to write file");
-    Ok(())
+// Overwrite the file with zeros and flush it to the disk before removing it,
+// so the key material doesn't stay in the freed blocks of the file system
+fn shred(path: &str) -> io::Result<()> {
+    let len = fs::metadata(path)?.len() as usize;
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0u8; len])?;
+    file.sync_all()?;
+    fs::remove_file(path)
+}
+
+impl KeyStore for DirectoryKeyStore {
+    fn get_key(&self, kid: &str) -> io::Result<Vec<u8>> {
+        let path = self.key_path(kid)?;
+        info!("get key from keyFile: {}", path);
+
+        let data = fs::read(path);
+        match data {
+            Ok(key) => Ok(key),
+            Err(e) => {
+                error!("Get kid:{}'s key failed, err: {}", kid, e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    fn set_key(&self, kid: &str, key: &[u8]) -> io::Result<()> {
+        let path = self.key_path(kid)?;
+        info!("set key for keyFile: {}", path);
+
+        create_parent(&path)?;
+        fs::write(path, key)
+    }
+
+    fn list_kids(&self) -> io::Result<Vec<String>> {
+        let mut kids: Vec<String> = file_names(Path::new(&self.path))?
+            .into_iter()
+            .filter(|(kid, is_dir)| !is_dir && !kid.starts_with('.'))
+            .map(|(kid, _)| kid)
+            .collect();
+
+        // The keys of the tenants
+        let tenants = Path::new(&self.path).join(TENANTS_DIR);
+        if tenants.exists() {
+            for (tenant, is_dir) in file_names(&tenants)? {
+                if !is_dir || tenant.starts_with('.') {
+                    continue;
+                }
+                kids.extend(
+                    file_names(&tenants.join(&tenant))?
+                        .into_iter()
+                        .filter(|(kid, is_dir)| !is_dir && !kid.starts_with('.'))
+                        .map(|(kid, _)| format!("{}/{}", tenant, kid)),
+                );
+            }
+        }
+        kids.sort();
+        Ok(kids)
+    }
+
+    fn delete_key(&self, kid: &str) -> io::Result<()> {
+        let path = self.key_path(kid)?;
+        info!("delete keyFile: {}", path);
+
+        shred(&path)?;
+        let metadata = self.metadata_path(kid)?;
+        match Path::new(&metadata).exists() {
+            true => fs::remove_file(metadata),
+            false => Ok(()),
+        }
+    }
+
+    fn get_metadata(&self, kid: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.metadata_path(kid)?) {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set_metadata(&self, kid: &str, metadata: &str) -> io::Result<()> {
+        let path = self.metadata_path(kid)?;
+        create_parent(&path)?;
+        fs::write(path, metadata)
+    }
 }
 
 #[cfg(test)]
@@ -32,19 +168,57 @@ mod tests {
 
     #[test]
     fn test_set_get_key() {
+        let store = DirectoryKeyStore::new(VERDICTD_KEY_PATH);
         let kid = String::from("test_key");
         let key_content = b"test_key_content".to_vec();
         let path = VERDICTD_KEY_PATH.to_string() + &kid;
         fs::create_dir_all(&VERDICTD_KEY_PATH).expect("Unable to create directory");
 
-        let set_res = set_key(&kid, &key_content);
+        let set_res = store.set_key(&kid, &key_content);
         assert!(set_res.is_ok());
 
-        let key = get_key(&kid);
+        let key = store.get_key(&kid);
         assert_eq!(key.unwrap(), key_content);
 
+        assert!(store.set_metadata(&kid, "{}").is_ok());
+        assert_eq!(store.get_metadata(&kid).unwrap(), Some("{}".to_string()));
+
         // Cleanup
-        let res = fs::remove_file(&path);
+        let res = store.delete_key(&kid);
         assert!(res.is_ok());
+        assert!(!Path::new(&path).exists());
+        assert_eq!(store.get_metadata(&kid).unwrap(), None);
+    }
+
+    #[test]
+    fn test_key_path() {
+        let store = DirectoryKeyStore::new(VERDICTD_KEY_PATH);
+        assert!(store.key_path("kid").is_ok());
+        assert!(store.key_path("tenant/kid").is_ok());
+        assert!(store.key_path("../gpg/keyring.gpg").is_err());
+        assert!(store.key_path("tenant/../../kid").is_err());
+        assert!(store.key_path(".meta/kid").is_err());
+        assert!(store.key_path("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_tenant_named_like_kid() {
+        let path = "/tmp/verdictd-test-tenant-keys/";
+        let _ = fs::remove_dir_all(path);
+        let store = DirectoryKeyStore::new(path);
+
+        // The default namespace's kid foo and the tenant foo don't collide, whichever comes first
+        assert!(store.set_key("foo", b"default").is_ok());
+        assert!(store.set_key("foo/bar", b"tenant").is_ok());
+        assert!(store.set_key("bar/foo", b"other tenant").is_ok());
+        assert!(store.set_key("bar", b"default too").is_ok());
+        assert_eq!(store.get_key("foo").unwrap(), b"default");
+        assert_eq!(store.get_key("foo/bar").unwrap(), b"tenant");
+        assert_eq!(
+            store.list_kids().unwrap(),
+            vec!["bar", "bar/foo", "foo", "foo/bar"]
+        );
+
+        fs::remove_dir_all(path).unwrap();
     }
 }
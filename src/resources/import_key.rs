@@ -0,0 +1,99 @@
+use crate::crypto::aes256_gcm;
+use hkdf::Hkdf;
+use lazy_static::lazy_static;
+use p256::ecdh;
+use rsa::pkcs8::{EncodePublicKey, LineEnding};
+use rsa::{PaddingScheme, RsaPrivateKey};
+use sha2::Sha256;
+
+/// The key material is encrypted with verdictd's RSA import key, OAEP padded with SHA-256
+pub const WRAP_RSA_OAEP: &str = "RSA-OAEP";
+/// The key material is encrypted with AES-256-GCM, by the key derived with HKDF-SHA256
+/// from the ECDH of an ephemeral P-256 key and verdictd's EC import key
+pub const WRAP_ECDH: &str = "ECDH-ES";
+/// HKDF info of the key derived from the ECDH shared secret
+pub const ECDH_KDF_INFO: &[u8] = b"verdictd key import";
+
+const RSA_BITS: usize = 3072;
+
+lazy_static! {
+    // The import keys are generated at their first use and never stored,
+    // a restarted verdictd publishes new ones
+    static ref RSA_KEY: RsaPrivateKey = RsaPrivateKey::new(&mut rand::rngs::OsRng, RSA_BITS)
+        .expect("Generate the RSA import key failed");
+    static ref EC_KEY: p256::SecretKey = p256::SecretKey::random(&mut rand::rngs::OsRng);
+}
+
+/// The PEM encoded public key the key material is wrapped with
+pub fn public_key(wrap_algorithm: &str) -> Result<String, String> {
+    match wrap_algorithm {
+        WRAP_RSA_OAEP => RSA_KEY.to_public_key().to_public_key_pem(LineEnding::LF),
+        WRAP_ECDH => EC_KEY.public_key().to_public_key_pem(LineEnding::LF),
+        _ => return Err(unknown(wrap_algorithm)),
+    }
+    .map_err(|e| format!("Encode the import key failed: {}", e))
+}
+
+/// Decrypt the key material, ephemeral_key (SEC1 encoded) and iv are only used by ECDH-ES
+pub fn unwrap(
+    wrap_algorithm: &str,
+    wrapped_key: &[u8],
+    ephemeral_key: &[u8],
+    iv: &[u8],
+) -> Result<Vec<u8>, String> {
+    match wrap_algorithm {
+        WRAP_RSA_OAEP => RSA_KEY
+            .decrypt(PaddingScheme::new_oaep::<Sha256>(), wrapped_key)
+            .map_err(|e| format!("Unwrap the key failed: {}", e)),
+        WRAP_ECDH => {
+            let ephemeral_key = p256::PublicKey::from_sec1_bytes(ephemeral_key)
+                .map_err(|e| format!("Parse the ephemeral key failed: {}", e))?;
+            let shared =
+                ecdh::diffie_hellman(EC_KEY.to_nonzero_scalar(), ephemeral_key.as_affine());
+            let kek = derive_kek(shared.raw_secret_bytes())?;
+            if iv.len() != 12 {
+                return Err("The iv of ECDH-ES must be 12 bytes".to_string());
+            }
+            aes256_gcm::decrypt(wrapped_key, &kek, iv)
+        }
+        _ => Err(unknown(wrap_algorithm)),
+    }
+}
+
+/// The key-encryption key of ECDH-ES, derived from the ECDH shared secret
+pub fn derive_kek(shared_secret: &[u8]) -> Result<[u8; 32], String> {
+    let mut kek = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(ECDH_KDF_INFO, &mut kek)
+        .map_err(|e| format!("Derive the key-encryption key failed: {}", e))?;
+    Ok(kek)
+}
+
+fn unknown(wrap_algorithm: &str) -> String {
+    format!(
+        "Unknown wrap algorithm: {:?}, expect {} or {}",
+        wrap_algorithm, WRAP_RSA_OAEP, WRAP_ECDH
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unwrap_ecdh() {
+        let key = [7u8; 32];
+        let iv = [1u8; 12];
+        let ephemeral = ecdh::EphemeralSecret::random(&mut rand::rngs::OsRng);
+        let shared = ephemeral.diffie_hellman(&EC_KEY.public_key());
+        let kek = derive_kek(shared.raw_secret_bytes()).unwrap();
+        let wrapped = aes256_gcm::encrypt(&key, &kek, &iv).unwrap();
+
+        let ephemeral_key = p256::EncodedPoint::from(ephemeral.public_key());
+        let unwrapped = unwrap(WRAP_ECDH, &wrapped, ephemeral_key.as_bytes(), &iv).unwrap();
+        assert_eq!(unwrapped, key);
+
+        assert!(unwrap(WRAP_ECDH, &wrapped, ephemeral_key.as_bytes(), &[0u8; 12]).is_err());
+        assert!(unwrap("AES-KW", &wrapped, &[], &[]).is_err());
+    }
+}
@@ -0,0 +1,1212 @@
+use crate::crypto::cipher::Cipher;
+use crate::crypto::key_type::KeyType;
+use crate::resources::audit;
+use crate::resources::directory_key_manager::{DirectoryKeyStore, VERDICTD_KEY_PATH};
+use crate::resources::master_key;
+use crate::resources::pkcs11_key_manager::{Pkcs11KeyStore, VERDICTD_PKCS11_MODULE};
+use crate::resources::sqlite_key_manager::{SqliteKeyStore, VERDICTD_KEY_DB};
+use hkdf::Hkdf;
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const BACKEND_DIRECTORY: &str = "directory";
+pub const BACKEND_SQLITE: &str = "sqlite";
+pub const BACKEND_PKCS11: &str = "pkcs11";
+
+const IV_LEN: usize = 12;
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the key store doesn't encrypt with its keys",
+    )
+}
+
+/// Storage of the keys identified by their kid
+pub trait KeyStore: Send + Sync {
+    // Whether get_key hands out the key material, else the backend encrypts and decrypts itself
+    fn exportable(&self) -> bool {
+        true
+    }
+    fn contains_key(&self, kid: &str) -> bool {
+        self.get_key(kid).is_ok()
+    }
+    fn get_key(&self, kid: &str) -> io::Result<Vec<u8>>;
+    fn set_key(&self, kid: &str, key: &[u8]) -> io::Result<()>;
+    // Generate the key inside the backend, for the backends whose keys aren't exportable
+    fn generate_key(&self, _kid: &str, _key_type: KeyType) -> io::Result<()> {
+        Err(unsupported())
+    }
+    // AES-GCM with the key inside the backend, for the backends whose keys aren't exportable
+    fn encrypt(&self, _kid: &str, _data: &[u8], _iv: &[u8], _aad: &[u8]) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+    fn decrypt(&self, _kid: &str, _data: &[u8], _iv: &[u8], _aad: &[u8]) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+    fn list_kids(&self) -> io::Result<Vec<String>>;
+    // Remove the key and its metadata, overwriting the stored key material
+    fn delete_key(&self, kid: &str) -> io::Result<()>;
+    // The key's metadata serialized as json, None for keys stored without metadata
+    fn get_metadata(&self, kid: &str) -> io::Result<Option<String>>;
+    fn set_metadata(&self, kid: &str, metadata: &str) -> io::Result<()>;
+}
+
+lazy_static! {
+    // The key store selected by configuration, the key directory by default
+    static ref KEY_STORE: RwLock<Box<dyn KeyStore>> =
+        RwLock::new(Box::new(DirectoryKeyStore::new(VERDICTD_KEY_PATH)));
+    // The key-encryption key every stored key is wrapped with, keys are stored in plaintext without it
+    static ref MASTER_KEY: RwLock<Option<Vec<u8>>> = RwLock::new(None);
+    // Number of the operations in progress using each kid
+    static ref PENDING_OPERATIONS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+#[cfg(test)]
+lazy_static! {
+    // Held by the tests using KEY_STORE, so a test pointing it to a store of its own
+    // doesn't change the store the others run with
+    pub static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// Every key stored before the metadata existed is an AES-256 key
+fn default_algorithm() -> String {
+    "AES".to_string()
+}
+
+fn default_length() -> u32 {
+    256
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+/// Separates the tenant from the kid within the tenant, "<tenant>/<kid>"
+pub const TENANT_SEPARATOR: char = '/';
+const NAME_MAX_LEN: usize = 128;
+
+// Tenants and kids within a tenant start with a letter or a digit, followed by letters,
+// digits, '.', '_' or '-'
+fn check_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = name.len() <= NAME_MAX_LEN
+        && chars.next().map_or(false, |c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+    match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid name: {:?}", name)),
+    }
+}
+
+/// A kid is either "<kid>" in the default namespace, or "<tenant>/<kid>"
+pub fn validate_kid(kid: &str) -> Result<(), String> {
+    match kid.split_once(TENANT_SEPARATOR) {
+        Some((tenant, name)) => check_name(tenant).and_then(|_| check_name(name)),
+        None => check_name(kid),
+    }
+    .map_err(|_| format!("Invalid kid: {:?}", kid))
+}
+
+/// The tenant granted every namespace, only by explicit configuration
+pub const GLOBAL_TENANT: &str = "*";
+
+/// The namespace the kids of a request are resolved in: the default one of the kids
+/// without tenant, a tenant's, or every namespace from the global scope
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scope {
+    tenant: Option<String>,
+    global: bool,
+}
+
+impl Scope {
+    pub fn global() -> Scope {
+        Scope {
+            tenant: None,
+            global: true,
+        }
+    }
+
+    pub fn tenant(tenant: &str) -> Result<Scope, String> {
+        check_name(tenant).map_err(|_| format!("Invalid tenant: {:?}", tenant))?;
+        Ok(Scope {
+            tenant: Some(tenant.to_string()),
+            global: false,
+        })
+    }
+
+    /// The scope of a granted tenant, the global one for GLOBAL_TENANT
+    pub fn granted(tenant: &str) -> Result<Scope, String> {
+        match tenant {
+            GLOBAL_TENANT => Ok(Scope::global()),
+            _ => Scope::tenant(tenant),
+        }
+    }
+
+    /// The full kid of a kid requested within the scope, a tenant's kid can't be
+    /// qualified with another tenant
+    pub fn resolve(&self, kid: &str) -> Result<String, String> {
+        validate_kid(kid)?;
+        match (&self.tenant, kid.split_once(TENANT_SEPARATOR)) {
+            (None, _) if self.global => Ok(kid.to_string()),
+            (None, None) => Ok(kid.to_string()),
+            (None, Some(_)) => Err(format!("kid {} is out of the default namespace", kid)),
+            (Some(tenant), None) => Ok(format!("{}{}{}", tenant, TENANT_SEPARATOR, kid)),
+            (Some(tenant), Some((kid_tenant, _))) if kid_tenant == tenant => Ok(kid.to_string()),
+            (Some(tenant), Some(_)) => Err(format!("kid {} is out of tenant {}", kid, tenant)),
+        }
+    }
+
+    pub fn contains(&self, kid: &str) -> bool {
+        match &self.tenant {
+            None => self.global || !kid.contains(TENANT_SEPARATOR),
+            Some(tenant) => kid
+                .split_once(TENANT_SEPARATOR)
+                .map_or(false, |(kid_tenant, _)| kid_tenant == tenant),
+        }
+    }
+}
+
+/// The operations a key can be used for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Usage {
+    // Handed out to the attested TEE by Get KEK
+    Export,
+    // Decrypting the attested TEE's data in verdictd
+    Decrypt,
+    // Wrapping the image layer keys by the key provider
+    Wrap,
+    // Unwrapping the image layer keys by the key provider
+    Unwrap,
+    Sign,
+    // Deriving the keys bound to the attested TEE's identity by Derive Key
+    Derive,
+}
+
+impl Usage {
+    pub const ALL: [Usage; 6] = [
+        Usage::Export,
+        Usage::Decrypt,
+        Usage::Wrap,
+        Usage::Unwrap,
+        Usage::Sign,
+        Usage::Derive,
+    ];
+
+    pub fn parse(usage: &str) -> Result<Usage, String> {
+        Usage::ALL
+            .iter()
+            .find(|known| known.as_str() == usage)
+            .copied()
+            .ok_or(format!(
+                "Unknown usage: {:?}, expect export, decrypt, wrap, unwrap, sign or derive",
+                usage
+            ))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Usage::Export => "export",
+            Usage::Decrypt => "decrypt",
+            Usage::Wrap => "wrap",
+            Usage::Unwrap => "unwrap",
+            Usage::Sign => "sign",
+            Usage::Derive => "derive",
+        }
+    }
+}
+
+// Keys stored before the usages existed can be used for everything but deriving,
+// see check_usages
+fn default_usages() -> BTreeSet<Usage> {
+    Usage::ALL
+        .iter()
+        .copied()
+        .filter(|usage| *usage != Usage::Derive)
+        .collect()
+}
+
+// A key needs a usage, and a key the TEEs' keys are derived from is never exported:
+// whoever holds it would compute the key of every other TEE
+fn check_usages(usages: &BTreeSet<Usage>) -> Result<(), String> {
+    if usages.is_empty() {
+        return Err("A key needs at least one usage".to_string());
+    }
+    if usages.contains(&Usage::Derive) && usages.contains(&Usage::Export) {
+        return Err("A key can't be used for both derive and export".to_string());
+    }
+    Ok(())
+}
+
+// Versions after the first one are stored as "<kid>#<version>", the first one as the kid itself
+const VERSION_SEPARATOR: char = '#';
+
+/// Metadata kept alongside a key, keys stored before it existed have the default one
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeyMetadata {
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+    // Length of the key in bits
+    #[serde(default = "default_length")]
+    pub length: u32,
+    // The latest version, the one new data is encrypted with
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub created: u64,
+    #[serde(default)]
+    pub last_used: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default = "default_usages")]
+    pub usages: BTreeSet<Usage>,
+    // Soft deletion time, the key can't be used anymore but can still be restored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<u64>,
+    // Time the soft deleted key is purged at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_after: Option<u64>,
+}
+
+impl Default for KeyMetadata {
+    fn default() -> Self {
+        KeyMetadata {
+            algorithm: default_algorithm(),
+            length: default_length(),
+            version: default_version(),
+            created: 0,
+            last_used: 0,
+            labels: BTreeMap::new(),
+            usages: default_usages(),
+            deleted: None,
+            purge_after: None,
+        }
+    }
+}
+
+impl KeyMetadata {
+    pub const STATE_ACTIVE: &'static str = "active";
+    pub const STATE_DELETED: &'static str = "deleted";
+
+    /// The metadata of a new key of the type, which can be used for everything
+    pub fn new(key_type: KeyType) -> KeyMetadata {
+        KeyMetadata {
+            algorithm: key_type.algorithm().to_string(),
+            length: key_type.length(),
+            created: now(),
+            ..Default::default()
+        }
+    }
+
+    pub fn state(&self) -> &'static str {
+        match self.deleted {
+            Some(_) => Self::STATE_DELETED,
+            None => Self::STATE_ACTIVE,
+        }
+    }
+
+    pub fn key_type(&self) -> Result<KeyType, String> {
+        KeyType::parse(&self.algorithm, self.length)
+    }
+
+    // Whether the key carries all the labels
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        labels
+            .iter()
+            .all(|(name, value)| self.labels.get(name) == Some(value))
+    }
+}
+
+// last_used is persisted at most once per interval, so reading a key rarely writes
+const LAST_USED_INTERVAL: u64 = 60;
+
+/// Registration of an operation using a kid, the kid can't be deleted until it's dropped
+pub struct PendingOperation {
+    kid: String,
+}
+
+impl Drop for PendingOperation {
+    fn drop(&mut self) {
+        let mut pending = PENDING_OPERATIONS.lock();
+        if let Some(count) = pending.get_mut(&self.kid) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.kid);
+            }
+        }
+    }
+}
+
+pub fn begin_operation(kid: &str) -> PendingOperation {
+    *PENDING_OPERATIONS
+        .lock()
+        .entry(kid.to_string())
+        .or_insert(0) += 1;
+    PendingOperation {
+        kid: kid.to_string(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+fn io_error(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn not_found(kid: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("kid {} not found", kid))
+}
+
+fn storage_id(kid: &str, version: u32) -> String {
+    match version {
+        0 | 1 => kid.to_string(),
+        _ => format!("{}{}{}", kid, VERSION_SEPARATOR, version),
+    }
+}
+
+// The kids of the store, without the ids of their later versions
+fn list_kids() -> io::Result<Vec<String>> {
+    Ok(KEY_STORE
+        .read()
+        .list_kids()?
+        .into_iter()
+        .filter(|id| !id.contains(VERSION_SEPARATOR))
+        .collect())
+}
+
+/// The token and user PIN the PKCS#11 key store logs in
+pub struct Pkcs11Login {
+    pub token: String,
+    pub pin: String,
+}
+
+/// Select the backend the keys are stored in, path is the backend's default location if it's None,
+/// the PKCS#11 module for pkcs11 which also needs the token login
+pub fn init(backend: &str, path: Option<&str>, login: Option<Pkcs11Login>) -> Result<(), String> {
+    let store: Box<dyn KeyStore> = match backend {
+        BACKEND_DIRECTORY => Box::new(DirectoryKeyStore::new(path.unwrap_or(VERDICTD_KEY_PATH))),
+        BACKEND_SQLITE => Box::new(SqliteKeyStore::open(path.unwrap_or(VERDICTD_KEY_DB))?),
+        BACKEND_PKCS11 => {
+            let login = login.ok_or("The pkcs11 key store needs the token's PIN".to_string())?;
+            Box::new(Pkcs11KeyStore::open(
+                path.unwrap_or(VERDICTD_PKCS11_MODULE),
+                &login.token,
+                &login.pin,
+            )?)
+        }
+        _ => {
+            return Err(format!(
+                "Unknown key store: {:?}, expect {}, {} or {}",
+                backend, BACKEND_DIRECTORY, BACKEND_SQLITE, BACKEND_PKCS11
+            ))
+        }
+    };
+
+    info!("key store: {}", backend);
+    *KEY_STORE.write() = store;
+    Ok(())
+}
+
+pub fn set_master_key(key: Vec<u8>) {
+    *MASTER_KEY.write() = Some(key);
+}
+
+/// The key's metadata, the default one for the keys stored before it existed.
+/// A kid without a key has no metadata, even if some was left behind.
+pub fn get_metadata(kid: &str) -> io::Result<KeyMetadata> {
+    validate_kid(kid).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let store = KEY_STORE.read();
+    if !store.contains_key(kid) {
+        return Err(not_found(kid));
+    }
+    match store.get_metadata(kid)? {
+        Some(metadata) => serde_json::from_str(&metadata)
+            .map_err(|e| io_error(format!("kid {}'s metadata is broken: {}", kid, e))),
+        None => Ok(KeyMetadata::default()),
+    }
+}
+
+fn set_metadata(kid: &str, metadata: &KeyMetadata) -> io::Result<()> {
+    let metadata = serde_json::to_string(metadata).map_err(|e| io_error(e.to_string()))?;
+    KEY_STORE.read().set_metadata(kid, &metadata)
+}
+
+// Check the version, the latest one if version is None, can be used and record the use
+fn use_key(kid: &str, version: Option<u32>) -> io::Result<u32> {
+    let mut metadata = get_metadata(kid)?;
+    if metadata.deleted.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("kid {}'s key is deleted", kid),
+        ));
+    }
+    let version = version.unwrap_or(metadata.version);
+    if version == 0 || version > metadata.version {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("kid {} has no version {}", kid, version),
+        ));
+    }
+
+    let now = now();
+    if now >= metadata.last_used + LAST_USED_INTERVAL {
+        metadata.last_used = now;
+        if let Err(e) = set_metadata(kid, &metadata) {
+            warn!("update kid {}'s last used time failed: {}", kid, e);
+        }
+    }
+    Ok(version)
+}
+
+// The key material of the version, the latest one if version is None
+fn read_key(kid: &str, version: Option<u32>) -> io::Result<(u32, Vec<u8>)> {
+    if !KEY_STORE.read().exportable() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("kid {}'s key can't be exported from the key store", kid),
+        ));
+    }
+    let version = use_key(kid, version)?;
+    let id = storage_id(kid, version);
+    let data = KEY_STORE.read().get_key(&id)?;
+    Ok((version, unwrap_stored(&id, data)?))
+}
+
+// The key material of the data stored under id, unwrapped if it's wrapped by the master key
+fn unwrap_stored(id: &str, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let key = match (master_key::is_wrapped(&data), MASTER_KEY.read().as_ref()) {
+        (true, Some(master)) => master_key::unwrap(master, id, &data).map_err(io_error)?,
+        (true, None) => {
+            return Err(io_error(format!(
+                "{}'s key is wrapped, but no master key is loaded",
+                id
+            )))
+        }
+        (false, Some(_)) => {
+            warn!("{}'s key is stored in plaintext, migrate the keys", id);
+            data
+        }
+        (false, None) => data,
+    };
+    Ok(key)
+}
+
+/// The latest version of the key
+pub fn get_key(kid: &str) -> io::Result<Vec<u8>> {
+    read_key(kid, None).map(|(_, key)| key)
+}
+
+/// The latest version of the key and its version number, to record along the encrypted data
+pub fn get_latest_key(kid: &str) -> io::Result<(u32, Vec<u8>)> {
+    read_key(kid, None)
+}
+
+// Store the key material, wrapped if a master key is set and the backend hands it out
+fn store_key(id: &str, key: &[u8]) -> io::Result<()> {
+    match MASTER_KEY.read().as_ref() {
+        Some(master) if KEY_STORE.read().exportable() => {
+            let wrapped = master_key::wrap(master, id, key).map_err(io_error)?;
+            KEY_STORE.read().set_key(id, &wrapped)
+        }
+        _ => KEY_STORE.read().set_key(id, key),
+    }
+}
+
+// The cipher the key encrypts with, the key type's one if none is specified.
+// The keys the backend doesn't export only encrypt with AES-GCM inside it.
+fn check_cipher(kid: &str, key_type: KeyType, cipher: Option<Cipher>) -> Result<(), String> {
+    let cipher = match cipher {
+        Some(cipher) => cipher,
+        None => return Ok(()),
+    };
+    if cipher.key_type() != key_type {
+        return Err(format!(
+            "kid {}'s key can't encrypt with {}",
+            kid,
+            cipher.as_str()
+        ));
+    }
+    match KEY_STORE.read().exportable() || key_type.cipher() == Some(cipher) {
+        true => Ok(()),
+        false => Err(format!(
+            "kid {}'s key only encrypts with {} inside the key store",
+            kid,
+            key_type
+                .cipher()
+                .map(|cipher| cipher.as_str())
+                .unwrap_or("")
+        )),
+    }
+}
+
+/// Encrypt the data with the latest version of the key and the cipher, the key type's one
+/// if None, return the version, the encrypted data and the iv. The aad is authenticated along the data.
+/// The keys the backend doesn't export encrypt with AES-GCM inside it.
+pub fn encrypt(
+    kid: &str,
+    cipher: Option<Cipher>,
+    data: &[u8],
+    aad: &[u8],
+) -> Result<(u32, Vec<u8>, Vec<u8>), String> {
+    let key_type = get_key_type(kid)?;
+    check_cipher(kid, key_type, cipher)?;
+    if KEY_STORE.read().exportable() {
+        let (version, key) = read_key(kid, None).map_err(|e| e.to_string())?;
+        let (encrypted_data, iv) = match cipher {
+            Some(cipher) => cipher.encrypt(&key, data, aad)?,
+            None => key_type.encrypt(&key, data, aad)?,
+        };
+        return Ok((version, encrypted_data, iv));
+    }
+
+    let version = use_key(kid, None).map_err(|e| e.to_string())?;
+    let mut iv = vec![0; IV_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut iv);
+    let encrypted_data = KEY_STORE
+        .read()
+        .encrypt(&storage_id(kid, version), data, &iv, aad)
+        .map_err(|e| format!("Encrypt data failed: {}", e))?;
+    Ok((version, encrypted_data, iv))
+}
+
+/// Decrypt the data encrypted by the given version of the key with the cipher,
+/// the key type's one if None, and the aad it was encrypted with
+pub fn decrypt(
+    kid: &str,
+    version: u32,
+    cipher: Option<Cipher>,
+    data: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, String> {
+    let key_type = get_key_type(kid)?;
+    check_cipher(kid, key_type, cipher)?;
+    if KEY_STORE.read().exportable() {
+        let (_, key) = read_key(kid, Some(version)).map_err(|e| e.to_string())?;
+        return match cipher {
+            Some(cipher) => cipher.decrypt(&key, data, iv, aad),
+            None => key_type.decrypt(&key, data, iv, aad),
+        };
+    }
+
+    let version = use_key(kid, Some(version)).map_err(|e| e.to_string())?;
+    KEY_STORE
+        .read()
+        .decrypt(&storage_id(kid, version), data, iv, aad)
+        .map_err(|e| format!("Decrypt data failed: {}", e))
+}
+
+/// Derive a key of length bytes from the version of the key, the latest one if version is None,
+/// with HKDF-SHA256. The keys the backend doesn't export can't be derived from.
+pub fn derive_key(
+    kid: &str,
+    version: Option<u32>,
+    info: &[u8],
+    length: usize,
+) -> Result<(u32, Vec<u8>), String> {
+    if !get_key_type(kid)?.is_symmetric() {
+        return Err(format!("kid {}'s key isn't a symmetric key", kid));
+    }
+    let (version, root) = read_key(kid, version).map_err(|e| e.to_string())?;
+
+    let mut key = vec![0u8; length];
+    Hkdf::<Sha256>::new(None, &root)
+        .expand(info, &mut key)
+        .map_err(|e| format!("Derive key failed: {}", e))?;
+    Ok((version, key))
+}
+
+// Whether the backend can hold the type of key
+fn check_key_type(key_type: KeyType) -> io::Result<()> {
+    match KEY_STORE.read().exportable() || matches!(key_type, KeyType::Aes128 | KeyType::Aes256) {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the key store only holds AES-128 and AES-256 keys",
+        )),
+    }
+}
+
+// Generate a new random key, inside the backend if it doesn't export its keys
+fn generate(id: &str, key_type: KeyType) -> io::Result<()> {
+    let exportable = KEY_STORE.read().exportable();
+    match exportable {
+        true => store_key(id, &key_type.generate().map_err(io_error)?),
+        false => KEY_STORE.read().generate_key(id, key_type),
+    }
+}
+
+fn check_exists(kid: &str) -> Result<(), String> {
+    match KEY_STORE.read().contains_key(kid) {
+        true => Ok(()),
+        false => Err(format!("kid {} not found", kid)),
+    }
+}
+
+/// The type of the key, recorded in its metadata
+pub fn get_key_type(kid: &str) -> Result<KeyType, String> {
+    get_metadata(kid)
+        .map_err(|e| e.to_string())?
+        .key_type()
+        .map_err(|e| format!("kid {}'s key: {}", kid, e))
+}
+
+/// Store the key material with the metadata of a new key
+pub fn set_key(kid: &str, key: &[u8], metadata: &KeyMetadata) -> io::Result<()> {
+    create_key(kid, metadata, |_| store_key(kid, key))
+}
+
+/// Generate a new random key under kid, of the type of the metadata of a new key
+pub fn generate_key(kid: &str, metadata: &KeyMetadata) -> io::Result<()> {
+    create_key(kid, metadata, |key_type| generate(kid, key_type))
+}
+
+// The whole metadata of a new key is written before its material, overwriting any left
+// without a key, so the key never exists with other metadata. The metadata of a replaced key is kept.
+fn create_key<F>(kid: &str, metadata: &KeyMetadata, store: F) -> io::Result<()>
+where
+    F: FnOnce(KeyType) -> io::Result<()>,
+{
+    validate_kid(kid).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let key_type = metadata
+        .key_type()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    check_key_type(key_type)?;
+    check_usages(&metadata.usages).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let replaced = {
+        let store = KEY_STORE.read();
+        store.contains_key(kid) && store.get_metadata(kid)?.is_some()
+    };
+    if !replaced {
+        set_metadata(kid, metadata)?;
+    }
+    store(key_type)
+}
+
+/// Store a new key, refuse the kids which are already used
+pub fn add_key(kid: &str, key: &[u8], metadata: &KeyMetadata) -> Result<(), String> {
+    validate_kid(kid)?;
+
+    // Serialized with the deletions, so a kid being deleted isn't reused
+    let _pending = PENDING_OPERATIONS.lock();
+    if KEY_STORE.read().contains_key(kid) {
+        return Err(format!("kid {} already exists", kid));
+    }
+    set_key(kid, key, metadata).map_err(|e| e.to_string())
+}
+
+/// Add a new random version of the key, return its version number. The previous
+/// versions are kept to decrypt the data encrypted with them.
+pub fn rotate_key(kid: &str) -> Result<u32, String> {
+    // Serialized with the deletions and the other rotations
+    let _pending = PENDING_OPERATIONS.lock();
+
+    let mut metadata = get_metadata(kid).map_err(|e| e.to_string())?;
+    check_exists(kid)?;
+    if metadata.deleted.is_some() {
+        return Err(format!("kid {} is deleted", kid));
+    }
+
+    let key_type = metadata
+        .key_type()
+        .map_err(|e| format!("kid {}'s key: {}", kid, e))?;
+    let version = metadata.version + 1;
+    generate(&storage_id(kid, version), key_type).map_err(|e| e.to_string())?;
+
+    metadata.version = version;
+    set_metadata(kid, &metadata).map_err(|e| e.to_string())?;
+    Ok(version)
+}
+
+/// Replace the key's labels
+pub fn set_labels(kid: &str, labels: BTreeMap<String, String>) -> Result<(), String> {
+    check_exists(kid)?;
+
+    let mut metadata = get_metadata(kid).map_err(|e| e.to_string())?;
+    metadata.labels = labels;
+    set_metadata(kid, &metadata).map_err(|e| e.to_string())
+}
+
+/// Restrict the operations the key can be used for
+pub fn set_usages(kid: &str, usages: BTreeSet<Usage>) -> Result<(), String> {
+    check_usages(&usages)?;
+    check_exists(kid)?;
+
+    let mut metadata = get_metadata(kid).map_err(|e| e.to_string())?;
+    metadata.usages = usages;
+    set_metadata(kid, &metadata).map_err(|e| e.to_string())
+}
+
+/// Whether the key can be used for the operation
+pub fn check_usage(kid: &str, usage: Usage) -> Result<(), String> {
+    let metadata = get_metadata(kid).map_err(|e| e.to_string())?;
+    // Whatever the stored usages say, the keys derived from aren't exported
+    let derived_from = usage == Usage::Export && metadata.usages.contains(&Usage::Derive);
+    match metadata.usages.contains(&usage) && !derived_from {
+        true => Ok(()),
+        false => Err(format!(
+            "kid {}'s key can't be used for {}",
+            kid,
+            usage.as_str()
+        )),
+    }
+}
+
+/// The stored keys of the scope carrying all the labels, soft deleted ones included
+pub fn list_keys(
+    scope: &Scope,
+    labels: &BTreeMap<String, String>,
+) -> Result<Vec<(String, KeyMetadata)>, String> {
+    let kids = list_kids().map_err(|e| format!("List kids failed: {}", e))?;
+
+    let mut keys = vec![];
+    for kid in kids.into_iter().filter(|kid| scope.contains(kid)) {
+        let metadata = get_metadata(&kid).map_err(|e| e.to_string())?;
+        if metadata.matches(labels) {
+            keys.push((kid, metadata));
+        }
+    }
+    Ok(keys)
+}
+
+/// Delete the key, at once if grace_period is 0, else soft delete it: the key can't be used
+/// anymore, but can be restored until it's purged grace_period seconds later
+pub fn delete_key(kid: &str, grace_period: u64) -> Result<(), String> {
+    // No operation can start using the kid while it's being deleted
+    let pending = PENDING_OPERATIONS.lock();
+    if pending.contains_key(kid) {
+        return Err(format!("kid {} is used by a pending operation", kid));
+    }
+
+    let mut metadata = get_metadata(kid).map_err(|e| e.to_string())?;
+    check_exists(kid)?;
+
+    match grace_period {
+        0 => {
+            let store = KEY_STORE.read();
+            for version in (2..=metadata.version).rev() {
+                store
+                    .delete_key(&storage_id(kid, version))
+                    .map_err(|e| e.to_string())?;
+            }
+            store.delete_key(kid).map_err(|e| e.to_string())
+        }
+        _ => {
+            if metadata.deleted.is_some() {
+                return Err(format!("kid {} is already deleted", kid));
+            }
+            let now = now();
+            metadata.deleted = Some(now);
+            metadata.purge_after = Some(now + grace_period);
+            set_metadata(kid, &metadata).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Cancel the soft deletion of the key
+pub fn restore_key(kid: &str) -> Result<(), String> {
+    check_exists(kid)?;
+
+    let mut metadata = get_metadata(kid).map_err(|e| e.to_string())?;
+    if metadata.deleted.is_none() {
+        return Err(format!("kid {} is not deleted", kid));
+    }
+
+    metadata.deleted = None;
+    metadata.purge_after = None;
+    set_metadata(kid, &metadata).map_err(|e| e.to_string())
+}
+
+/// Delete the soft deleted keys whose grace period is over, return how many were purged
+pub fn purge_expired() -> usize {
+    let kids = match list_kids() {
+        Ok(kids) => kids,
+        Err(e) => {
+            error!("list kids failed: {}", e);
+            return 0;
+        }
+    };
+
+    let now = now();
+    let mut purged = 0;
+    for kid in kids {
+        match get_metadata(&kid) {
+            Ok(metadata) if metadata.purge_after.map_or(false, |time| time <= now) => {}
+            _ => continue,
+        }
+
+        let res = delete_key(&kid, 0);
+        audit::record("purge", &kid, "verdictd", &res);
+        if res.is_ok() {
+            purged += 1;
+        }
+    }
+
+    purged
+}
+
+/// A key with its metadata and the material of all its versions (base64 encoded), as backed up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyBackup {
+    pub kid: String,
+    pub metadata: KeyMetadata,
+    pub versions: Vec<String>,
+}
+
+// The stored state of a key replaced by a restored one, to put it back if the restoration fails
+struct KeySnapshot {
+    metadata: Option<String>,
+    versions: Vec<Vec<u8>>,
+}
+
+/// Every key with the plaintext material of its versions, soft deleted ones included.
+/// The keys of a backend which doesn't export them fail the export, unless without_keys
+/// explicitly leaves them out.
+pub fn export_keys(without_keys: bool) -> Result<Vec<KeyBackup>, String> {
+    if !KEY_STORE.read().exportable() {
+        if !without_keys {
+            return Err(
+                "The keys of the key store can't be exported, back up without them explicitly"
+                    .to_string(),
+            );
+        }
+        warn!("the keys of the key store can't be exported, they're left out of the backup");
+        return Ok(vec![]);
+    }
+
+    // Serialized with the rotations and deletions, so every key is exported whole
+    let _pending = PENDING_OPERATIONS.lock();
+    let mut keys = vec![];
+    for kid in list_kids().map_err(|e| format!("List kids failed: {}", e))? {
+        let metadata = get_metadata(&kid).map_err(|e| e.to_string())?;
+        let versions = (1..=metadata.version)
+            .map(|version| {
+                let id = storage_id(&kid, version);
+                let data = KEY_STORE.read().get_key(&id)?;
+                unwrap_stored(&id, data).map(base64::encode)
+            })
+            .collect::<io::Result<Vec<String>>>()
+            .map_err(|e| format!("Read kid {} failed: {}", kid, e))?;
+        keys.push(KeyBackup {
+            kid,
+            metadata,
+            versions,
+        });
+    }
+    Ok(keys)
+}
+
+fn check_backup(key: &KeyBackup) -> Result<(), String> {
+    validate_kid(&key.kid)?;
+    let key_type = key.metadata.key_type()?;
+    check_key_type(key_type).map_err(|e| format!("kid {}: {}", key.kid, e))?;
+    if key.versions.is_empty() || key.versions.len() != key.metadata.version as usize {
+        return Err(format!(
+            "kid {} has {} versions, expect {}",
+            key.kid,
+            key.versions.len(),
+            key.metadata.version
+        ));
+    }
+    for version in &key.versions {
+        let material =
+            base64::decode(version).map_err(|e| format!("kid {}'s key: {}", key.kid, e))?;
+        key_type
+            .check(&material)
+            .map_err(|e| format!("kid {}'s key: {}", key.kid, e))?;
+    }
+    Ok(())
+}
+
+fn snapshot(kid: &str) -> Result<Option<KeySnapshot>, String> {
+    let store = KEY_STORE.read();
+    if !store.contains_key(kid) {
+        return Ok(None);
+    }
+    if !store.exportable() {
+        return Err(format!(
+            "kid {} already exists and can't be replaced, delete it to restore it",
+            kid
+        ));
+    }
+
+    let metadata = store.get_metadata(kid).map_err(|e| e.to_string())?;
+    let version = get_metadata(kid).map_err(|e| e.to_string())?.version;
+    let versions = (1..=version)
+        .map(|version| store.get_key(&storage_id(kid, version)))
+        .collect::<io::Result<Vec<Vec<u8>>>>()
+        .map_err(|e| format!("Read kid {} failed: {}", kid, e))?;
+    Ok(Some(KeySnapshot { metadata, versions }))
+}
+
+fn write_backup(key: &KeyBackup, snapshot: &Option<KeySnapshot>) -> Result<(), String> {
+    for (index, version) in key.versions.iter().enumerate() {
+        let material = base64::decode(version).map_err(|e| e.to_string())?;
+        store_key(&storage_id(&key.kid, index as u32 + 1), &material)
+            .map_err(|e| format!("Write kid {} failed: {}", key.kid, e))?;
+    }
+    set_metadata(&key.kid, &key.metadata)
+        .map_err(|e| format!("Write kid {}'s metadata failed: {}", key.kid, e))?;
+
+    // The versions the replaced key had beyond the restored ones
+    if let Some(snapshot) = snapshot {
+        for version in key.versions.len() + 1..=snapshot.versions.len() {
+            KEY_STORE
+                .read()
+                .delete_key(&storage_id(&key.kid, version as u32))
+                .map_err(|e| format!("Delete kid {} failed: {}", key.kid, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn rollback(key: &KeyBackup, snapshot: Option<KeySnapshot>) {
+    let store = KEY_STORE.read();
+    for version in (1..=key.versions.len() as u32).rev() {
+        let _ = store.delete_key(&storage_id(&key.kid, version));
+    }
+
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+    for (index, data) in snapshot.versions.iter().enumerate() {
+        if let Err(e) = store.set_key(&storage_id(&key.kid, index as u32 + 1), data) {
+            error!("roll back kid {} failed: {}", key.kid, e);
+        }
+    }
+    if let Some(metadata) = snapshot.metadata {
+        if let Err(e) = store.set_metadata(&key.kid, &metadata) {
+            error!("roll back kid {}'s metadata failed: {}", key.kid, e);
+        }
+    }
+}
+
+/// Store the backed up keys, replacing the keys with the same kids, the other keys are kept.
+/// then runs once they're all stored: if it fails, or a key can't be stored, the keys are
+/// put back as they were.
+pub fn import_keys<F>(keys: &[KeyBackup], then: F) -> Result<(), String>
+where
+    F: FnOnce() -> Result<(), String>,
+{
+    // No operation can use the keys while they're replaced
+    let pending = PENDING_OPERATIONS.lock();
+    for key in keys {
+        check_backup(key)?;
+        if pending.contains_key(&key.kid) {
+            return Err(format!("kid {} is used by a pending operation", key.kid));
+        }
+    }
+
+    let mut snapshots = vec![];
+    for key in keys {
+        snapshots.push(snapshot(&key.kid)?);
+    }
+
+    let mut written = 0;
+    let res = keys
+        .iter()
+        .zip(snapshots.iter())
+        .try_for_each(|(key, snapshot)| {
+            written += 1;
+            write_backup(key, snapshot)
+        })
+        .and_then(|_| then());
+    if res.is_err() {
+        for (key, snapshot) in keys.iter().zip(snapshots).take(written) {
+            rollback(key, snapshot);
+        }
+    }
+    res
+}
+
+/// Wrap the keys still stored in plaintext, or wrapped without their storage id bound,
+/// with the master key, return how many were wrapped
+pub fn migrate() -> Result<usize, String> {
+    let master = MASTER_KEY
+        .read()
+        .clone()
+        .ok_or("No master key is loaded".to_string())?;
+    let store = KEY_STORE.read();
+    if !store.exportable() {
+        return Err("The keys of the key store are never stored in plaintext".to_string());
+    }
+
+    let mut migrated = 0;
+    for kid in store.list_kids().map_err(|e| e.to_string())? {
+        let data = store
+            .get_key(&kid)
+            .map_err(|e| format!("Read kid {} failed: {}", kid, e))?;
+        if master_key::is_bound(&data) {
+            continue;
+        }
+        // The keys wrapped before the storage id was bound are wrapped again bound to it
+        let key = match master_key::is_wrapped(&data) {
+            true => master_key::unwrap(&master, &kid, &data)?,
+            false => data,
+        };
+
+        let wrapped = master_key::wrap(&master, &kid, &key)?;
+        store
+            .set_key(&kid, &wrapped)
+            .map_err(|e| format!("Write kid {} failed: {}", kid, e))?;
+        info!("kid {}'s key is wrapped by the master key", kid);
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::MutexGuard;
+    use std::fs;
+
+    // KEY_STORE pointed to a directory of its own, the previous store is put back
+    // and the directory removed once it's dropped
+    struct TestStore {
+        path: &'static str,
+        previous: Option<Box<dyn KeyStore>>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl TestStore {
+        fn new(path: &'static str) -> TestStore {
+            let lock = TEST_LOCK.lock();
+            let _ = fs::remove_dir_all(path);
+            fs::create_dir_all(path).unwrap();
+            let previous = std::mem::replace(
+                &mut *KEY_STORE.write(),
+                Box::new(DirectoryKeyStore::new(path)),
+            );
+            TestStore {
+                path,
+                previous: Some(previous),
+                _lock: lock,
+            }
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            if let Some(previous) = self.previous.take() {
+                *KEY_STORE.write() = previous;
+            }
+            let _ = fs::remove_dir_all(self.path);
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let legacy: KeyMetadata = serde_json::from_str("{}").unwrap();
+        assert_eq!(legacy, KeyMetadata::default());
+        assert_eq!(legacy.algorithm, "AES");
+        assert_eq!(legacy.version, 1);
+        assert!(legacy.usages.contains(&Usage::Export));
+        assert!(!legacy.usages.contains(&Usage::Derive));
+        assert!(check_usages(&legacy.usages).is_ok());
+        assert!(check_usages(&[Usage::Derive].into_iter().collect()).is_ok());
+        assert!(check_usages(&[Usage::Derive, Usage::Export].into_iter().collect()).is_err());
+        assert!(check_usages(&BTreeSet::new()).is_err());
+
+        let metadata: KeyMetadata = serde_json::from_str(r#"{"usages":["unwrap"]}"#).unwrap();
+        assert_eq!(
+            metadata.usages.into_iter().collect::<Vec<Usage>>(),
+            vec![Usage::Unwrap]
+        );
+        assert_eq!(Usage::parse("wrap"), Ok(Usage::Wrap));
+        assert!(Usage::parse("copy").is_err());
+        assert_eq!(legacy.state(), KeyMetadata::STATE_ACTIVE);
+
+        let mut metadata = KeyMetadata::default();
+        metadata.labels.insert("team".to_string(), "a".to_string());
+        metadata
+            .labels
+            .insert("env".to_string(), "prod".to_string());
+        let mut filter = BTreeMap::new();
+        assert!(metadata.matches(&filter));
+        filter.insert("team".to_string(), "a".to_string());
+        assert!(metadata.matches(&filter));
+        filter.insert("env".to_string(), "test".to_string());
+        assert!(!metadata.matches(&filter));
+    }
+
+    #[test]
+    fn test_validate_kid() {
+        assert!(validate_kid("6d3b0ef6-3c0f-4b3c-9a3e-7f0d1e2c3b4a").is_ok());
+        assert!(validate_kid("team-a/key.v1").is_ok());
+        assert!(validate_kid("../gpg/keyring.gpg").is_err());
+        assert!(validate_kid("a/b/c").is_err());
+        assert!(validate_kid(".meta").is_err());
+        assert!(validate_kid("kid#2").is_err());
+        assert!(validate_kid("").is_err());
+    }
+
+    #[test]
+    fn test_scope() {
+        let scope = Scope::tenant("team-a").unwrap();
+        assert_eq!(scope.resolve("kid"), Ok("team-a/kid".to_string()));
+        assert_eq!(scope.resolve("team-a/kid"), Ok("team-a/kid".to_string()));
+        assert!(scope.resolve("team-b/kid").is_err());
+        assert!(scope.contains("team-a/kid"));
+        assert!(!scope.contains("kid"));
+
+        assert_eq!(
+            Scope::global().resolve("team-b/kid"),
+            Ok("team-b/kid".to_string())
+        );
+        assert!(Scope::global().contains("kid"));
+        assert!(Scope::tenant("../x").is_err());
+
+        let scope = Scope::default();
+        assert_eq!(scope.resolve("kid"), Ok("kid".to_string()));
+        assert!(scope.resolve("team-b/kid").is_err());
+        assert!(scope.contains("kid"));
+        assert!(!scope.contains("team-b/kid"));
+        assert_eq!(Scope::granted(GLOBAL_TENANT), Ok(Scope::global()));
+        assert_eq!(Scope::granted("team-a"), Scope::tenant("team-a"));
+    }
+
+    #[test]
+    fn test_unknown_kid() {
+        let _store = TestStore::new("/tmp/verdictd-test-keys/");
+        let kid = "test_unknown_key";
+
+        let unknown = get_metadata(kid).unwrap_err();
+        assert_eq!(unknown.kind(), io::ErrorKind::NotFound);
+        assert!(get_key(kid).is_err());
+        assert!(check_usage(kid, Usage::Export).is_err());
+        assert!(encrypt(kid, None, b"test_data", &[]).is_err());
+        assert_eq!(KEY_STORE.read().get_metadata(kid).unwrap(), None);
+
+        // Metadata left without a key doesn't apply to the key imported later
+        KEY_STORE.read().set_metadata(kid, "{}").unwrap();
+        let key = KeyType::ChaCha20Poly1305.generate().unwrap();
+        let mut metadata = KeyMetadata::new(KeyType::ChaCha20Poly1305);
+        metadata.usages = [Usage::Decrypt].into_iter().collect();
+        add_key(kid, &key, &metadata).unwrap();
+        assert_eq!(get_metadata(kid).unwrap(), metadata);
+        assert!(metadata.created > 0);
+        assert!(add_key(kid, &key, &metadata).is_err());
+    }
+
+    #[test]
+    fn test_storage_id() {
+        assert_eq!(storage_id("kid", 1), "kid");
+        assert_eq!(storage_id("kid", 3), "kid#3");
+    }
+}
@@ -0,0 +1,174 @@
+use crate::crypto::aes256_gcm;
+use rand::RngCore;
+use std::fs;
+use std::io::{self, BufRead};
+
+const MASTER_KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+
+// Prefix of a key wrapped by the master key, the plaintext keys stored before don't carry it.
+// The keys wrapped with the legacy prefix aren't bound to their storage id.
+const WRAPPED_MAGIC: &[u8] = b"VDKW\x02";
+const LEGACY_WRAPPED_MAGIC: &[u8] = b"VDKW\x01";
+// Wrapped by the master key as the check value telling a wrong master key
+const CHECK_PLAINTEXT: &[u8] = b"verdictd master key check";
+// The id the check value is bound to, no key is stored under it
+const CHECK_ID: &str = "verdictd master key check";
+
+// The master key is either 32 raw bytes or their base64 encoding
+fn decode(material: &[u8]) -> Result<Vec<u8>, String> {
+    let key = match material.len() == MASTER_KEY_LEN {
+        true => material.to_vec(),
+        false => {
+            let material = String::from_utf8_lossy(material);
+            base64::decode(material.trim())
+                .map_err(|_| "Master key is neither raw nor base64 encoded".to_string())?
+        }
+    };
+
+    match key.len() == MASTER_KEY_LEN {
+        true => Ok(key),
+        false => Err(format!(
+            "Master key is {} bytes, expect {}",
+            key.len(),
+            MASTER_KEY_LEN
+        )),
+    }
+}
+
+/// Load the master key from source: file:<PATH>, env:<VARIABLE>,
+/// or stdin to have the operator unlock verdictd at startup
+pub fn load(source: &str) -> Result<Vec<u8>, String> {
+    let material = match source.split_once(':') {
+        Some(("file", path)) => {
+            fs::read(path).map_err(|e| format!("Read master key file {} failed: {}", path, e))?
+        }
+        Some(("env", name)) => std::env::var(name)
+            .map_err(|e| format!("Read master key variable {} failed: {}", name, e))?
+            .into_bytes(),
+        None if source == "stdin" => {
+            info!("enter the base64 encoded master key:");
+            let mut line = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| format!("Read master key from stdin failed: {}", e))?;
+            line.into_bytes()
+        }
+        _ => {
+            return Err(format!(
+                "Unknown master key source: {:?}, expect file:<PATH>, env:<VARIABLE> or stdin",
+                source
+            ))
+        }
+    };
+
+    decode(&material)
+}
+
+pub fn is_wrapped(data: &[u8]) -> bool {
+    data.starts_with(WRAPPED_MAGIC) || data.starts_with(LEGACY_WRAPPED_MAGIC)
+}
+
+/// Whether the key is wrapped bound to the id it's stored under
+pub fn is_bound(data: &[u8]) -> bool {
+    data.starts_with(WRAPPED_MAGIC)
+}
+
+/// Wrap the key with the master key by AES-256-GCM: magic || iv || ciphertext. The id the key
+/// is stored under (kid and version) is the aad, the wrapped key only unwraps under the same id.
+pub fn wrap(master_key: &[u8], id: &str, key: &[u8]) -> Result<Vec<u8>, String> {
+    let mut iv = [0u8; IV_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut iv);
+
+    let encrypted_key = aes256_gcm::encrypt_with_aad(key, master_key, &iv, id.as_bytes())?;
+    let mut wrapped = WRAPPED_MAGIC.to_vec();
+    wrapped.extend_from_slice(&iv);
+    wrapped.extend(encrypted_key);
+    Ok(wrapped)
+}
+
+pub fn unwrap(master_key: &[u8], id: &str, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+    // The keys wrapped before the id was bound unwrap without aad
+    let aad = match wrapped.starts_with(LEGACY_WRAPPED_MAGIC) {
+        true => &[][..],
+        false => id.as_bytes(),
+    };
+    if !is_wrapped(wrapped) || wrapped.len() < WRAPPED_MAGIC.len() + IV_LEN {
+        return Err("Key is not wrapped by the master key".to_string());
+    }
+
+    let (iv, encrypted_key) = wrapped[WRAPPED_MAGIC.len()..].split_at(IV_LEN);
+    aes256_gcm::decrypt_with_aad(encrypted_key, master_key, iv, aad)
+        .map_err(|_| format!("Unwrap {} failed, wrong master key or id?", id))
+}
+
+/// The check value of the master key, kept to tell whether a master key is the right one
+pub fn check_value(master_key: &[u8]) -> Result<Vec<u8>, String> {
+    wrap(master_key, CHECK_ID, CHECK_PLAINTEXT)
+}
+
+pub fn check(master_key: &[u8], check_value: &[u8]) -> Result<(), String> {
+    if master_key.len() != MASTER_KEY_LEN {
+        return Err(format!(
+            "Master key is {} bytes, expect {}",
+            master_key.len(),
+            MASTER_KEY_LEN
+        ));
+    }
+    match unwrap(master_key, CHECK_ID, check_value)? == CHECK_PLAINTEXT {
+        true => Ok(()),
+        false => Err("Master key doesn't match the check value".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let master_key = [7u8; MASTER_KEY_LEN];
+        let key = b"01234567890123456789012345678901";
+
+        let wrapped = wrap(&master_key, "kid", key).unwrap();
+        assert!(is_wrapped(&wrapped));
+        assert!(!is_wrapped(key));
+        assert_eq!(unwrap(&master_key, "kid", &wrapped).unwrap(), key.to_vec());
+        assert!(unwrap(&[8u8; MASTER_KEY_LEN], "kid", &wrapped).is_err());
+        // A wrapped key swapped with another kid's, or version's, doesn't unwrap
+        assert!(unwrap(&master_key, "other", &wrapped).is_err());
+        assert!(unwrap(&master_key, "kid#2", &wrapped).is_err());
+    }
+
+    #[test]
+    fn test_unwrap_legacy() {
+        let master_key = [7u8; MASTER_KEY_LEN];
+        let key = b"01234567890123456789012345678901";
+        let iv = [1u8; IV_LEN];
+
+        let mut wrapped = LEGACY_WRAPPED_MAGIC.to_vec();
+        wrapped.extend_from_slice(&iv);
+        wrapped.extend(aes256_gcm::encrypt(key, &master_key, &iv).unwrap());
+        assert!(is_wrapped(&wrapped));
+        assert_eq!(unwrap(&master_key, "kid", &wrapped).unwrap(), key.to_vec());
+    }
+
+    #[test]
+    fn test_check() {
+        let master_key = [7u8; MASTER_KEY_LEN];
+        let check_value = check_value(&master_key).unwrap();
+        assert!(check(&master_key, &check_value).is_ok());
+        assert!(check(&[8u8; MASTER_KEY_LEN], &check_value).is_err());
+        assert!(check(&[7u8; 16], &check_value).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let master_key = [7u8; MASTER_KEY_LEN];
+        assert_eq!(decode(&master_key).unwrap(), master_key.to_vec());
+        let encoded = base64::encode(master_key) + "\n";
+        assert_eq!(decode(encoded.as_bytes()).unwrap(), master_key.to_vec());
+        assert!(decode(b"c2hvcnQ=").is_err());
+    }
+}
@@ -1,5 +1,16 @@
+pub mod audit;
+pub mod backup;
 pub mod directory_key_manager;
 pub mod file;
 pub mod gpg;
 pub mod image;
+pub mod import_key;
+pub mod key_store;
+pub mod master_key;
 pub mod opa;
+pub mod opa_policy_set;
+pub mod opa_schema;
+pub mod pkcs11_key_manager;
+pub mod sigstruct;
+pub mod sqlite_key_manager;
+pub mod unseal;
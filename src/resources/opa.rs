@@ -1,9 +1,15 @@
+use crate::policy_engine::opa::opa_engine;
+use crate::rats_tls::PolicyContext;
 use crate::resources::file;
+use crate::resources::opa_policy_set;
+use crate::resources::opa_schema;
 use lazy_static::lazy_static;
 use parking_lot::RwLock;
+use serde_json::Value;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 lazy_static! {
     // Global file lock
@@ -17,7 +23,28 @@ pub const OPA_DATA_SGX: &str = "sgxData";
 pub const OPA_POLICY_CSV: &str = "csvPolicy.rego";
 pub const OPA_DATA_CSV: &str = "csvData";
 
-pub fn set_reference(name: &str, reference: &str) -> Result<(), String> {
+// Revoked mrEnclave/mrSigner/measure values, every policy sees them as data.revoked
+pub const OPA_REVOCATIONS: &str = "revocations";
+
+// Policy sets of the TEE types, see opa_policy_set
+pub const OPA_POLICY_SETS: &str = "policySets";
+
+// Shadow policies of the TEE types, see policy_engine::opa::shadow
+pub const OPA_SHADOW_POLICIES: &str = "shadowPolicies";
+
+pub const OPA_TEST_SUFFIX: &str = "_test.rego";
+
+// Name of the unit tests file stored alongside the policy,
+// e.g. sgxPolicy.rego's tests live in sgxPolicy_test.rego
+pub fn test_name(policy_name: &str) -> String {
+    policy_name.trim_end_matches(".rego").to_string() + OPA_TEST_SUFFIX
+}
+
+/// Save the reference after validating it against the schema of its TEE type,
+/// the TEE type is derived from the name if it isn't given
+pub fn set_reference(name: &str, tee: Option<&str>, reference: &str) -> Result<(), String> {
+    opa_schema::validate(tee.or(opa_schema::tee_of(name)), reference)?;
+
     let lock = FILE_LOCK.write();
     assert_eq!(*lock, 0);
 
@@ -25,6 +52,123 @@ pub fn set_reference(name: &str, reference: &str) -> Result<(), String> {
     file::set(&name, reference)
 }
 
+/// Patch the stored reference in place, the whole read-modify-write happens under
+/// FILE_LOCK so concurrent updates can't lose each other's changes
+pub fn update_reference<F>(name: &str, tee: Option<&str>, patch: F) -> Result<(), String>
+where
+    F: FnOnce(&mut Value) -> Result<(), String>,
+{
+    let lock = FILE_LOCK.write();
+    assert_eq!(*lock, 0);
+
+    // A reference that doesn't exist yet is created from an empty document
+    let src = String::from(OPA_PATH) + name;
+    let mut reference: Value = match Path::new(&src).exists() {
+        true => file::export_string(&src)
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Load reference {} failed: {}", name, e))?,
+        false => Value::Object(serde_json::Map::new()),
+    };
+
+    patch(&mut reference)?;
+
+    let reference = serde_json::to_string_pretty(&reference).map_err(|e| e.to_string())?;
+    opa_schema::validate(tee.or(opa_schema::tee_of(name)), &reference)?;
+    file::set(&src, &reference)
+}
+
+fn reference_list<'a>(reference: &'a mut Value, key: &str) -> Result<&'a mut Vec<Value>, String> {
+    reference
+        .as_object_mut()
+        .ok_or("Reference is not a json object".to_string())?
+        .entry(key)
+        .or_insert(Value::Array(vec![]))
+        .as_array_mut()
+        .ok_or(format!("Reference's {} is not a list", key))
+}
+
+// Value of a reference entry, which is either the value itself or
+// an object carrying the value and its validity window
+fn entry_value(entry: &Value) -> Option<&str> {
+    match entry {
+        Value::String(value) => Some(value),
+        Value::Object(entry) => entry.get("value").and_then(|value| value.as_str()),
+        _ => None,
+    }
+}
+
+/// Validity window of a reference entry, in seconds since the unix epoch
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Validity {
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+}
+
+// Add value into the reference's list named key, an existing entry of the value is replaced
+pub fn add_reference_value(
+    reference: &mut Value,
+    key: &str,
+    value: &str,
+    validity: Validity,
+) -> Result<(), String> {
+    let entry = match (validity.not_before, validity.not_after) {
+        (None, None) => Value::String(value.to_string()),
+        (not_before, not_after) => {
+            let mut entry = serde_json::Map::new();
+            entry.insert("value".to_string(), Value::String(value.to_string()));
+            if let Some(not_before) = not_before {
+                entry.insert("not_before".to_string(), Value::from(not_before));
+            }
+            if let Some(not_after) = not_after {
+                entry.insert("not_after".to_string(), Value::from(not_after));
+            }
+            Value::Object(entry)
+        }
+    };
+
+    let list = reference_list(reference, key)?;
+    match list.iter_mut().find(|e| entry_value(e) == Some(value)) {
+        Some(existing) => *existing = entry,
+        None => list.push(entry),
+    }
+    Ok(())
+}
+
+pub fn remove_reference_value(reference: &mut Value, key: &str, value: &str) -> Result<(), String> {
+    let list = reference_list(reference, key)?;
+    let len = list.len();
+    list.retain(|entry| entry_value(entry) != Some(value));
+    match list.len() == len {
+        true => Err(format!("{} isn't in reference's {}", value, key)),
+        false => Ok(()),
+    }
+}
+
+/// Add the revocation list into the reference document as `revoked`
+pub fn with_revocations(reference: String) -> Result<String, String> {
+    let revocations = String::from(OPA_PATH) + OPA_REVOCATIONS;
+    if !Path::new(&revocations).exists() {
+        return Ok(reference);
+    }
+
+    let revocations: Value = export(OPA_REVOCATIONS)
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Load {} failed: {}", OPA_REVOCATIONS, e))?;
+    let mut reference: Value = match serde_json::from_str(&reference) {
+        Ok(reference) => reference,
+        // Leave it to the policy engine to report the broken reference
+        Err(_) => return Ok(reference),
+    };
+    match reference.as_object_mut() {
+        Some(reference) => {
+            reference.insert("revoked".to_string(), revocations);
+        }
+        None => return Err("Reference is not a json object".to_string()),
+    }
+
+    Ok(reference.to_string())
+}
+
 /// Save the input raw policy file
 /// Note that the OPA binary program needs to be installed and placed in the system path
 pub fn set_policy(name: &str, policy: &str) -> Result<(), String> {
@@ -74,6 +218,228 @@ pub fn set_policy(name: &str, policy: &str) -> Result<(), String> {
         })
 }
 
+// Baseline policy and reference the evidence verifier of the TEE type makes decisions with,
+// the only ones unless a policy set is configured for the TEE type
+pub fn verifier_files(tee: &str) -> Option<(&'static str, &'static str)> {
+    match tee {
+        opa_schema::TEE_SGX => Some((OPA_POLICY_SGX, OPA_DATA_SGX)),
+        opa_schema::TEE_CSV => Some((OPA_POLICY_CSV, OPA_DATA_CSV)),
+        _ => None,
+    }
+}
+
+// Policies and references the rats-tls evidence verifiers make decisions with,
+// the caller holds FILE_LOCK
+fn in_use(name: &str) -> bool {
+    [
+        OPA_POLICY_SGX,
+        OPA_DATA_SGX,
+        OPA_POLICY_CSV,
+        OPA_DATA_CSV,
+        OPA_REVOCATIONS,
+        OPA_POLICY_SETS,
+        OPA_SHADOW_POLICIES,
+    ]
+    .contains(&name)
+        || opa_policy_set::uses(name)
+}
+
+// The kinds of values the revocation list holds
+const REVOCATION_KINDS: [&str; 3] = ["mrEnclave", "mrSigner", "measure"];
+
+// Evidence of the TEE type and the reference granting it, the policies in use are evaluated
+// with them to tell whether they honor the revocation list and the validity windows
+fn fixture(tee: &str) -> Option<(Value, Value)> {
+    let value = |byte: u8| Value::from(base64::encode([byte; 32]));
+    match tee {
+        opa_schema::TEE_SGX => Some((
+            serde_json::json!({ "mrEnclave": value(1), "mrSigner": value(2), "productId": 0, "svn": 0 }),
+            serde_json::json!({ "mrEnclave": [value(1)], "mrSigner": [value(2)], "productId": 0, "svn": 0 }),
+        )),
+        opa_schema::TEE_CSV => Some((
+            serde_json::json!({ "measure": value(3) }),
+            serde_json::json!({ "measure": [value(3)] }),
+        )),
+        _ => None,
+    }
+}
+
+// Whether the policy allows the input with the reference, None if it couldn't decide
+fn allows(policy: &str, reference: &Value, input: &str) -> Option<bool> {
+    opa_engine::make_decision_with(policy, &reference.to_string(), input)
+        .ok()
+        .and_then(|res| serde_json::from_str::<Value>(&res).ok())
+        .filter(|res| res["reason"].is_null())
+        .map(|res| res["allow"] == true)
+}
+
+// Whether the policy still allows the TEE type's fixture evidence once change is applied to
+// the reference granting it. change returns false if it doesn't apply to the TEE type. A policy
+// that doesn't allow the fixture evidence in the first place can't tell, it isn't reported.
+fn ignores(policy: &str, tee: &str, change: &impl Fn(&mut Value, &Value) -> bool) -> bool {
+    let (evidence, mut reference) = match fixture(tee) {
+        Some(fixture) => fixture,
+        None => return false,
+    };
+    reference["revoked"] = serde_json::json!({ "mrEnclave": [], "mrSigner": [], "measure": [] });
+
+    let mut input = evidence.clone();
+    input["context"] = serde_json::to_value(PolicyContext {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0),
+        ..Default::default()
+    })
+    .unwrap_or(Value::Null);
+    let input = input.to_string();
+
+    if allows(policy, &reference, &input) != Some(true) {
+        return false;
+    }
+    change(&mut reference, &evidence) && allows(policy, &reference, &input) == Some(true)
+}
+
+// The policies the evidence verifiers make decisions with which ignore the change
+fn policies_ignoring(change: impl Fn(&mut Value, &Value) -> bool) -> Result<Vec<String>, String> {
+    let mut policies: Vec<String> = vec![];
+    for tee in [opa_schema::TEE_SGX, opa_schema::TEE_CSV] {
+        for policy_ref in opa_policy_set::get(tee)?.policies {
+            if policies.contains(&policy_ref.policy) {
+                continue;
+            }
+            if let Ok(policy) = export(&policy_ref.policy) {
+                if ignores(&policy, tee, &change) {
+                    policies.push(policy_ref.policy);
+                }
+            }
+        }
+    }
+    Ok(policies)
+}
+
+// Revoke the evidence's value of the kind, false if the evidence has none
+fn revoke(kind: &str) -> impl Fn(&mut Value, &Value) -> bool + '_ {
+    move |reference, evidence| match evidence.get(kind) {
+        Some(value) => {
+            reference["revoked"][kind] = serde_json::json!([value]);
+            true
+        }
+        None => false,
+    }
+}
+
+// Replace the granted values with entries which expired at the start of the epoch
+fn expire(reference: &mut Value, _evidence: &Value) -> bool {
+    for kind in REVOCATION_KINDS {
+        if let Some(Value::Array(entries)) = reference.get_mut(kind) {
+            for entry in entries.iter_mut() {
+                *entry = serde_json::json!({ "value": entry.clone(), "not_after": 1 });
+            }
+        }
+    }
+    true
+}
+
+/// The policies in use which still let a value of the kind in once it's revoked,
+/// revoking values of the kind doesn't apply to them
+pub fn ignoring_revocations(kind: &str) -> Result<Vec<String>, String> {
+    policies_ignoring(revoke(kind))
+}
+
+/// Warn about the policies in use which ignore the revocation list or the validity windows,
+/// the default policies written by an earlier verdictd are kept as they are
+pub fn check_outdated() {
+    let mut ignoring: Vec<String> = vec![];
+    for kind in REVOCATION_KINDS {
+        match ignoring_revocations(kind) {
+            Ok(policies) => policies.into_iter().for_each(|policy| {
+                if !ignoring.contains(&policy) {
+                    ignoring.push(policy)
+                }
+            }),
+            Err(e) => {
+                warn!("check the policies in use failed: {}", e);
+                return;
+            }
+        }
+    }
+    ignoring.iter().for_each(|policy| {
+        warn!(
+            "{} doesn't check the revocation list, revocations are refused until it's updated",
+            policy
+        )
+    });
+    if let Ok(policies) = policies_ignoring(expire) {
+        policies.iter().for_each(|policy| {
+            warn!(
+                "{} doesn't check the reference values' validity windows, they're ignored",
+                policy
+            )
+        });
+    }
+}
+
+fn is_policy(name: &str) -> bool {
+    name.ends_with(".rego")
+}
+
+// List the stored files accepted by filter, backups left by set are skipped
+fn list(filter: fn(&str) -> bool) -> Result<Vec<String>, String> {
+    let lock = FILE_LOCK.read();
+    assert_eq!(*lock, 0);
+
+    let mut names: Vec<String> = fs::read_dir(OPA_PATH)
+        .map_err(|e| format!("Read {} failed: {}", OPA_PATH, e))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| !name.ends_with(".bak") && filter(name))
+        .collect();
+    names.sort();
+
+    Ok(names)
+}
+
+pub fn list_policies() -> Result<Vec<String>, String> {
+    list(is_policy)
+}
+
+pub fn list_references() -> Result<Vec<String>, String> {
+    list(|name| !is_policy(name))
+}
+
+fn delete(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("Invalid name {:?}", name));
+    }
+    // Checked under the lock, a policy set update can't start using the file
+    // between the check and its removal
+    let lock = FILE_LOCK.write();
+    assert_eq!(*lock, 0);
+
+    if in_use(name) {
+        return Err(format!("{} is in use by an evidence verifier", name));
+    }
+
+    let src = String::from(OPA_PATH) + name;
+    fs::remove_file(&src).map_err(|e| format!("Delete {} failed: {}", name, e))
+}
+
+pub fn delete_policy(name: &str) -> Result<(), String> {
+    match is_policy(name) {
+        true => delete(name),
+        false => Err(format!("{} is not a policy", name)),
+    }
+}
+
+pub fn delete_reference(name: &str) -> Result<(), String> {
+    match is_policy(name) {
+        true => Err(format!("{} is not a reference", name)),
+        false => delete(name),
+    }
+}
+
 // Export existing policy from verdictd
 pub fn export(name: &str) -> Result<String, String> {
     let lock = FILE_LOCK.read();
@@ -100,6 +466,7 @@ allow {
     mrSigner_is_grant
     input.productId >= data.productId
     input.svn >= data.svn
+    not revoked
 }
 
 mrEnclave_is_grant {
@@ -107,7 +474,7 @@ mrEnclave_is_grant {
 }
 mrEnclave_is_grant {
     count(data.mrEnclave) > 0
-    input.mrEnclave == data.mrEnclave[_]
+    is_valid(data.mrEnclave[_], input.mrEnclave)
 }
 
 mrSigner_is_grant {
@@ -115,7 +482,30 @@ mrSigner_is_grant {
 }
 mrSigner_is_grant {
     count(data.mrSigner) > 0
-    input.mrSigner == data.mrSigner[_]
+    is_valid(data.mrSigner[_], input.mrSigner)
+}
+
+revoked {
+    input.mrEnclave == data.revoked.mrEnclave[_]
+}
+revoked {
+    input.mrSigner == data.revoked.mrSigner[_]
+}
+
+# A reference entry is either the value, or an object carrying the value
+# and its optional not_before/not_after validity window
+is_valid(entry, value) {
+    entry == value
+}
+is_valid(entry, value) {
+    entry.value == value
+    now := time.now_ns() / 1000000000
+    object.get(entry, "not_before", 0) <= now
+    not expired(entry, now)
+}
+
+expired(entry, now) {
+    now >= entry.not_after
 }
 "#;
         file::write(
@@ -144,5 +534,146 @@ mrSigner_is_grant {
         .map_err(|e| format!("Set {} failed with error {:?}", OPA_DATA_SGX, e))?;
     }
 
+    if !Path::new(&(OPA_PATH.to_string() + OPA_POLICY_CSV)).exists() {
+        info!("{} isn't exist", OPA_POLICY_CSV);
+        // Unlike SGX, an empty measure list doesn't grant anything
+        let policy = r#"
+package policy
+
+# By default, deny requests.
+default allow = false
+
+allow {
+    is_valid(data.measure[_], input.measure)
+    not revoked
+}
+
+revoked {
+    input.measure == data.revoked.measure[_]
+}
+
+# A reference entry is either the value, or an object carrying the value
+# and its optional not_before/not_after validity window
+is_valid(entry, value) {
+    entry == value
+}
+is_valid(entry, value) {
+    entry.value == value
+    now := time.now_ns() / 1000000000
+    object.get(entry, "not_before", 0) <= now
+    not expired(entry, now)
+}
+
+expired(entry, now) {
+    now >= entry.not_after
+}
+"#;
+        file::write(
+            &(String::from(OPA_PATH) + OPA_POLICY_CSV),
+            &policy.to_string(),
+        )
+        .map_err(|e| format!("Set {} failed with error {:?}", OPA_POLICY_CSV, e))?;
+    }
+
+    if !Path::new(&(OPA_PATH.to_string() + OPA_DATA_CSV)).exists() {
+        info!("{} isn't exist", OPA_DATA_CSV);
+        let csv_data = r#"{
+    "measure": []
+}"#;
+
+        let lock = FILE_LOCK.write();
+        assert_eq!(*lock, 0);
+
+        file::write(
+            &(String::from(OPA_PATH) + OPA_DATA_CSV),
+            &csv_data.to_string(),
+        )
+        .map_err(|e| format!("Set {} failed with error {:?}", OPA_DATA_CSV, e))?;
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_test_name() {
+        assert_eq!(test_name(OPA_POLICY_SGX), "sgxPolicy_test.rego");
+        assert_eq!(test_name("custom"), "custom_test.rego");
+    }
+
+    #[test]
+    fn test_reference_value() {
+        let mut reference = serde_json::json!({ "mrEnclave": [], "svn": 0 });
+        let always = Validity::default();
+
+        assert!(add_reference_value(&mut reference, "mrEnclave", "a", always).is_ok());
+        assert!(add_reference_value(&mut reference, "mrEnclave", "a", always).is_ok());
+        assert!(add_reference_value(&mut reference, "mrSigner", "b", always).is_ok());
+        assert_eq!(reference["mrEnclave"], serde_json::json!(["a"]));
+        assert_eq!(reference["mrSigner"], serde_json::json!(["b"]));
+
+        let validity = Validity {
+            not_before: None,
+            not_after: Some(100),
+        };
+        assert!(add_reference_value(&mut reference, "mrEnclave", "a", validity).is_ok());
+        assert_eq!(
+            reference["mrEnclave"],
+            serde_json::json!([{ "value": "a", "not_after": 100 }])
+        );
+
+        assert!(remove_reference_value(&mut reference, "mrEnclave", "a").is_ok());
+        assert!(remove_reference_value(&mut reference, "mrEnclave", "a").is_err());
+        assert!(add_reference_value(&mut reference, "svn", "a", always).is_err());
+    }
+
+    #[test]
+    fn test_ignores_revocations() {
+        // The comment isn't a check of the revocation list
+        let ignoring = r#"
+package policy
+default allow = false
+# revoked measures aren't checked
+allow {
+    data.measure[_] == input.measure
+}
+"#;
+        let honoring = r#"
+package policy
+default allow = false
+allow {
+    data.measure[_] == input.measure
+    not denied(input.measure)
+}
+denied(value) {
+    value == data.revoked.measure[_]
+}
+"#;
+        assert!(ignores(ignoring, opa_schema::TEE_CSV, &revoke("measure")));
+        assert!(!ignores(honoring, opa_schema::TEE_CSV, &revoke("measure")));
+        // Nothing to revoke for a CSV policy
+        assert!(!ignores(
+            ignoring,
+            opa_schema::TEE_CSV,
+            &revoke("mrEnclave")
+        ));
+        // A policy denying the fixture evidence can't tell
+        assert!(!ignores(
+            ignoring,
+            opa_schema::TEE_SGX,
+            &revoke("mrEnclave")
+        ));
+    }
+
+    #[test]
+    fn test_delete_refused() {
+        assert!(delete_policy(OPA_POLICY_SGX).is_err());
+        assert!(delete_reference(OPA_DATA_CSV).is_err());
+        assert!(delete_policy(OPA_DATA_SGX).is_err());
+        assert!(delete_reference(OPA_POLICY_CSV).is_err());
+        assert!(delete_policy("../keys/kid.rego").is_err());
+    }
+}
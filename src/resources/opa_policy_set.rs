@@ -0,0 +1,155 @@
+use crate::resources::file;
+use crate::resources::opa;
+use crate::resources::opa_schema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// How the decisions of a policy set are combined
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // Every policy must allow
+    All,
+    // A single allowing policy is enough
+    Any,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Result<Mode, String> {
+        match mode {
+            "all" => Ok(Mode::All),
+            "any" => Ok(Mode::Any),
+            _ => Err(format!("Unknown mode: {:?}, expect all or any", mode)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::All => "all",
+            Mode::Any => "any",
+        }
+    }
+
+    pub fn combine(&self, allows: &[bool]) -> bool {
+        match self {
+            Mode::All => allows.iter().all(|allow| *allow),
+            Mode::Any => allows.iter().any(|allow| *allow),
+        }
+    }
+}
+
+/// A policy and the reference it makes decisions with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PolicyRef {
+    pub policy: String,
+    pub reference: String,
+}
+
+/// The policies the evidence verifier of a TEE type makes decisions with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PolicySet {
+    pub mode: Mode,
+    pub policies: Vec<PolicyRef>,
+}
+
+// The configured policy sets indexed by TEE type, the caller holds FILE_LOCK
+fn read() -> Result<BTreeMap<String, PolicySet>, String> {
+    let src = String::from(opa::OPA_PATH) + opa::OPA_POLICY_SETS;
+    if !Path::new(&src).exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    file::export_string(&src)
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Load {} failed: {}", opa::OPA_POLICY_SETS, e))
+}
+
+fn load() -> Result<BTreeMap<String, PolicySet>, String> {
+    let lock = opa::FILE_LOCK.read();
+    assert_eq!(*lock, 0);
+    read()
+}
+
+/// The TEE type's policy set, its single baseline policy if none is configured
+pub fn get(tee: &str) -> Result<PolicySet, String> {
+    let (policy, reference) =
+        opa::verifier_files(tee).ok_or(format!("Unknown TEE type: {:?}", tee))?;
+
+    match load()?.remove(tee) {
+        Some(set) => Ok(set),
+        None => Ok(PolicySet {
+            mode: Mode::All,
+            policies: vec![PolicyRef {
+                policy: policy.to_string(),
+                reference: reference.to_string(),
+            }],
+        }),
+    }
+}
+
+/// Replace the TEE type's policy set, all of its policies and references must exist
+pub fn set(tee: &str, set: PolicySet) -> Result<(), String> {
+    if opa::verifier_files(tee).is_none() {
+        return Err(format!("Unknown TEE type: {:?}", tee));
+    }
+    if set.policies.is_empty() {
+        return Err("A policy set needs at least one policy".to_string());
+    }
+
+    // The same lock deletions take, a policy or reference can't be deleted
+    // between the check that it exists and the set starting to use it
+    let lock = opa::FILE_LOCK.write();
+    assert_eq!(*lock, 0);
+
+    for policy_ref in &set.policies {
+        for name in [&policy_ref.policy, &policy_ref.reference] {
+            file::export_string(&(String::from(opa::OPA_PATH) + name))
+                .map_err(|e| format!("Load {} failed: {}", name, e))?;
+        }
+    }
+
+    let mut sets = read()?;
+    sets.insert(tee.to_string(), set);
+    let content = serde_json::to_string_pretty(&sets).map_err(|e| e.to_string())?;
+    opa_schema::validate(opa_schema::tee_of(opa::OPA_POLICY_SETS), &content)?;
+
+    file::set(
+        &(String::from(opa::OPA_PATH) + opa::OPA_POLICY_SETS),
+        &content,
+    )
+}
+
+/// Whether a configured policy set makes decisions with the policy or reference,
+/// the caller holds FILE_LOCK
+pub fn uses(name: &str) -> bool {
+    match read() {
+        Ok(sets) => sets.values().any(|set| {
+            set.policies
+                .iter()
+                .any(|policy_ref| policy_ref.policy == name || policy_ref.reference == name)
+        }),
+        // Refuse to touch anything while the configuration is unreadable
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combine() {
+        assert!(Mode::All.combine(&[true, true]));
+        assert!(!Mode::All.combine(&[true, false]));
+        assert!(Mode::Any.combine(&[false, true]));
+        assert!(!Mode::Any.combine(&[false, false]));
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(Mode::parse("all"), Ok(Mode::All));
+        assert_eq!(Mode::parse(Mode::Any.as_str()), Ok(Mode::Any));
+        assert!(Mode::parse("some").is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::resources::opa;
+use jsonschema::JSONSchema;
+use lazy_static::lazy_static;
+use serde_json::Value;
+
+pub const TEE_SGX: &str = "sgx";
+pub const TEE_CSV: &str = "csv";
+// Not a TEE type, the revocation list shared by all TEE types
+pub const SCHEMA_REVOCATIONS: &str = "revocations";
+// Not a TEE type, the policy sets of the TEE types
+pub const SCHEMA_POLICY_SETS: &str = "policySets";
+// Not a TEE type, the shadow policies of the TEE types
+pub const SCHEMA_SHADOW_POLICIES: &str = "shadowPolicies";
+
+// mrEnclave, mrSigner and the CSV measure are 32 bytes digests encoded by base64,
+// exactly as sgx_callback/csv_callback put them into the policy input
+const DIGEST_BASE64_PATTERN: &str = "^[A-Za-z0-9+/]{43}=$";
+
+lazy_static! {
+    // A reference entry is either the digest, or the digest with its validity window
+    static ref REFERENCE_ENTRY: Value = serde_json::json!({
+        "oneOf": [
+            { "type": "string", "pattern": DIGEST_BASE64_PATTERN },
+            {
+                "type": "object",
+                "properties": {
+                    "value": { "type": "string", "pattern": DIGEST_BASE64_PATTERN },
+                    "not_before": { "type": "integer", "minimum": 0 },
+                    "not_after": { "type": "integer", "minimum": 0 }
+                },
+                "required": ["value"],
+                "additionalProperties": false
+            }
+        ]
+    });
+    static ref DIGEST_LIST: Value = serde_json::json!({
+        "type": "array",
+        "items": { "type": "string", "pattern": DIGEST_BASE64_PATTERN }
+    });
+    static ref SGX_SCHEMA: JSONSchema = JSONSchema::compile(&serde_json::json!({
+        "type": "object",
+        "properties": {
+            "mrEnclave": { "type": "array", "items": *REFERENCE_ENTRY },
+            "mrSigner": { "type": "array", "items": *REFERENCE_ENTRY },
+            "productId": { "type": "integer", "minimum": 0, "maximum": 65535 },
+            "svn": { "type": "integer", "minimum": 0, "maximum": 65535 }
+        },
+        "required": ["mrEnclave", "mrSigner", "productId", "svn"],
+        "additionalProperties": false
+    }))
+    .expect("invalid SGX reference schema");
+    static ref CSV_SCHEMA: JSONSchema = JSONSchema::compile(&serde_json::json!({
+        "type": "object",
+        "properties": {
+            "measure": { "type": "array", "items": *REFERENCE_ENTRY }
+        },
+        "required": ["measure"],
+        "additionalProperties": false
+    }))
+    .expect("invalid CSV reference schema");
+    static ref REVOCATIONS_SCHEMA: JSONSchema = JSONSchema::compile(&serde_json::json!({
+        "type": "object",
+        "properties": {
+            "mrEnclave": *DIGEST_LIST,
+            "mrSigner": *DIGEST_LIST,
+            "measure": *DIGEST_LIST
+        },
+        "additionalProperties": false
+    }))
+    .expect("invalid revocations schema");
+    static ref POLICY_SETS_SCHEMA: JSONSchema = JSONSchema::compile(&serde_json::json!({
+        "type": "object",
+        "propertyNames": { "enum": [TEE_SGX, TEE_CSV] },
+        "additionalProperties": {
+            "type": "object",
+            "properties": {
+                "mode": { "enum": ["all", "any"] },
+                "policies": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "policy": { "type": "string", "pattern": "\\.rego$" },
+                            "reference": { "type": "string", "minLength": 1 }
+                        },
+                        "required": ["policy", "reference"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["mode", "policies"],
+            "additionalProperties": false
+        }
+    }))
+    .expect("invalid policy sets schema");
+    static ref SHADOW_POLICIES_SCHEMA: JSONSchema = JSONSchema::compile(&serde_json::json!({
+        "type": "object",
+        "propertyNames": { "enum": [TEE_SGX, TEE_CSV] },
+        "additionalProperties": {
+            "type": "object",
+            "properties": {
+                "policy": { "type": "string", "pattern": "\\.rego$" },
+                "reference": { "type": "string", "minLength": 1 }
+            },
+            "required": ["policy"],
+            "additionalProperties": false
+        }
+    }))
+    .expect("invalid shadow policies schema");
+}
+
+// TEE type of the references the evidence verifiers use by default
+pub fn tee_of(name: &str) -> Option<&'static str> {
+    match name {
+        opa::OPA_DATA_SGX => Some(TEE_SGX),
+        opa::OPA_DATA_CSV => Some(TEE_CSV),
+        opa::OPA_REVOCATIONS => Some(SCHEMA_REVOCATIONS),
+        opa::OPA_POLICY_SETS => Some(SCHEMA_POLICY_SETS),
+        opa::OPA_SHADOW_POLICIES => Some(SCHEMA_SHADOW_POLICIES),
+        _ => None,
+    }
+}
+
+/// Check the reference is a json object, and if the TEE type is known,
+/// that it has the shape the TEE type's policy expects
+pub fn validate(tee: Option<&str>, reference: &str) -> Result<(), String> {
+    let reference: Value = serde_json::from_str(reference)
+        .map_err(|e| format!("Reference is not in json format: {}", e))?;
+    if !reference.is_object() {
+        return Err("Reference is not a json object".to_string());
+    }
+
+    let schema: &JSONSchema = match tee {
+        Some(TEE_SGX) => &SGX_SCHEMA,
+        Some(TEE_CSV) => &CSV_SCHEMA,
+        Some(SCHEMA_REVOCATIONS) => &REVOCATIONS_SCHEMA,
+        Some(SCHEMA_POLICY_SETS) => &POLICY_SETS_SCHEMA,
+        Some(SCHEMA_SHADOW_POLICIES) => &SHADOW_POLICIES_SCHEMA,
+        Some(tee) => return Err(format!("Unknown TEE type: {}", tee)),
+        None => return Ok(()),
+    };
+
+    schema.validate(&reference).map_err(|errors| {
+        let errors: Vec<String> = errors
+            .map(|e| format!("{}: {}", e.instance_path, e))
+            .collect();
+        format!(
+            "Reference doesn't match the {} schema: {}",
+            tee.unwrap(),
+            errors.join("; ")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_sgx() {
+        let mr = base64::encode([0u8; 32]);
+        let reference = serde_json::json!({
+            "mrEnclave": [mr],
+            "mrSigner": [],
+            "productId": 0,
+            "svn": 1
+        });
+        assert!(validate(Some(TEE_SGX), &reference.to_string()).is_ok());
+
+        let typo = serde_json::json!({
+            "mrEnclaves": [mr],
+            "mrSigner": [],
+            "productId": 0,
+            "svn": 1
+        });
+        assert!(validate(Some(TEE_SGX), &typo.to_string()).is_err());
+
+        let short = serde_json::json!({
+            "mrEnclave": ["AAAA"],
+            "mrSigner": [],
+            "productId": 0,
+            "svn": 1
+        });
+        assert!(validate(Some(TEE_SGX), &short.to_string()).is_err());
+
+        let window = serde_json::json!({
+            "mrEnclave": [{ "value": mr, "not_before": 0, "not_after": 1700000000 }],
+            "mrSigner": [],
+            "productId": 0,
+            "svn": 1
+        });
+        assert!(validate(Some(TEE_SGX), &window.to_string()).is_ok());
+
+        let negative = serde_json::json!({
+            "mrEnclave": [{ "value": mr, "not_after": -1 }],
+            "mrSigner": [],
+            "productId": 0,
+            "svn": 1
+        });
+        assert!(validate(Some(TEE_SGX), &negative.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_validate_revocations() {
+        let mr = base64::encode([0u8; 32]);
+        let revocations = serde_json::json!({ "mrSigner": [mr] });
+        assert!(validate(tee_of(opa::OPA_REVOCATIONS), &revocations.to_string()).is_ok());
+
+        let unknown = serde_json::json!({ "mrSigners": [mr] });
+        assert!(validate(tee_of(opa::OPA_REVOCATIONS), &unknown.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_validate_policy_sets() {
+        let sets = serde_json::json!({
+            "sgx": {
+                "mode": "all",
+                "policies": [{ "policy": "sgxPolicy.rego", "reference": "sgxData" }]
+            }
+        });
+        assert!(validate(tee_of(opa::OPA_POLICY_SETS), &sets.to_string()).is_ok());
+
+        let tdx = serde_json::json!({ "tdx": sets["sgx"] });
+        assert!(validate(tee_of(opa::OPA_POLICY_SETS), &tdx.to_string()).is_err());
+
+        let empty = serde_json::json!({ "sgx": { "mode": "any", "policies": [] } });
+        assert!(validate(tee_of(opa::OPA_POLICY_SETS), &empty.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_validate_shadow_policies() {
+        let shadows = serde_json::json!({
+            "sgx": { "policy": "candidate.rego" },
+            "csv": { "policy": "candidate.rego", "reference": "candidateData" }
+        });
+        assert!(validate(tee_of(opa::OPA_SHADOW_POLICIES), &shadows.to_string()).is_ok());
+
+        let unnamed = serde_json::json!({ "sgx": { "reference": "candidateData" } });
+        assert!(validate(tee_of(opa::OPA_SHADOW_POLICIES), &unnamed.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_validate_not_json() {
+        assert!(validate(None, "{}").is_ok());
+        assert!(validate(None, "not json").is_err());
+        assert!(validate(None, "[]").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use crate::crypto::key_type::KeyType;
+use crate::resources::key_store::KeyStore;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::aead::GcmParams;
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{
+    Attribute, AttributeType, KeyType as TokenKeyType, ObjectClass, ObjectHandle,
+};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use parking_lot::Mutex;
+use std::fs;
+use std::io;
+
+pub const VERDICTD_PKCS11_MODULE: &str = "/usr/lib/softhsm/libsofthsm2.so";
+pub const VERDICTD_PKCS11_TOKEN: &str = "verdictd";
+
+// CKA_ID of the secret keys created by verdictd, the other objects of the token are left alone
+const KEY_OBJECT_ID: &[u8] = b"verdictd";
+// CKA_APPLICATION of the data objects holding the key metadata
+const METADATA_APPLICATION: &[u8] = b"verdictd";
+const GCM_TAG_BITS: u64 = 128;
+
+/// Keys held as non-extractable AES secret keys of a PKCS#11 token, the key material
+/// never leaves the token: the data is encrypted and decrypted with AES-GCM inside it.
+/// The key metadata is stored in the token's data objects.
+pub struct Pkcs11KeyStore {
+    session: Mutex<Session>,
+}
+
+fn io_error(e: cryptoki::error::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn not_found(kid: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("kid {} not found", kid))
+}
+
+/// Read the token's user PIN from source: file:<PATH> or env:<VARIABLE>
+pub fn read_pin(source: &str) -> Result<String, String> {
+    let pin = match source.split_once(':') {
+        Some(("file", path)) => fs::read_to_string(path)
+            .map_err(|e| format!("Read PKCS#11 PIN file {} failed: {}", path, e))?,
+        Some(("env", name)) => std::env::var(name)
+            .map_err(|e| format!("Read PKCS#11 PIN variable {} failed: {}", name, e))?,
+        _ => {
+            return Err(format!(
+                "Unknown PKCS#11 PIN source: {:?}, expect file:<PATH> or env:<VARIABLE>",
+                source
+            ))
+        }
+    };
+    Ok(pin.trim().to_string())
+}
+
+impl Pkcs11KeyStore {
+    /// Load the PKCS#11 module and log in the token labeled token as its user
+    pub fn open(module: &str, token: &str, pin: &str) -> Result<Pkcs11KeyStore, String> {
+        let pkcs11 = Pkcs11::new(module)
+            .map_err(|e| format!("Load PKCS#11 module {} failed: {}", module, e))?;
+        pkcs11
+            .initialize(CInitializeArgs::OsThreads)
+            .map_err(|e| format!("Initialize PKCS#11 module {} failed: {}", module, e))?;
+
+        let slots = pkcs11
+            .get_slots_with_token()
+            .map_err(|e| format!("List PKCS#11 slots failed: {}", e))?;
+        let slot = slots
+            .into_iter()
+            .find(|slot| match pkcs11.get_token_info(*slot) {
+                Ok(info) => info.label().trim() == token,
+                Err(_) => false,
+            })
+            .ok_or(format!("PKCS#11 token {} not found", token))?;
+
+        let session = pkcs11
+            .open_rw_session(slot)
+            .map_err(|e| format!("Open PKCS#11 session failed: {}", e))?;
+        session
+            .login(UserType::User, Some(&AuthPin::new(pin.to_string())))
+            .map_err(|e| format!("Log in PKCS#11 token {} failed: {}", token, e))?;
+
+        Ok(Pkcs11KeyStore {
+            session: Mutex::new(session),
+        })
+    }
+
+    fn find_key(session: &Session, kid: &str) -> io::Result<Option<ObjectHandle>> {
+        session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::SECRET_KEY),
+                Attribute::Id(KEY_OBJECT_ID.to_vec()),
+                Attribute::Label(kid.as_bytes().to_vec()),
+            ])
+            .map(|objects| objects.into_iter().next())
+            .map_err(io_error)
+    }
+
+    fn find_metadata(session: &Session, kid: &str) -> io::Result<Option<ObjectHandle>> {
+        session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::DATA),
+                Attribute::Application(METADATA_APPLICATION.to_vec()),
+                Attribute::Label(kid.as_bytes().to_vec()),
+            ])
+            .map(|objects| objects.into_iter().next())
+            .map_err(io_error)
+    }
+
+    // The attributes of verdictd's keys: usable to encrypt and decrypt only, never readable
+    fn key_template(kid: &str) -> Vec<Attribute> {
+        vec![
+            Attribute::Class(ObjectClass::SECRET_KEY),
+            Attribute::KeyType(TokenKeyType::AES),
+            Attribute::Id(KEY_OBJECT_ID.to_vec()),
+            Attribute::Label(kid.as_bytes().to_vec()),
+            Attribute::Token(true),
+            Attribute::Private(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+            Attribute::Encrypt(true),
+            Attribute::Decrypt(true),
+        ]
+    }
+
+    // Replace the kid's key by the one created by create
+    fn replace_key(
+        &self,
+        kid: &str,
+        create: impl FnOnce(&Session) -> cryptoki::error::Result<ObjectHandle>,
+    ) -> io::Result<()> {
+        let session = self.session.lock();
+        let previous = Self::find_key(&session, kid)?;
+        create(&session).map_err(io_error)?;
+        if let Some(previous) = previous {
+            session.destroy_object(previous).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    fn gcm(
+        &self,
+        kid: &str,
+        data: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+        encrypt: bool,
+    ) -> io::Result<Vec<u8>> {
+        let session = self.session.lock();
+        let key = Self::find_key(&session, kid)?.ok_or_else(|| not_found(kid))?;
+        let mechanism = Mechanism::AesGcm(GcmParams::new(iv, aad, GCM_TAG_BITS.into()));
+        match encrypt {
+            true => session.encrypt(&mechanism, key, data),
+            false => session.decrypt(&mechanism, key, data),
+        }
+        .map_err(io_error)
+    }
+}
+
+impl KeyStore for Pkcs11KeyStore {
+    fn exportable(&self) -> bool {
+        false
+    }
+
+    fn contains_key(&self, kid: &str) -> bool {
+        matches!(Self::find_key(&self.session.lock(), kid), Ok(Some(_)))
+    }
+
+    fn get_key(&self, kid: &str) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "kid {}'s key is held by the PKCS#11 token, it can't be exported",
+                kid
+            ),
+        ))
+    }
+
+    fn set_key(&self, kid: &str, key: &[u8]) -> io::Result<()> {
+        info!("import key into PKCS#11 token: {}", kid);
+
+        if key.len() != 16 && key.len() != 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the PKCS#11 token only holds AES-128 and AES-256 keys",
+            ));
+        }
+        let mut template = Self::key_template(kid);
+        template.push(Attribute::Value(key.to_vec()));
+        self.replace_key(kid, |session| session.create_object(&template))
+    }
+
+    fn generate_key(&self, kid: &str, key_type: KeyType) -> io::Result<()> {
+        info!("generate key in PKCS#11 token: {}", kid);
+
+        let length: u64 = match key_type {
+            KeyType::Aes128 => 16,
+            KeyType::Aes256 => 32,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the PKCS#11 token only holds AES-128 and AES-256 keys",
+                ))
+            }
+        };
+        let mut template = Self::key_template(kid);
+        template.push(Attribute::ValueLen(length.into()));
+        self.replace_key(kid, |session| {
+            session.generate_key(&Mechanism::AesKeyGen, &template)
+        })
+    }
+
+    fn encrypt(&self, kid: &str, data: &[u8], iv: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.gcm(kid, data, iv, aad, true)
+    }
+
+    fn decrypt(&self, kid: &str, data: &[u8], iv: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.gcm(kid, data, iv, aad, false)
+    }
+
+    fn list_kids(&self) -> io::Result<Vec<String>> {
+        let session = self.session.lock();
+        let keys = session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::SECRET_KEY),
+                Attribute::Id(KEY_OBJECT_ID.to_vec()),
+            ])
+            .map_err(io_error)?;
+
+        let mut kids = vec![];
+        for key in keys {
+            for attribute in session
+                .get_attributes(key, &[AttributeType::Label])
+                .map_err(io_error)?
+            {
+                if let Attribute::Label(label) = attribute {
+                    kids.push(String::from_utf8_lossy(&label).to_string());
+                }
+            }
+        }
+        kids.sort();
+        Ok(kids)
+    }
+
+    fn delete_key(&self, kid: &str) -> io::Result<()> {
+        info!("delete key from PKCS#11 token: {}", kid);
+
+        let session = self.session.lock();
+        let key = Self::find_key(&session, kid)?.ok_or_else(|| not_found(kid))?;
+        session.destroy_object(key).map_err(io_error)?;
+        if let Some(metadata) = Self::find_metadata(&session, kid)? {
+            session.destroy_object(metadata).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    fn get_metadata(&self, kid: &str) -> io::Result<Option<String>> {
+        let session = self.session.lock();
+        let metadata = match Self::find_metadata(&session, kid)? {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+        for attribute in session
+            .get_attributes(metadata, &[AttributeType::Value])
+            .map_err(io_error)?
+        {
+            if let Attribute::Value(value) = attribute {
+                return String::from_utf8(value)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_metadata(&self, kid: &str, metadata: &str) -> io::Result<()> {
+        let session = self.session.lock();
+        let previous = Self::find_metadata(&session, kid)?;
+        session
+            .create_object(&[
+                Attribute::Class(ObjectClass::DATA),
+                Attribute::Application(METADATA_APPLICATION.to_vec()),
+                Attribute::Label(kid.as_bytes().to_vec()),
+                Attribute::Token(true),
+                Attribute::Private(true),
+                Attribute::Value(metadata.as_bytes().to_vec()),
+            ])
+            .map_err(io_error)?;
+        if let Some(previous) = previous {
+            session.destroy_object(previous).map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Needs a SoftHSM token: softhsm2-util --init-token --free --label verdictd --pin 1234 --so-pin 1234
+    #[test]
+    #[ignore]
+    fn test_softhsm() {
+        let store =
+            Pkcs11KeyStore::open(VERDICTD_PKCS11_MODULE, VERDICTD_PKCS11_TOKEN, "1234").unwrap();
+        let key = [3u8; 32];
+        let iv = [5u8; 12];
+
+        assert!(store.set_key("test_key", &key).is_ok());
+        assert!(store.contains_key("test_key"));
+        assert!(store.get_key("test_key").is_err());
+        assert!(store.set_key("test_key", b"not an AES key").is_err());
+
+        // The token encrypts the way the keys handed out do
+        let encrypted_data = store
+            .encrypt("test_key", b"test_data", &iv, b"kid")
+            .unwrap();
+        let decrypted_data = KeyType::Aes256
+            .decrypt(&key, &encrypted_data, &iv, b"kid")
+            .unwrap();
+        assert_eq!(decrypted_data, b"test_data".to_vec());
+        assert_eq!(
+            store
+                .decrypt("test_key", &encrypted_data, &iv, b"kid")
+                .unwrap(),
+            b"test_data".to_vec()
+        );
+        assert!(store
+            .decrypt("test_key", &encrypted_data, &iv, &[])
+            .is_err());
+
+        assert!(store.generate_key("test_key#2", KeyType::Aes128).is_ok());
+        assert!(store.generate_key("test_key#3", KeyType::EcP256).is_err());
+        assert!(store
+            .list_kids()
+            .unwrap()
+            .contains(&"test_key#2".to_string()));
+
+        assert_eq!(store.get_metadata("test_key").unwrap(), None);
+        assert!(store.set_metadata("test_key", "{}").is_ok());
+        assert!(store.set_metadata("test_key", "{\"version\":2}").is_ok());
+        assert_eq!(
+            store.get_metadata("test_key").unwrap(),
+            Some("{\"version\":2}".to_string())
+        );
+
+        assert!(store.delete_key("test_key#2").is_ok());
+        assert!(store.delete_key("test_key").is_ok());
+        assert!(!store.contains_key("test_key"));
+        assert_eq!(store.get_metadata("test_key").unwrap(), None);
+    }
+}
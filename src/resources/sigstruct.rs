@@ -0,0 +1,161 @@
+use crate::resources::opa::{self, Validity};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde_json::Value;
+use std::convert::TryInto;
+
+// Layout of the SGX enclave signature structure (SIGSTRUCT), see the Intel SDM
+const SIGSTRUCT_SIZE: usize = 1808;
+const HEADER: [u8; 16] = [
+    0x06, 0x00, 0x00, 0x00, 0xe1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const HEADER2_OFFSET: usize = 24;
+const HEADER2: [u8; 16] = [
+    0x01, 0x01, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+];
+const MODULUS_OFFSET: usize = 128;
+const MODULUS_SIZE: usize = 384;
+const ENCLAVE_HASH_OFFSET: usize = 960;
+const ISV_PROD_ID_OFFSET: usize = 1024;
+const ISV_SVN_OFFSET: usize = 1026;
+
+/// SGX reference values of a signed enclave, encoded the way sgx_callback puts
+/// the evidence into the policy input
+#[derive(Debug, PartialEq)]
+pub struct SgxIdentity {
+    pub mr_enclave: String,
+    pub mr_signer: String,
+    pub product_id: u16,
+    pub svn: u16,
+}
+
+// Position of the SIGSTRUCT, either the whole content or embedded in a signed enclave
+fn find(content: &[u8]) -> Option<usize> {
+    if content.len() < SIGSTRUCT_SIZE {
+        return None;
+    }
+
+    (0..=content.len() - SIGSTRUCT_SIZE).find(|offset| {
+        content[*offset..].starts_with(&HEADER)
+            && content[offset + HEADER2_OFFSET..].starts_with(&HEADER2)
+    })
+}
+
+fn read_u16(sigstruct: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(sigstruct[offset..offset + 2].try_into().unwrap())
+}
+
+/// Extract the reference values from a SIGSTRUCT file, or a signed enclave
+/// (e.g. enclave.signed.so) whose metadata embeds the SIGSTRUCT
+pub fn parse(content: &[u8]) -> Result<SgxIdentity, String> {
+    let offset = find(content).ok_or("No SIGSTRUCT found".to_string())?;
+    let sigstruct = &content[offset..offset + SIGSTRUCT_SIZE];
+
+    // MRSIGNER is the SHA-256 of the signer's RSA modulus, in little endian as stored
+    let mut hasher = Sha256::new();
+    hasher.input(&sigstruct[MODULUS_OFFSET..MODULUS_OFFSET + MODULUS_SIZE]);
+    let mut mr_signer = [0u8; 32];
+    hasher.result(&mut mr_signer);
+
+    Ok(SgxIdentity {
+        mr_enclave: base64::encode(&sigstruct[ENCLAVE_HASH_OFFSET..ENCLAVE_HASH_OFFSET + 32]),
+        mr_signer: base64::encode(mr_signer),
+        product_id: read_u16(sigstruct, ISV_PROD_ID_OFFSET),
+        svn: read_u16(sigstruct, ISV_SVN_OFFSET),
+    })
+}
+
+/// Add the enclave's mrEnclave and mrSigner into the SGX reference. The productId is only
+/// set if the reference has none (0 matches any), a build of another product is refused.
+/// The minimum svn is only raised or lowered to the build's if set_svn is given, adding an
+/// older build mustn't let in the vulnerable builds the current minimum keeps out.
+pub fn add_to_reference(
+    reference: &mut Value,
+    identity: &SgxIdentity,
+    validity: Validity,
+    set_svn: bool,
+) -> Result<(), String> {
+    match reference["productId"].as_u64() {
+        None | Some(0) => reference["productId"] = Value::from(identity.product_id),
+        Some(product_id) if product_id == identity.product_id as u64 => {}
+        Some(product_id) => {
+            return Err(format!(
+                "productId {} of the SIGSTRUCT doesn't match the reference's {}",
+                identity.product_id, product_id
+            ))
+        }
+    }
+    opa::add_reference_value(reference, "mrEnclave", &identity.mr_enclave, validity)?;
+    opa::add_reference_value(reference, "mrSigner", &identity.mr_signer, validity)?;
+    if set_svn {
+        reference["svn"] = Value::from(identity.svn);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sigstruct() -> Vec<u8> {
+        let mut sigstruct = vec![0u8; SIGSTRUCT_SIZE];
+        sigstruct[..16].copy_from_slice(&HEADER);
+        sigstruct[HEADER2_OFFSET..HEADER2_OFFSET + 16].copy_from_slice(&HEADER2);
+        sigstruct[ENCLAVE_HASH_OFFSET..ENCLAVE_HASH_OFFSET + 32].copy_from_slice(&[1u8; 32]);
+        sigstruct[ISV_PROD_ID_OFFSET..ISV_PROD_ID_OFFSET + 2].copy_from_slice(&3u16.to_le_bytes());
+        sigstruct[ISV_SVN_OFFSET..ISV_SVN_OFFSET + 2].copy_from_slice(&258u16.to_le_bytes());
+        sigstruct
+    }
+
+    #[test]
+    fn test_parse() {
+        let identity = parse(&sigstruct()).unwrap();
+        assert_eq!(identity.mr_enclave, base64::encode([1u8; 32]));
+        // SHA-256 of the all zero modulus
+        assert_eq!(
+            identity.mr_signer,
+            "oaT1chwcRhCvf3EHjzpowzBTbWeYA7DgUH7o3BDF38o="
+        );
+        assert_eq!(identity.product_id, 3);
+        assert_eq!(identity.svn, 258);
+
+        let mut enclave = vec![0xffu8; 100];
+        enclave.extend(sigstruct());
+        enclave.extend(vec![0xffu8; 100]);
+        assert_eq!(parse(&enclave).unwrap(), identity);
+
+        assert!(parse(&[0u8; SIGSTRUCT_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_add_to_reference() {
+        let always = Validity::default();
+        let mut reference = serde_json::json!({
+            "mrEnclave": [],
+            "mrSigner": [],
+            "productId": 0,
+            "svn": 0
+        });
+        let identity = parse(&sigstruct()).unwrap();
+        assert!(add_to_reference(&mut reference, &identity, always, true).is_ok());
+        assert_eq!(reference["productId"], 3);
+        assert_eq!(reference["svn"], 258);
+
+        // An older build is added without lowering the minimum svn
+        let mut older = sigstruct();
+        older[ENCLAVE_HASH_OFFSET..ENCLAVE_HASH_OFFSET + 32].copy_from_slice(&[2u8; 32]);
+        older[ISV_SVN_OFFSET..ISV_SVN_OFFSET + 2].copy_from_slice(&1u16.to_le_bytes());
+        let older = parse(&older).unwrap();
+        assert!(add_to_reference(&mut reference, &older, always, false).is_ok());
+        assert_eq!(reference["svn"], 258);
+        assert_eq!(reference["mrEnclave"].as_array().unwrap().len(), 2);
+
+        // A build of another product is refused, the reference is left as it was
+        let mut other = sigstruct();
+        other[ISV_PROD_ID_OFFSET..ISV_PROD_ID_OFFSET + 2].copy_from_slice(&4u16.to_le_bytes());
+        let other = parse(&other).unwrap();
+        let before = reference.clone();
+        assert!(add_to_reference(&mut reference, &other, always, true).is_err());
+        assert_eq!(reference, before);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::resources::key_store::KeyStore;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io;
+
+pub const VERDICTD_KEY_DB: &str = "/opt/verdictd/keys.db";
+
+/// Keys stored in an embedded SQLite database
+pub struct SqliteKeyStore {
+    conn: Mutex<Connection>,
+}
+
+fn io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl SqliteKeyStore {
+    pub fn open(path: &str) -> Result<SqliteKeyStore, String> {
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Open key database {} failed: {}", path, e))?;
+        // Deleted keys are overwritten with zeros instead of lingering in the free pages
+        conn.pragma_update(None, "secure_delete", true)
+            .map_err(|e| format!("Enable secure_delete in {} failed: {}", path, e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS keys (kid TEXT PRIMARY KEY, key BLOB NOT NULL)",
+            [],
+        )
+        .map_err(|e| format!("Create keys table in {} failed: {}", path, e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS metadata (kid TEXT PRIMARY KEY, metadata TEXT NOT NULL)",
+            [],
+        )
+        .map_err(|e| format!("Create metadata table in {} failed: {}", path, e))?;
+
+        Ok(SqliteKeyStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl KeyStore for SqliteKeyStore {
+    fn get_key(&self, kid: &str) -> io::Result<Vec<u8>> {
+        info!("get key from key database: {}", kid);
+
+        self.conn
+            .lock()
+            .query_row("SELECT key FROM keys WHERE kid = ?1", params![kid], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(io_error)?
+            .ok_or_else(|| {
+                error!("Get kid:{}'s key failed, err: not found", kid);
+                io::Error::new(io::ErrorKind::NotFound, format!("kid {} not found", kid))
+            })
+    }
+
+    fn set_key(&self, kid: &str, key: &[u8]) -> io::Result<()> {
+        info!("set key in key database: {}", kid);
+
+        self.conn
+            .lock()
+            .execute(
+                "INSERT OR REPLACE INTO keys (kid, key) VALUES (?1, ?2)",
+                params![kid, key],
+            )
+            .map_err(io_error)
+            .and_then(|_| Ok(()))
+    }
+
+    fn list_kids(&self) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn
+            .prepare("SELECT kid FROM keys ORDER BY kid")
+            .map_err(io_error)?;
+        let kids = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(io_error)?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(io_error);
+        kids
+    }
+
+    fn delete_key(&self, kid: &str) -> io::Result<()> {
+        info!("delete key from key database: {}", kid);
+
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(io_error)?;
+        let deleted = tx
+            .execute("DELETE FROM keys WHERE kid = ?1", params![kid])
+            .map_err(io_error)?;
+        if deleted == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("kid {} not found", kid),
+            ));
+        }
+        tx.execute("DELETE FROM metadata WHERE kid = ?1", params![kid])
+            .map_err(io_error)?;
+        tx.commit().map_err(io_error)
+    }
+
+    fn get_metadata(&self, kid: &str) -> io::Result<Option<String>> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT metadata FROM metadata WHERE kid = ?1",
+                params![kid],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io_error)
+    }
+
+    fn set_metadata(&self, kid: &str, metadata: &str) -> io::Result<()> {
+        self.conn
+            .lock()
+            .execute(
+                "INSERT OR REPLACE INTO metadata (kid, metadata) VALUES (?1, ?2)",
+                params![kid, metadata],
+            )
+            .map_err(io_error)
+            .and_then(|_| Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_get_key() {
+        let store = SqliteKeyStore::open(":memory:").unwrap();
+        let key_content = b"test_key_content".to_vec();
+
+        assert!(store.set_key("test_key", &key_content).is_ok());
+        assert_eq!(store.get_key("test_key").unwrap(), key_content);
+
+        assert!(store.set_key("test_key", b"replaced").is_ok());
+        assert_eq!(store.get_key("test_key").unwrap(), b"replaced".to_vec());
+
+        assert_eq!(store.list_kids().unwrap(), vec!["test_key".to_string()]);
+
+        assert!(store.set_metadata("test_key", "{}").is_ok());
+        assert_eq!(
+            store.get_metadata("test_key").unwrap(),
+            Some("{}".to_string())
+        );
+
+        assert!(store.delete_key("test_key").is_ok());
+        assert!(store.get_metadata("test_key").unwrap().is_none());
+
+        let res = store.get_key("notexist");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let res = store.delete_key("notexist");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::crypto::shamir::{self, Share};
+use crate::resources::{key_store, master_key};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Check value of the split master key, written when it's split
+pub const MASTER_KEY_CHECK: &str = "/opt/verdictd/master_key.check";
+/// Threshold and commitments of the master key's shares, written when it's split
+pub const MASTER_KEY_SHARES: &str = "/opt/verdictd/master_key.shares";
+
+// A share is verdictd-share:<THRESHOLD>:<X>:<base64 encoded Y>
+const SHARE_PREFIX: &str = "verdictd-share";
+
+// Every share is checked on its own against its commitment, so a bogus share can't
+// disturb the genuine ones
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Commitments {
+    threshold: u8,
+    // SHA-256 of each encoded share, base64 encoded, by the share's x
+    shares: BTreeMap<u8, String>,
+}
+
+// The shares submitted so far, verdictd is unsealed once the master key they recover is checked
+struct Sealed {
+    check_value: Vec<u8>,
+    commitments: Commitments,
+    shares: Vec<Share>,
+}
+
+lazy_static! {
+    // None once verdictd is unsealed, or when it isn't started sealed
+    static ref SEALED: Mutex<Option<Sealed>> = Mutex::new(None);
+}
+
+/// Progress of the unseal after a share is submitted
+#[derive(Debug, PartialEq)]
+pub struct Progress {
+    pub received: u32,
+    pub threshold: u32,
+    pub sealed: bool,
+}
+
+fn encode_share(threshold: u8, share: &Share) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        SHARE_PREFIX,
+        threshold,
+        share.x,
+        base64::encode(&share.y)
+    )
+}
+
+fn commitment(encoded_share: &str) -> String {
+    base64::encode(Sha256::digest(encoded_share.as_bytes()))
+}
+
+fn decode_share(share: &str) -> Result<(u8, Share), String> {
+    let broken = || "The share is broken".to_string();
+    let fields: Vec<&str> = share.trim().split(':').collect();
+    match fields.as_slice() {
+        [SHARE_PREFIX, threshold, x, y] => {
+            let threshold = threshold.parse::<u8>().map_err(|_| broken())?;
+            let x = x.parse::<u8>().map_err(|_| broken())?;
+            let y = base64::decode(y).map_err(|_| broken())?;
+            Ok((threshold, Share { x, y }))
+        }
+        _ => Err(broken()),
+    }
+}
+
+/// Split the master key into shares as specified by "<THRESHOLD>-of-<SHARES>",
+/// and write its check value to tell the master key the shares recover.
+/// A master key other than the one of the existing check value isn't split.
+pub fn split(master_key: &[u8], spec: &str) -> Result<Vec<String>, String> {
+    check_master_key(master_key)?;
+    let (threshold, shares) = spec
+        .split_once("-of-")
+        .and_then(|(threshold, shares)| {
+            Some((threshold.parse::<u8>().ok()?, shares.parse::<u8>().ok()?))
+        })
+        .ok_or(format!("{:?} isn't <THRESHOLD>-of-<SHARES>", spec))?;
+    let split: Vec<String> = shamir::split(master_key, threshold, shares)?
+        .iter()
+        .map(|share| encode_share(threshold, share))
+        .collect();
+
+    let commitments = Commitments {
+        threshold,
+        shares: (1..=shares)
+            .zip(split.iter())
+            .map(|(x, share)| (x, commitment(share)))
+            .collect(),
+    };
+    let commitments = serde_json::to_string(&commitments).map_err(|e| e.to_string())?;
+    fs::write(MASTER_KEY_SHARES, commitments)
+        .map_err(|e| format!("Write {} failed: {}", MASTER_KEY_SHARES, e))?;
+    let check_value = master_key::check_value(master_key)?;
+    fs::write(MASTER_KEY_CHECK, check_value)
+        .map_err(|e| format!("Write {} failed: {}", MASTER_KEY_CHECK, e))?;
+
+    Ok(split)
+}
+
+/// Check the loaded master key against the check value of the split master key, if any
+pub fn check_master_key(master_key: &[u8]) -> Result<(), String> {
+    match fs::read(MASTER_KEY_CHECK) {
+        Ok(check_value) => master_key::check(master_key, &check_value),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Start sealed: the master key is recovered from the shares submitted by unseal
+pub fn seal() -> Result<(), String> {
+    let check_value = fs::read(MASTER_KEY_CHECK).map_err(|e| {
+        format!(
+            "Read {} failed: {}, split the master key first",
+            MASTER_KEY_CHECK, e
+        )
+    })?;
+    let commitments = fs::read_to_string(MASTER_KEY_SHARES)
+        .map_err(|e| {
+            format!(
+                "Read {} failed: {}, split the master key again",
+                MASTER_KEY_SHARES, e
+            )
+        })
+        .and_then(|commitments| {
+            serde_json::from_str(&commitments)
+                .map_err(|e| format!("Parse {} failed: {}", MASTER_KEY_SHARES, e))
+        })?;
+
+    *SEALED.lock() = Some(Sealed {
+        check_value,
+        commitments,
+        shares: vec![],
+    });
+    Ok(())
+}
+
+pub fn is_sealed() -> bool {
+    SEALED.lock().is_some()
+}
+
+/// Submit a share, the master key is recovered and loaded once threshold shares are submitted.
+/// A share which doesn't match its commitment is refused, the submitted shares are kept.
+pub fn unseal(share: &str) -> Result<Progress, String> {
+    let mut state = SEALED.lock();
+    let sealed = state.as_mut().ok_or("verdictd isn't sealed".to_string())?;
+    let share = sealed.commitments.verify(share)?;
+    if sealed.shares.iter().any(|submitted| submitted.x == share.x) {
+        return Err(format!("Share {} is already submitted", share.x));
+    }
+    sealed.shares.push(share);
+
+    let threshold = sealed.commitments.threshold;
+    let received = sealed.shares.len() as u32;
+    if received < threshold as u32 {
+        info!("{} of {} shares are submitted", received, threshold);
+        return Ok(Progress {
+            received,
+            threshold: threshold as u32,
+            sealed: true,
+        });
+    }
+
+    // The shares are genuine, they only fail to recover the master key if the files don't match
+    let master = shamir::combine(&sealed.shares)
+        .and_then(|master| master_key::check(&master, &sealed.check_value).map(|_| master))
+        .map_err(|e| format!("The shares don't recover the master key: {}", e))?;
+
+    key_store::set_master_key(master);
+    *state = None;
+    info!("verdictd is unsealed");
+    Ok(Progress {
+        received,
+        threshold: threshold as u32,
+        sealed: false,
+    })
+}
+
+impl Commitments {
+    // The decoded share, if it's one of the shares the master key was split into
+    fn verify(&self, share: &str) -> Result<Share, String> {
+        let (threshold, share) = decode_share(share)?;
+        let genuine = threshold == self.threshold
+            && self.shares.get(&share.x).map_or(false, |expected| {
+                let actual = commitment(&encode_share(threshold, &share));
+                expected.len() == actual.len()
+                    && expected
+                        .bytes()
+                        .zip(actual.bytes())
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            });
+        match genuine {
+            true => Ok(share),
+            false => Err("The share isn't one of the master key's shares".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_encoding() {
+        let share = Share {
+            x: 3,
+            y: vec![1, 2, 3],
+        };
+        let encoded = encode_share(2, &share);
+        assert!(encoded.starts_with("verdictd-share:2:3:"));
+        assert_eq!(decode_share(&(encoded + "\n")).unwrap(), (2, share));
+
+        assert!(decode_share("verdictd-share:2:3").is_err());
+        assert!(decode_share("verdictd-share:2:256:AQID").is_err());
+        assert!(decode_share("other-share:2:3:AQID").is_err());
+    }
+
+    #[test]
+    fn test_commitments() {
+        let secret = [7u8; 32];
+        let split: Vec<String> = shamir::split(&secret, 2, 3)
+            .unwrap()
+            .iter()
+            .map(|share| encode_share(2, share))
+            .collect();
+        let commitments = Commitments {
+            threshold: 2,
+            shares: (1..=3)
+                .zip(split.iter())
+                .map(|(x, share)| (x, commitment(share)))
+                .collect(),
+        };
+
+        assert_eq!(commitments.verify(&split[1]).unwrap().x, 2);
+        let (_, mut bogus) = decode_share(&split[1]).unwrap();
+        bogus.y[0] ^= 1;
+        assert!(commitments.verify(&encode_share(2, &bogus)).is_err());
+        let (_, other_threshold) = decode_share(&split[1]).unwrap();
+        assert!(commitments
+            .verify(&encode_share(3, &other_threshold))
+            .is_err());
+        bogus.x = 4;
+        assert!(commitments.verify(&encode_share(2, &bogus)).is_err());
+    }
+}